@@ -1,7 +1,7 @@
 //! The `BufReaderWriterRand<RW>` and `BufReaderWriterSeq<RW>` are convenience structs that facilitate automatic
 //! switching between buffered reading and writing from a single underlying IO instance. `BufReaderWriterRand` is
 //! for random access IO (i.e. `Read + Write + Seek`, such as `std::fs::File`), while `BufReaderWriterSeq` is for sequential IO (i.e. `Read + Write`).  
-//! 
+//!
 //! Both structs move the underlying IO instance between a `BufReader` and `BufWriter` as needed.  However, when switching from
 //! reading to writing, `BufReaderWriterRand` discards any buffered data and seeks the underlying IO instance back to the
 //! current `BufReader` position, while `BufReaderWriterSeq` saves any buffered data and makes it available for subsequent
@@ -26,19 +26,573 @@
 //! }
 //! ```
 
+#![cfg_attr(
+    feature = "nightly",
+    feature(can_vector, core_io_borrowed_buf, read_buf)
+)]
+
+/// Expands to inherent `read_u8` and `write_u8` methods, and `read_*` and `write_*` methods for the listed fixed width
+/// integers in big-endian and little-endian byte order.  Each method reads or writes the whole integer through the
+/// buffered layers, switching modes at most once.
+macro_rules! int_io_methods {
+    ($($ty:ty: $read_be:ident, $read_le:ident, $write_be:ident, $write_le:ident;)*) => {
+        /// Reads a single byte.  Returns an error of kind `UnexpectedEof` if the stream ends first.
+        pub fn read_u8(&mut self) -> io::Result<u8> {
+            let mut byte = [0_u8];
+            self.read_exact(&mut byte)?;
+            Ok(byte[0])
+        }
+
+        /// Writes a single byte.
+        pub fn write_u8(&mut self, n: u8) -> io::Result<()> {
+            self.write_all(&[n])
+        }
+
+        /// Reads exactly `N` bytes into an array, without allocating, as for fixed-size headers.  Returns an error of
+        /// kind `UnexpectedEof` if the stream ends first.
+        pub fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+            let mut bytes = [0_u8; N];
+            self.read_exact(&mut bytes)?;
+            Ok(bytes)
+        }
+
+        /// Writes all of the array `data`, the counterpart to `read_array`.
+        pub fn write_array<const N: usize>(&mut self, data: [u8; N]) -> io::Result<()> {
+            self.write_all(&data)
+        }
+        $(
+            #[doc = concat!(
+                "Reads a big-endian `", stringify!($ty), "`.  ",
+                "Returns an error of kind `UnexpectedEof` if the stream ends first."
+            )]
+            pub fn $read_be(&mut self) -> io::Result<$ty> {
+                let mut bytes = [0_u8; std::mem::size_of::<$ty>()];
+                self.read_exact(&mut bytes)?;
+                Ok(<$ty>::from_be_bytes(bytes))
+            }
+
+            #[doc = concat!(
+                "Reads a little-endian `", stringify!($ty), "`.  ",
+                "Returns an error of kind `UnexpectedEof` if the stream ends first."
+            )]
+            pub fn $read_le(&mut self) -> io::Result<$ty> {
+                let mut bytes = [0_u8; std::mem::size_of::<$ty>()];
+                self.read_exact(&mut bytes)?;
+                Ok(<$ty>::from_le_bytes(bytes))
+            }
+
+            #[doc = concat!("Writes a big-endian `", stringify!($ty), "`.")]
+            pub fn $write_be(&mut self, n: $ty) -> io::Result<()> {
+                self.write_all(&n.to_be_bytes())
+            }
+
+            #[doc = concat!("Writes a little-endian `", stringify!($ty), "`.")]
+            pub fn $write_le(&mut self, n: $ty) -> io::Result<()> {
+                self.write_all(&n.to_le_bytes())
+            }
+        )*
+    };
+}
+
 pub mod rand;
 pub mod seq;
 
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+
+/// The mode of a `BufReaderWriter`, determined by whether the last operation was a read or a write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Reader,
+    Writer,
+    /// Neither, as no read or write has happened yet on an instance created with `new_lazy`.
+    Unset,
+}
+
+/// A snapshot of the buffering state of a `BufReaderWriter`, for debugging and logging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufReaderWriterState {
+    /// The current mode.
+    pub mode: Mode,
+    /// The capacity of the current buffer.
+    pub capacity: usize,
+    /// The number of bytes waiting in the write buffer.
+    pub pending_write: usize,
+    /// The number of bytes of read data in the `BufReader` buffer.
+    pub buffered_read: usize,
+    /// The number of bytes of read data saved across a switch to write mode.  Always 0 for `BufReaderWriterRand`.
+    pub saved_buffer: usize,
+}
+
+/// The configuration of a `BufReaderWriter`, from `config`, for creating identically configured instances over other IO
+/// instances with `build_seq` or `build_rand`.  Observers, callbacks and buffer budgets are not part of the configuration.
+/// Options that apply to only one of the structs are ignored by the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufReaderWriterConfig {
+    /// The starting mode, `Mode::Unset` for a lazily constructed instance.
+    pub mode: Mode,
+    /// The read buffer capacity, or `None` for the default.
+    pub read_capacity: Option<usize>,
+    /// The write buffer capacity, or `None` for the default.
+    pub write_capacity: Option<usize>,
+    /// Whether reads and writes retry on `Interrupted`, see `set_retry_on_interrupt`.
+    pub retry_on_interrupt: bool,
+    /// The write alignment, see `BufReaderWriterRand::set_write_alignment`.
+    pub write_alignment: Option<usize>,
+    /// The maximum saved buffer size, see `BufReaderWriterSeq::set_max_saved_buffer`.
+    pub max_saved_buffer: Option<usize>,
+    /// The maximum write chunk, see `BufReaderWriterSeq::set_max_write_chunk`.
+    pub max_write_chunk: Option<usize>,
+    /// Whether reads fill the `BufReader` ahead, see `BufReaderWriterSeq::set_read_ahead`.
+    pub read_ahead: bool,
+    /// Whether `WouldBlock` reads end the stream, see `BufReaderWriterSeq::set_wouldblock_as_eof`.
+    pub wouldblock_as_eof: bool,
+    /// The seek bound, see `BufReaderWriterSeq::set_seek_bounds`.
+    pub seek_bound: Option<u64>,
+}
+
+/// Owned copies of the data buffered in a `BufReaderWriter`, from `export_state`, for restoring into a new instance with
+/// `import_state`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedBuffers {
+    /// The mode at the time of export.
+    pub mode: Mode,
+    /// The data waiting in the write buffer.
+    pub pending_write: Vec<u8>,
+    /// The read data not yet consumed, saved or in the `BufReader` buffer.  Always empty for `BufReaderWriterRand`, whose
+    /// unconsumed read data can be read again from the underlying IO instance.
+    pub saved_read: Vec<u8>,
+}
+
+/// Receives notifications of the IO activity on a `BufReaderWriter`, for instrumentation.
+pub trait IoObserver {
+    /// Called after a read returns `n` bytes, or `n` bytes are consumed after `fill_buf`.
+    fn on_read(&mut self, n: usize);
+
+    /// Called after a write accepts `n` bytes.
+    fn on_write(&mut self, n: usize);
+
+    /// Called after the `BufReaderWriter` switches to the `to` mode.
+    fn on_switch(&mut self, to: Mode);
+
+    /// Called with the name of the `BufReaderWriter` when it is set with `set_name`, or when the observer is set on a named
+    /// instance, so that the observer can tag its events.  Does nothing by default.
+    fn on_name(&mut self, _name: &str) {}
+
+    /// Called after an explicit flush of the write buffer.
+    fn on_flush(&mut self);
+}
+
+/// An IO instance that reads nothing, accepts and discards all writes, and ignores seeks, for measuring the overhead of
+/// a `BufReaderWriter` without real IO.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EmptySink;
+
+impl io::Read for EmptySink {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl io::Write for EmptySink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for EmptySink {
+    fn seek(&mut self, _pos: io::SeekFrom) -> io::Result<u64> {
+        Ok(0)
+    }
+}
+
+/// An IO instance whose written data can be synced to durable storage, for `flush_durable`.
+pub trait Syncable {
+    /// Syncs all written data and metadata to durable storage.
+    fn sync_all(&self) -> io::Result<()>;
+}
+
+impl Syncable for File {
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+/// An IO instance whose length can be set, for `truncate_here`.
+pub trait Truncate {
+    /// Truncates or extends the underlying storage to `len` bytes.
+    fn set_len(&self, len: u64) -> io::Result<()>;
+}
+
+impl Truncate for File {
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+/// An IO instance that can report the preferred block size of its storage, for `suggested_block_io_size`.
+pub trait BlockSize {
+    /// Returns the preferred block size for IO, if known.
+    fn block_size(&self) -> Option<usize>;
+}
+
+impl BlockSize for File {
+    #[cfg(unix)]
+    fn block_size(&self) -> Option<usize> {
+        use std::os::unix::fs::MetadataExt;
+        self.metadata().ok().map(|m| m.blksize() as usize)
+    }
+
+    #[cfg(not(unix))]
+    fn block_size(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Error payload for a failed switch from write mode to read mode, holding a copy of the buffered data that could not be
+/// flushed.  It is returned inside an `io::Error` with the same `kind()` as the original error, and can be recovered with
+/// `io::Error::into_inner` and `downcast`.  The data remains buffered in the writer, so a later flush may still write it.
+#[derive(Debug)]
+pub struct ModeSwitchError {
+    error: io::Error,
+    bytes: Vec<u8>,
+}
+
+impl ModeSwitchError {
+    fn wrap(error: io::Error, bytes: &[u8]) -> io::Error {
+        io::Error::new(
+            error.kind(),
+            ModeSwitchError {
+                error,
+                bytes: bytes.to_vec(),
+            },
+        )
+    }
+
+    /// Gets a reference to the original IO error.
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    /// Gets a reference to the data that could not be flushed.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Unwraps this `ModeSwitchError`, returning the data that could not be flushed.
+    pub fn into_inner_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl fmt::Display for ModeSwitchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to flush {} bytes when switching to read mode: {}",
+            self.bytes.len(),
+            self.error
+        )
+    }
+}
+
+impl Error for ModeSwitchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Message for the error or panic raised when a `BufReaderWriter` is used with no underlying IO instance, which can only
+/// happen if an earlier operation panicked part way through.
+pub(crate) const POISONED: &str = "BufReaderWriter used after being unwrapped";
+
+pub(crate) fn poisoned() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, POISONED)
+}
+
+/// Writes `data` to `w`, retrying on `Interrupted`, until all is written or an error occurs.  Returns the number of bytes
+/// written along with the result.
+pub(crate) fn write_raw<W: io::Write>(w: &mut W, data: &[u8]) -> (usize, io::Result<()>) {
+    let mut written = 0;
+    while written < data.len() {
+        match w.write(&data[written..]) {
+            Ok(0) => return (written, Err(io::Error::from(io::ErrorKind::WriteZero))),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return (written, Err(e)),
+        }
+    }
+    (written, Ok(()))
+}
+
+/// Byte order swapping in fixed-width groups, installed with `set_byteswap`.  Bytes that don't yet make up a whole
+/// group are held back until the rest of the group arrives.
+pub(crate) struct ByteSwap {
+    width: usize,
+    read_data: Vec<u8>,
+    read_pos: usize,
+    read_ready: usize,
+    write_partial: Vec<u8>,
+}
+
+impl ByteSwap {
+    pub(crate) fn new(width: usize) -> ByteSwap {
+        ByteSwap {
+            width,
+            read_data: Vec::new(),
+            read_pos: 0,
+            read_ready: 0,
+            write_partial: Vec::new(),
+        }
+    }
+
+    fn swap_groups(data: &mut [u8], width: usize) {
+        for group in data.chunks_exact_mut(width) {
+            group.reverse();
+        }
+    }
+
+    /// Reads swapped data into `buf`, reading raw data with `read` as needed.  An incomplete trailing group is
+    /// returned as is once `read` reports end of stream.
+    pub(crate) fn read(
+        &mut self,
+        buf: &mut [u8],
+        mut read: impl FnMut(&mut [u8]) -> io::Result<usize>,
+    ) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.read_pos == self.read_ready {
+            self.read_data.drain(..self.read_pos);
+            self.read_pos = 0;
+            self.read_ready = 0;
+            loop {
+                let n = read(buf)?;
+                if n == 0 {
+                    self.read_ready = self.read_data.len();
+                    break;
+                }
+                self.read_data.extend_from_slice(&buf[..n]);
+                let complete = self.read_data.len() / self.width * self.width;
+                if complete > 0 {
+                    ByteSwap::swap_groups(&mut self.read_data[..complete], self.width);
+                    self.read_ready = complete;
+                    break;
+                }
+            }
+        }
+        let n = buf.len().min(self.read_ready - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_data[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+
+    /// Accepts all of `buf`, writing each completed group swapped with `write_all` and holding back the rest.  If
+    /// `write_all` fails, none of `buf` is accepted and the held back bytes are kept.
+    pub(crate) fn write(
+        &mut self,
+        buf: &[u8],
+        write_all: impl FnOnce(&[u8]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        let held = self.write_partial.len();
+        self.write_partial.extend_from_slice(buf);
+        let complete = self.write_partial.len() / self.width * self.width;
+        if complete > 0 {
+            ByteSwap::swap_groups(&mut self.write_partial[..complete], self.width);
+            if let Err(e) = write_all(&self.write_partial[..complete]) {
+                ByteSwap::swap_groups(&mut self.write_partial[..complete], self.width);
+                self.write_partial.truncate(held);
+                return Err(e);
+            }
+            self.write_partial.drain(..complete);
+        }
+        Ok(buf.len())
+    }
+
+    /// Returns the bytes of an incomplete group held back from writing.
+    pub(crate) fn write_partial(&self) -> &[u8] {
+        &self.write_partial
+    }
+
+    /// Takes the bytes of an incomplete group held back from writing.
+    pub(crate) fn take_write_partial(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.write_partial)
+    }
+
+    /// Returns the number of bytes read ahead of the caller, which the position of the underlying reader includes.
+    pub(crate) fn read_ahead(&self) -> usize {
+        self.read_data.len() - self.read_pos
+    }
+
+    /// Discards data read ahead of the caller.
+    pub(crate) fn clear_read(&mut self) {
+        self.read_data.clear();
+        self.read_pos = 0;
+        self.read_ready = 0;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::rand::BufReaderWriterRand;
-    use crate::seq::BufReaderWriterSeq;
-    use std::io::{Read, Seek, SeekFrom, Write};
-    use std::net::{TcpListener, TcpStream};
+    use crate::seq::{BufReaderWriterSeq, BufferBudget};
+    use crate::{
+        BlockSize, BufReaderWriterState, EmptySink, ExportedBuffers, IoObserver, Mode,
+        ModeSwitchError,
+    };
+    use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
     use std::thread;
     use std::time::Duration;
     use tempfile::tempfile;
 
+    #[derive(Default)]
+    struct MockIO {
+        data: Cursor<Vec<u8>>,
+        fail_writes: bool,
+        panic_writes: bool,
+        interrupts: usize,
+        fail_seeks: bool,
+        fail_seeks_after: Option<usize>,
+        writes: Vec<usize>,
+        seeks: usize,
+        accept: Option<usize>,
+        halve_writes: bool,
+    }
+
+    impl MockIO {
+        fn interrupted(&mut self) -> std::io::Result<()> {
+            if self.interrupts > 0 {
+                self.interrupts -= 1;
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl Read for MockIO {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.interrupted()?;
+            self.data.read(buf)
+        }
+    }
+
+    impl Write for MockIO {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.panic_writes {
+                panic!("mock write panic");
+            }
+            self.interrupted()?;
+            if self.fail_writes {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "mock write failure",
+                ));
+            }
+            let buf = match self.accept.as_mut() {
+                Some(0) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "mock write limit reached",
+                    ))
+                }
+                Some(accept) => {
+                    let len = buf.len().min(*accept);
+                    *accept -= len;
+                    &buf[..len]
+                }
+                None => buf,
+            };
+            let buf = if self.halve_writes {
+                &buf[..(buf.len() + 1) / 2]
+            } else {
+                buf
+            };
+            let n = self.data.write(buf)?;
+            self.writes.push(n);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for MockIO {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            if self.fail_seeks || self.fail_seeks_after.map_or(false, |n| self.seeks >= n) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "mock seek failure",
+                ));
+            }
+            self.seeks += 1;
+            self.data.seek(pos)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(usize),
+        Write(usize),
+        Switch(Mode),
+        Flush,
+    }
+
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl IoObserver for Recorder {
+        fn on_read(&mut self, n: usize) {
+            self.0.lock().unwrap().push(Event::Read(n));
+        }
+
+        fn on_write(&mut self, n: usize) {
+            self.0.lock().unwrap().push(Event::Write(n));
+        }
+
+        fn on_switch(&mut self, to: Mode) {
+            self.0.lock().unwrap().push(Event::Switch(to));
+        }
+
+        fn on_flush(&mut self) {
+            self.0.lock().unwrap().push(Event::Flush);
+        }
+    }
+
+    fn echo_server() -> (SocketAddr, thread::JoinHandle<()>) {
+        let tcp = TcpListener::bind("127.0.0.1:0").expect("TcpListener error");
+        let addr = tcp.local_addr().expect("TcpListener address error");
+        let handle = thread::spawn(move || match tcp.accept() {
+            Ok((mut socket, _addr)) => {
+                socket
+                    .set_read_timeout(Some(Duration::new(2, 0)))
+                    .expect("Read timeout");
+                let mut buf = [0_u8; 100];
+                loop {
+                    match socket.read(&mut buf[..]) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            socket.write_all(&buf[0..n]).expect("write io error");
+                        }
+                        Err(e) => match e.kind() {
+                            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => break,
+                            _ => panic!("listener read error {}", e),
+                        },
+                    }
+                }
+            }
+            Err(e) => panic!("TCP Listen error {}", e),
+        });
+        (addr, handle)
+    }
+
     #[test]
     fn testrand() {
         let file = tempfile().expect("Error creating temp file");
@@ -64,7 +618,7 @@ mod tests {
 
         brw.get_mut().set_len(3).expect("Error truncating file");
         brw.seek(SeekFrom::End(0)).expect("Seek error");
-        brw.write(" dog".as_bytes()).expect("Write error");
+        brw.write_all(" dog".as_bytes()).expect("Write error");
 
         brw.seek(SeekFrom::Start(0)).expect("Seek error");
         let mut bin = vec![0; 7];
@@ -74,36 +628,29 @@ mod tests {
         let _f = brw.into_inner().expect("Error extracting underlying file");
     }
 
+    #[test]
+    fn testwriteafterread() {
+        let file = tempfile().expect("Error creating temp file");
+        let mut brw = BufReaderWriterRand::new_writer(file);
+        brw.write_all(&[0; 8]).expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        brw.read_exact(&mut [0; 3]).expect("Read error");
+        brw.write_all(&[9]).expect("Write error");
+        assert_eq!(4, brw.stream_position().expect("Seek error"));
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut bin = vec![0; 8];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(vec![0, 0, 0, 9, 0, 0, 0, 0], bin);
+    }
+
     #[test]
     fn testseq() {
         let data = "The quick brown fox jumps over the lazy dog".to_owned();
         let data_len = data.len();
 
-        let handle = thread::spawn(|| {
-            let tcp = TcpListener::bind("127.0.0.1:8080").expect("TcpListener error");
-            match tcp.accept() {
-                Ok((mut socket, _addr)) => {
-                    socket
-                        .set_read_timeout(Some(Duration::new(2, 0)))
-                        .expect("Read timeout");
-                    let mut buf = vec![0_u8; 100];
-                    loop {
-                        match socket.read(&mut buf[..]) {
-                            Ok(n) => {
-                                socket.write(&buf[0..n]).expect("write io error");
-                            }
-                            Err(e) => match e.kind() {
-                                std::io::ErrorKind::TimedOut => break,
-                                _ => panic!("listener read error {}", e),
-                            },
-                        }
-                    }
-                }
-                Err(e) => panic!("TCP Listen error {}", e),
-            }
-        });
+        let (addr, handle) = echo_server();
 
-        let socket2 = TcpStream::connect("127.0.0.1:8080").expect("TcpStream error");
+        let socket2 = TcpStream::connect(addr).expect("TcpStream error");
         let mut brw = BufReaderWriterSeq::writer_with_capacity(3333, socket2);
         assert_eq!(3333, brw.capacity());
 
@@ -115,21 +662,2942 @@ mod tests {
         assert_eq!(std::str::from_utf8(&buf).unwrap(), &data[0..10]);
 
         let _n = brw.write(data.as_bytes()).expect("write io error");
-        assert_eq!(brw.buffer().map_or(0, |b| b.len()) > 0, true);
+        assert!(brw.buffer().map_or(0, |b| b.len()) > 0);
 
         let mut buf = vec![0_u8; 5];
         let _n = brw.read(&mut buf[..]).expect("read io error");
-        assert_eq!(brw.buffer().unwrap().len() > 0, true);
+        assert!(!brw.buffer().unwrap().is_empty());
         let outdata = std::str::from_utf8(&buf).unwrap();
         assert_eq!(outdata, &data[10..15]);
 
         let mut buf = vec![0_u8; 2 * data_len];
         let n = brw.read(&mut buf[..]).expect("read io error");
-        assert_eq!(n, (2* data_len) - 15);
+        assert_eq!(n, (2 * data_len) - 15);
         let outdata = std::str::from_utf8(&buf).unwrap();
         assert_eq!(&outdata[0..data_len - 15], &data[15..]);
         assert_eq!(&outdata[data_len - 15..n], &data);
 
         handle.join().expect("Join thread error");
     }
+
+    #[test]
+    fn testfrombufreader() {
+        let data = "The quick brown fox jumps over the lazy dog".to_owned();
+        let mut file = tempfile().expect("Error creating temp file");
+        file.write_all(data.as_bytes()).expect("Write error");
+        file.seek(SeekFrom::Start(0)).expect("Seek error");
+
+        let mut r = BufReader::with_capacity(16, file);
+        let mut bin = vec![0; 4];
+        r.read_exact(&mut bin).expect("Read error");
+        let mut brw = BufReaderWriterRand::from_bufreader(r);
+        assert_eq!(16, brw.capacity());
+        assert!(brw.is_reader());
+
+        let mut bin = vec![0; 12];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(&data[4..16], std::str::from_utf8(&bin).unwrap());
+        assert_eq!(16, brw.get_mut().stream_position().expect("Seek error"));
+
+        let mut file = brw.into_inner().expect("Error extracting underlying file");
+        file.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut r = BufReader::with_capacity(16, file);
+        let mut bin = vec![0; 4];
+        r.read_exact(&mut bin).expect("Read error");
+        let mut brw = BufReaderWriterSeq::from_bufreader(r);
+        assert_eq!(16, brw.capacity());
+        assert_eq!(brw.buffer().unwrap(), &data.as_bytes()[4..16]);
+
+        let mut bin = vec![0; data.len() - 4];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(&data[4..], std::str::from_utf8(&bin).unwrap());
+
+        let brw =
+            BufReaderWriterRand::from_bufwriter(BufWriter::with_capacity(100, tempfile().unwrap()));
+        assert_eq!(100, brw.capacity());
+        assert!(!brw.is_reader());
+    }
+
+    #[test]
+    fn testmaxsavedbuffer() {
+        let data = vec![7_u8; 64];
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(64, Cursor::new(data));
+        let mut bin = vec![0; 4];
+        brw.read_exact(&mut bin).expect("Read error");
+
+        brw.set_max_saved_buffer(32);
+        assert!(brw.write(b"x").is_err());
+        assert!(brw.is_reader());
+        assert_eq!(4, brw.read(&mut bin).expect("Read error"));
+
+        brw.set_max_saved_buffer(56);
+        assert_eq!(1, brw.write(b"x").expect("Write error"));
+        assert_eq!(56, brw.buffer().unwrap().len());
+    }
+
+    #[test]
+    fn testmodeswitcherror() {
+        let mock = MockIO {
+            fail_writes: true,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterRand::new_writer(mock);
+        brw.write_all(b"pending").expect("Write error");
+
+        let mut bin = vec![0; 4];
+        let e = brw.read(&mut bin).unwrap_err();
+        assert_eq!(std::io::ErrorKind::BrokenPipe, e.kind());
+        let e = e
+            .into_inner()
+            .unwrap()
+            .downcast::<ModeSwitchError>()
+            .expect("Expected ModeSwitchError");
+        assert_eq!(std::io::ErrorKind::BrokenPipe, e.error().kind());
+        assert_eq!(b"pending".to_vec(), e.into_inner_bytes());
+        assert!(!brw.is_reader());
+
+        brw.get_mut().fail_writes = false;
+        assert_eq!(0, brw.read(&mut bin).expect("Read error"));
+        assert_eq!(b"pending".to_vec(), brw.get_ref().data.get_ref().clone());
+
+        let mock = MockIO {
+            fail_writes: true,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::new_writer(mock);
+        brw.write_all(b"pending").expect("Write error");
+        let e = brw.read(&mut bin).unwrap_err();
+        assert_eq!(std::io::ErrorKind::BrokenPipe, e.kind());
+        assert!(!brw.is_reader());
+    }
+
+    #[test]
+    fn testunderlyingposition() {
+        let mut brw =
+            BufReaderWriterRand::new_writer(tempfile().expect("Error creating temp file"));
+        brw.write_all(&[1_u8; 100]).expect("Write error");
+        assert_eq!(0, brw.underlying_position().expect("Seek error"));
+        assert_eq!(100, brw.stream_position().expect("Seek error"));
+
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut bin = vec![0; 10];
+        brw.read_exact(&mut bin).expect("Read error");
+        let underlying = brw.underlying_position().expect("Seek error");
+        let logical = brw.stream_position().expect("Seek error");
+        assert_eq!(10, logical);
+        assert_eq!(100, underlying);
+        assert!(underlying >= logical);
+        assert_eq!(90, brw.get_bufreader_ref().unwrap().buffer().len());
+    }
+
+    #[test]
+    fn testwriteallsmall() {
+        let mut brw =
+            BufReaderWriterRand::new_reader(tempfile().expect("Error creating temp file"));
+        brw.write_all_small((0..10000).map(|i| (i % 251) as u8))
+            .expect("Write error");
+        assert!(!brw.is_reader());
+
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut bin = Vec::new();
+        brw.read_to_end(&mut bin).expect("Read error");
+        assert_eq!(10000, bin.len());
+        assert!(bin.iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+    }
+
+    #[test]
+    fn testbufread() {
+        let data = "The quick brown fox jumps over the lazy dog".to_owned();
+        let mut brw =
+            BufReaderWriterRand::new_writer(tempfile().expect("Error creating temp file"));
+        brw.write_all(data.as_bytes()).expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+
+        assert_eq!(data.as_bytes(), brw.fill_buf().expect("Read error"));
+        brw.consume(3);
+        let mut bin = vec![0; 6];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(&data[3..9], std::str::from_utf8(&bin).unwrap());
+        assert_eq!(&data.as_bytes()[9..], brw.fill_buf().expect("Read error"));
+
+        let mut line = String::new();
+        brw.read_line(&mut line).expect("Read error");
+        assert_eq!(&data[9..], line);
+    }
+
+    #[test]
+    fn teststreamlen() {
+        let data = "The quick brown fox jumps over the lazy dog".to_owned();
+        let mut brw = BufReaderWriterRand::writer_with_capacity(16, tempfile().unwrap());
+        brw.write_all(data.as_bytes()).expect("Write error");
+        assert_eq!(data.len() as u64, brw.stream_len().expect("Seek error"));
+
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut bin = vec![0; 4];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(12, brw.get_bufreader_ref().unwrap().buffer().len());
+        assert_eq!(data.len() as u64, brw.stream_len().expect("Seek error"));
+        assert_eq!(12, brw.get_bufreader_ref().unwrap().buffer().len());
+
+        let mut bin = vec![0; 16];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(&data[4..20], std::str::from_utf8(&bin).unwrap());
+    }
+
+    #[test]
+    fn testtakewritebuffer() {
+        let mut brw =
+            BufReaderWriterRand::new_writer(tempfile().expect("Error creating temp file"));
+        brw.write_all(b"not yet flushed").expect("Write error");
+        assert_eq!(Some(b"not yet flushed".to_vec()), brw.take_write_buffer());
+        brw.flush().expect("Flush error");
+        assert_eq!(0, brw.get_ref().metadata().unwrap().len());
+        assert_eq!(Some(Vec::new()), brw.take_write_buffer());
+
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut bin = vec![0; 4];
+        assert_eq!(0, brw.read(&mut bin).expect("Read error"));
+        assert_eq!(None, brw.take_write_buffer());
+
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(b"seq").expect("Write error");
+        assert_eq!(Some(b"seq".to_vec()), brw.take_write_buffer());
+        let cursor = brw
+            .into_inner()
+            .expect("Error extracting underlying cursor");
+        assert!(cursor.get_ref().is_empty());
+    }
+
+    #[test]
+    fn testtryintobufreader() {
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(b"kept").expect("Write error");
+        let brw = match brw.try_into_bufreader() {
+            Ok(_) => panic!("Expected writer mode"),
+            Err(brw) => brw,
+        };
+        assert_eq!(b"kept", brw.get_bufwriter_ref().unwrap().buffer());
+        let w = brw.try_into_bufwriter().expect("Expected writer mode");
+        assert_eq!(b"kept", w.buffer());
+
+        let brw = BufReaderWriterRand::new_reader(tempfile().unwrap());
+        let brw = brw.try_into_bufwriter().expect_err("Expected reader mode");
+        assert!(brw.try_into_bufreader().is_ok());
+    }
+
+    #[test]
+    fn testprefetch() {
+        let mut brw = BufReaderWriterRand::writer_with_capacity(16, tempfile().unwrap());
+        brw.write_all(&[5_u8; 40]).expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        assert_eq!(0, brw.buffered_read_len());
+        assert_eq!(16, brw.prefetch().expect("Read error"));
+        assert_eq!(16, brw.buffered_read_len());
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(16, Cursor::new(vec![1_u8; 40]));
+        assert_eq!(16, brw.prefetch().expect("Read error"));
+        assert_eq!(16, brw.buffered_read_len());
+        let mut bin = vec![0; 4];
+        brw.read_exact(&mut bin).expect("Read error");
+        brw.write_all(b"x").expect("Write error");
+        assert_eq!(12, brw.buffered_read_len());
+        assert_eq!(12, brw.prefetch().expect("Read error"));
+        assert_eq!(12, brw.buffered_read_len());
+    }
+
+    #[test]
+    fn testseqseek() {
+        let data: Vec<u8> = (0..20).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(32, Cursor::new(data));
+        let mut bin = vec![0; 10];
+        brw.read_exact(&mut bin).expect("Read error");
+        brw.write_all(&[99]).expect("Write error");
+        assert_eq!(10, brw.buffer().unwrap().len());
+
+        let mut bin = vec![0; 5];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(vec![10, 11, 12, 13, 14], bin);
+        assert_eq!(16, brw.stream_position().expect("Seek error"));
+        assert_eq!(14, brw.seek(SeekFrom::Current(-2)).expect("Seek error"));
+        assert_eq!(7, brw.buffer().unwrap().len());
+        assert_eq!(21, brw.get_ref().position());
+
+        let mut bin = vec![0; 2];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(vec![13, 14], bin);
+
+        assert_eq!(2, brw.seek(SeekFrom::Start(2)).expect("Seek error"));
+        assert!(brw.buffer().is_none());
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(vec![2, 3], bin);
+    }
+
+    #[test]
+    fn testnextwritewillflush() {
+        let mut brw = BufReaderWriterRand::writer_with_capacity(16, tempfile().unwrap());
+        assert!(!brw.next_write_will_flush(15));
+        assert!(brw.next_write_will_flush(16));
+        brw.write_all(&[0; 10]).expect("Write error");
+        assert!(!brw.next_write_will_flush(6));
+        assert!(brw.next_write_will_flush(7));
+
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        assert!(!brw.next_write_will_flush(100));
+
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(16, Cursor::new(Vec::new()));
+        brw.write_all(&[0; 15]).expect("Write error");
+        assert!(!brw.next_write_will_flush(1));
+        assert!(brw.next_write_will_flush(2));
+    }
+
+    #[test]
+    fn testseqeof() {
+        let tcp = TcpListener::bind("127.0.0.1:0").expect("TcpListener error");
+        let addr = tcp.local_addr().expect("TcpListener address error");
+        let handle = thread::spawn(move || {
+            let (mut socket, _addr) = tcp.accept().expect("TCP Listen error");
+            let mut buf = [0_u8; 5];
+            socket.read_exact(&mut buf).expect("listener read error");
+            socket.write_all(&buf).expect("write io error");
+            socket.shutdown(Shutdown::Write).expect("shutdown error");
+            let mut rest = Vec::new();
+            socket.read_to_end(&mut rest).expect("listener read error");
+            rest
+        });
+
+        let socket = TcpStream::connect(addr).expect("TcpStream error");
+        let mut brw = BufReaderWriterSeq::new_writer(socket);
+        brw.write_all(b"hello").expect("Write error");
+        let mut buf = [0_u8; 5];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!(b"hello", &buf);
+        assert!(!brw.is_eof());
+
+        assert_eq!(0, brw.read(&mut buf).expect("Read error"));
+        assert!(brw.is_eof());
+        brw.write_all(b"more").expect("Write error");
+        assert_eq!(0, brw.read(&mut buf).expect("Read error"));
+        assert!(brw.is_eof());
+
+        brw.clear_eof();
+        assert!(!brw.is_eof());
+        assert_eq!(0, brw.read(&mut buf).expect("Read error"));
+        assert!(brw.is_eof());
+
+        brw.get_ref()
+            .shutdown(Shutdown::Write)
+            .expect("shutdown error");
+        assert_eq!(b"more".to_vec(), handle.join().expect("Join thread error"));
+    }
+
+    #[test]
+    fn testframes() {
+        let (addr, handle) = echo_server();
+        let socket = TcpStream::connect(addr).expect("TcpStream error");
+        let mut brw = BufReaderWriterSeq::new_writer(socket);
+
+        brw.write_frame_u32_be(b"first frame").expect("Write error");
+        brw.write_frame_u32_be(b"").expect("Write error");
+        brw.write_frame_u32_be(b"second").expect("Write error");
+        assert_eq!(
+            b"first frame".to_vec(),
+            brw.read_frame_u32_be().expect("Read error")
+        );
+        assert_eq!(
+            Vec::<u8>::new(),
+            brw.read_frame_u32_be().expect("Read error")
+        );
+        assert_eq!(
+            b"second".to_vec(),
+            brw.read_frame_u32_be().expect("Read error")
+        );
+
+        brw.write_all(&[0, 0, 0, 10, 1, 2]).expect("Write error");
+        brw.flush().expect("Flush error");
+        brw.get_ref()
+            .shutdown(Shutdown::Write)
+            .expect("shutdown error");
+        let e = brw.read_frame_u32_be().unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, e.kind());
+        handle.join().expect("Join thread error");
+
+        let mut brw =
+            BufReaderWriterSeq::new_reader(Cursor::new(vec![0xff, 0xff, 0xff, 0xff, 1, 2, 3]));
+        let e = brw.read_frame_u32_be().unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, e.kind());
+    }
+
+    #[test]
+    fn testreadhitratio() {
+        let data: Vec<u8> = (0..20).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        assert_eq!(0.0, brw.read_hit_ratio());
+        let mut bin = vec![0; 2];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(0, brw.write(&[]).expect("Write error"));
+        assert_eq!(6, brw.buffer().unwrap().len());
+
+        let mut bin = vec![0; 4];
+        brw.read_exact(&mut bin).expect("Read error");
+        let mut bin = vec![0; 4];
+        assert_eq!(4, brw.read(&mut bin).expect("Read error"));
+        assert_eq!(vec![6, 7, 8, 9], bin);
+        assert_eq!(6, brw.buffer_hit_bytes());
+        assert_eq!(4, brw.inner_read_bytes());
+        assert_eq!(0.6, brw.read_hit_ratio());
+    }
+
+    #[test]
+    fn testseekfresh() {
+        let file = tempfile::NamedTempFile::new().expect("Error creating temp file");
+        std::fs::write(file.path(), b"old data").expect("Write error");
+        let mut brw = BufReaderWriterRand::new_reader(file.reopen().unwrap());
+        let mut bin = vec![0; 3];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(b"old", &bin[..]);
+
+        std::fs::write(file.path(), b"new data").expect("Write error");
+        assert_eq!(
+            0,
+            brw.seek_fresh(SeekFrom::Current(-3)).expect("Seek error")
+        );
+        assert_eq!(0, brw.buffered_read_len());
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(b"new", &bin[..]);
+
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        brw.write_all(b"NEW").expect("Write error");
+        assert_eq!(4, brw.seek_fresh(SeekFrom::Start(4)).expect("Seek error"));
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(b"dat", &bin[..]);
+        assert_eq!(b"NEW data".to_vec(), std::fs::read(file.path()).unwrap());
+    }
+
+    #[test]
+    fn testobserver() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut brw = BufReaderWriterRand::new_writer(tempfile().unwrap());
+        brw.set_observer(Box::new(Recorder(events.clone())));
+        assert_eq!(Mode::Writer, brw.mode());
+        brw.write_all(b"abcdef").expect("Write error");
+        brw.flush().expect("Flush error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut bin = vec![0; 4];
+        brw.read_exact(&mut bin).expect("Read error");
+        assert_eq!(Mode::Reader, brw.mode());
+        brw.write_all(b"x").expect("Write error");
+        assert_eq!(
+            vec![
+                Event::Write(6),
+                Event::Flush,
+                Event::Switch(Mode::Reader),
+                Event::Read(4),
+                Event::Switch(Mode::Writer),
+                Event::Write(1),
+            ],
+            *events.lock().unwrap()
+        );
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        brw.set_observer(Box::new(Recorder(events.clone())));
+        brw.write_all(b"abc").expect("Write error");
+        assert_eq!(0, brw.read(&mut bin).expect("Read error"));
+        assert_eq!(
+            vec![Event::Write(3), Event::Switch(Mode::Reader), Event::Read(0)],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn testflushcoalesced() {
+        let mut brw = BufReaderWriterRand::writer_with_capacity(100, MockIO::default());
+        brw.write_all(&[1; 30]).expect("Write error");
+        brw.write_all(&[2; 40]).expect("Write error");
+        assert_eq!(70, brw.flush_coalesced().expect("Flush error"));
+        assert_eq!(vec![70], brw.get_ref().writes);
+        assert_eq!(0, brw.flush_coalesced().expect("Flush error"));
+
+        brw.write_all(&[3; 100]).expect("Write error");
+        brw.write_all(&[4; 50]).expect("Write error");
+        brw.write_all(&[5; 50]).expect("Write error");
+        brw.get_mut().fail_writes = true;
+        assert!(brw.flush_coalesced().is_err());
+        assert_eq!(100, brw.get_bufwriter_ref().unwrap().buffer().len());
+        brw.get_mut().fail_writes = false;
+        assert_eq!(100, brw.flush_coalesced().expect("Flush error"));
+        assert_eq!(vec![70, 100, 100], brw.get_ref().writes);
+
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(100, MockIO::default());
+        brw.write_all(&[1; 50]).expect("Write error");
+        assert_eq!(50, brw.flush_coalesced().expect("Flush error"));
+        assert_eq!(vec![50], brw.get_ref().writes);
+    }
+
+    #[test]
+    fn testbufferbudget() {
+        let budget = Arc::new(BufferBudget::new(20000));
+        let brw1 = BufReaderWriterSeq::with_budget(budget.clone(), Cursor::new(Vec::new()));
+        let brw2 = BufReaderWriterSeq::with_budget(budget.clone(), Cursor::new(Vec::new()));
+        let mut brw3 = BufReaderWriterSeq::with_budget(budget.clone(), Cursor::new(Vec::new()));
+        let brw4 = BufReaderWriterSeq::with_budget(budget.clone(), Cursor::new(Vec::new()));
+        assert_eq!(8192, brw1.capacity());
+        assert_eq!(8192, brw2.capacity());
+        assert_eq!(3616, brw3.capacity());
+        assert_eq!(0, brw4.capacity());
+        assert_eq!(20000, budget.used());
+
+        drop(brw1);
+        drop(brw4);
+        assert_eq!(11808, budget.used());
+        assert_eq!(0, brw3.read(&mut [0; 4]).expect("Read error"));
+        assert_eq!(8192, brw3.capacity());
+        assert_eq!(16384, budget.used());
+
+        let _inner = brw2
+            .into_inner()
+            .expect("Error extracting underlying cursor");
+        drop(brw3);
+        assert_eq!(0, budget.used());
+    }
+
+    #[test]
+    fn testswapcapacities() {
+        let mut brw = BufReaderWriterRand::writer_with_capacities(16, 64, Cursor::new(Vec::new()));
+        assert_eq!(64, brw.capacity());
+        brw.write_all(&[1, 2, 3, 4]).expect("Write error");
+        brw.swap_capacities().expect("Swap error");
+        assert_eq!(16, brw.capacity());
+        brw.seek(SeekFrom::Start(1)).expect("Seek error");
+        let mut b = [0; 1];
+        brw.read_exact(&mut b).expect("Read error");
+        assert_eq!(2, b[0]);
+        assert_eq!(64, brw.capacity());
+        brw.swap_capacities().expect("Swap error");
+        assert_eq!(16, brw.capacity());
+        brw.read_exact(&mut b).expect("Read error");
+        assert_eq!(3, b[0]);
+
+        let mut brw =
+            BufReaderWriterSeq::reader_with_capacities(16, 64, Cursor::new(vec![1, 2, 3, 4]));
+        brw.read_exact(&mut b).expect("Read error");
+        assert_eq!(16, brw.capacity());
+        brw.swap_capacities().expect("Swap error");
+        assert_eq!(64, brw.capacity());
+        let mut rest = Vec::new();
+        brw.read_to_end(&mut rest).expect("Read error");
+        assert_eq!(vec![2, 3, 4], rest);
+        brw.write_all(&[5]).expect("Write error");
+        assert_eq!(16, brw.capacity());
+        brw.swap_capacities().expect("Swap error");
+        assert_eq!(64, brw.capacity());
+        assert_eq!(
+            vec![1, 2, 3, 4, 5],
+            brw.into_inner()
+                .expect("Error extracting underlying cursor")
+                .into_inner()
+        );
+    }
+
+    #[test]
+    fn testpoisoned() {
+        let mock = MockIO {
+            panic_writes: true,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::new_writer(mock);
+        brw.write_all(&[1, 2, 3]).expect("Write error");
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| brw.flush_coalesced()));
+        assert!(result.is_err());
+
+        let e = brw.write(&[4]).expect_err("Write succeeded after panic");
+        assert_eq!(std::io::ErrorKind::Other, e.kind());
+        assert_eq!("BufReaderWriter used after being unwrapped", e.to_string());
+        let e = brw
+            .read(&mut [0; 1])
+            .expect_err("Read succeeded after panic");
+        assert_eq!("BufReaderWriter used after being unwrapped", e.to_string());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| brw.is_reader()));
+        let payload = result.expect_err("is_reader succeeded after panic");
+        assert_eq!(
+            Some("BufReaderWriter used after being unwrapped"),
+            payload.downcast_ref::<String>().map(String::as_str)
+        );
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn testreadbuf() {
+        use std::io::BorrowedBuf;
+        use std::mem::MaybeUninit;
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(&[1, 2, 3, 4, 5]).expect("Write error");
+        brw.seek(SeekFrom::Start(1)).expect("Seek error");
+        let mut storage = [MaybeUninit::<u8>::uninit(); 3];
+        let mut bb = BorrowedBuf::from(&mut storage[..]);
+        brw.read_buf(bb.unfilled()).expect("Read error");
+        assert_eq!(&[2, 3, 4], bb.filled());
+
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(vec![1, 2, 3, 4, 5, 6]));
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        brw.write_all(&[7]).expect("Write error");
+        let mut storage = [MaybeUninit::<u8>::uninit(); 2];
+        let mut bb = BorrowedBuf::from(&mut storage[..]);
+        brw.read_buf(bb.unfilled()).expect("Read error");
+        assert_eq!(&[2, 3], bb.filled());
+        let mut storage = [MaybeUninit::<u8>::uninit(); 8];
+        let mut bb = BorrowedBuf::from(&mut storage[..]);
+        brw.read_buf(bb.unfilled()).expect("Read error");
+        assert_eq!(&[4, 5, 6], bb.filled());
+        assert_eq!(5, brw.buffer_hit_bytes());
+        assert_eq!(4, brw.total_ops());
+
+        let mock = MockIO {
+            data: Cursor::new(vec![1, 2, 3, 4]),
+            interrupts: 1,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::new_reader(mock);
+        brw.set_retry_on_interrupt(true);
+        let mut storage = [MaybeUninit::<u8>::uninit(); 2];
+        let mut bb = BorrowedBuf::from(&mut storage[..]);
+        brw.read_buf(bb.unfilled()).expect("Read error");
+        assert_eq!(&[1, 2], bb.filled());
+        assert_eq!(1, brw.total_ops());
+        brw.set_byteswap(2);
+        let mut bb = BorrowedBuf::from(&mut storage[..]);
+        brw.read_buf(bb.unfilled()).expect("Read error");
+        assert_eq!(&[4, 3], bb.filled());
+
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(vec![1, 2, 3, 4]));
+        brw.set_byteswap(4);
+        let mut storage = [MaybeUninit::<u8>::uninit(); 4];
+        let mut bb = BorrowedBuf::from(&mut storage[..]);
+        brw.read_buf(bb.unfilled()).expect("Read error");
+        assert_eq!(&[4, 3, 2, 1], bb.filled());
+        assert_eq!(1, brw.total_ops());
+    }
+
+    #[test]
+    fn testwritetxn() {
+        let mut brw = BufReaderWriterRand::writer_with_capacity(16, Cursor::new(Vec::new()));
+        brw.write_all(&[1, 2, 3]).expect("Write error");
+        brw.begin_write_txn();
+        brw.write_all(&[4, 5]).expect("Write error");
+        brw.rollback().expect("Rollback error");
+        brw.write_all(&[6]).expect("Write error");
+        brw.begin_write_txn();
+        brw.write_all(&[7]).expect("Write error");
+        brw.commit().expect("Commit error");
+        assert_eq!(
+            vec![1, 2, 3, 6, 7],
+            brw.into_inner()
+                .expect("Error extracting underlying cursor")
+                .into_inner()
+        );
+
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(4, Cursor::new(Vec::new()));
+        brw.write_all(&[1, 2]).expect("Write error");
+        brw.begin_write_txn();
+        brw.write_all(&[3, 4, 5]).expect("Write error");
+        assert!(brw.rollback().is_err());
+        assert!(brw.rollback().is_err());
+        assert_eq!(
+            vec![1, 2, 3, 4, 5],
+            brw.into_inner()
+                .expect("Error extracting underlying cursor")
+                .into_inner()
+        );
+    }
+
+    #[test]
+    fn testinnerstreamposition() {
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(&[1, 2, 3]).expect("Write error");
+        assert_eq!(3, brw.inner_stream_position().expect("Position error"));
+        brw.write_all(&[4, 5]).expect("Write error");
+        assert_eq!(5, brw.inner_stream_position().expect("Position error"));
+        assert_eq!(0, brw.get_bufwriter_ref().unwrap().buffer().len());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn testdebugassertinvariants() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(16, Cursor::new(data));
+        let mut buf = [0; 5];
+        brw.read_exact(&mut buf).expect("Read error");
+        brw.write_all(&[1, 2, 3]).expect("Write error");
+        brw.read_exact(&mut buf).expect("Read error");
+        brw.consume(3);
+        brw.write_all(&[4; 20]).expect("Write error");
+        brw.read_exact(&mut [0; 3]).expect("Read error");
+        brw.swap_capacities().expect("Swap error");
+        brw.read_exact(&mut [0; 10]).expect("Read error");
+        brw.flush_coalesced().expect("Flush error");
+        brw.debug_assert_invariants();
+        brw.read_to_end(&mut Vec::new()).expect("Read error");
+        brw.debug_assert_invariants();
+    }
+
+    #[test]
+    fn testwriteallflushed() {
+        let file = tempfile().expect("Error creating temp file");
+        let mut reader = file.try_clone().expect("Error cloning temp file");
+        let mut brw = BufReaderWriterRand::new_writer(file);
+        brw.write_all_flushed(b"flushed").expect("Write error");
+        let mut s = String::new();
+        reader.seek(SeekFrom::Start(0)).expect("Seek error");
+        reader.read_to_string(&mut s).expect("Read error");
+        assert_eq!("flushed", s);
+    }
+
+    #[test]
+    fn testreaduntilinto() {
+        let data = b"one;two;three;four".to_vec();
+        let mut brw = BufReaderWriterRand::reader_with_capacity(4, Cursor::new(data.clone()));
+        let mut buf = Vec::new();
+        assert_eq!(4, brw.read_until_into(b';', &mut buf).expect("Read error"));
+        assert_eq!(4, brw.read_until_into(b';', &mut buf).expect("Read error"));
+        assert_eq!(b"one;two;".to_vec(), buf);
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(6, Cursor::new(data));
+        let mut buf = Vec::new();
+        assert_eq!(4, brw.read_until_into(b';', &mut buf).expect("Read error"));
+        brw.write_all(b"!").expect("Write error");
+        assert_eq!(2, brw.buffered_read_len());
+        assert_eq!(3, brw.read_until_into(b';', &mut buf).expect("Read error"));
+        assert_eq!(6, brw.read_until_into(b';', &mut buf).expect("Read error"));
+        assert_eq!(b"one;tw;three;".to_vec(), buf);
+        buf.clear();
+        assert_eq!(4, brw.read_until_into(b';', &mut buf).expect("Read error"));
+        assert_eq!(b"four".to_vec(), buf);
+        assert_eq!(0, brw.read_until_into(b';', &mut buf).expect("Read error"));
+        assert!(brw.is_eof());
+    }
+
+    #[test]
+    fn testneedsflush() {
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(vec![1, 2, 3]));
+        assert!(!brw.needs_flush());
+        brw.write_all(&[4]).expect("Write error");
+        assert!(brw.needs_flush());
+        brw.flush().expect("Flush error");
+        assert!(!brw.needs_flush());
+
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        assert!(!brw.needs_flush());
+        brw.write_all(&[1, 2]).expect("Write error");
+        assert!(brw.needs_flush());
+        brw.flush().expect("Flush error");
+        assert!(!brw.needs_flush());
+    }
+
+    #[test]
+    fn testcopy() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut file = tempfile().expect("Error creating temp file");
+        file.write_all(&data).expect("Write error");
+        file.seek(SeekFrom::Start(0)).expect("Seek error");
+
+        let mut brw = BufReaderWriterRand::writer_with_capacity(32, file);
+        let mut sink = Vec::new();
+        assert_eq!(100, std::io::copy(&mut brw, &mut sink).expect("Copy error"));
+        assert_eq!(data, sink);
+
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        brw.write_all(&[200]).expect("Write error");
+        let mut sink = MockIO::default();
+        assert_eq!(99, brw.copy_to(&mut sink).expect("Copy error"));
+        assert_eq!(&data[1..], &sink.data.get_ref()[..]);
+        assert_eq!(vec![32, 32, 32, 3], sink.writes);
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(32, Cursor::new(data.clone()));
+        brw.read_exact(&mut [0; 10]).expect("Read error");
+        brw.write_all(&[200]).expect("Write error");
+        let mut sink = MockIO::default();
+        assert_eq!(89, brw.copy_to(&mut sink).expect("Copy error"));
+        assert_eq!(vec![22, 32, 32, 3], sink.writes);
+    }
+
+    #[test]
+    fn testreadexactat() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut brw =
+            BufReaderWriterRand::new_writer(tempfile().expect("Error creating temp file"));
+        brw.write_all(&data).expect("Write error");
+        assert_eq!(
+            vec![50, 51, 52],
+            brw.read_exact_at(50, 3).expect("Read error")
+        );
+        assert_eq!(53, brw.stream_position().expect("Position error"));
+        assert_eq!(vec![5, 6], brw.read_exact_at(5, 2).expect("Read error"));
+        assert_eq!(
+            vec![90, 91, 92, 93],
+            brw.read_exact_at(90, 4).expect("Read error")
+        );
+        let e = brw
+            .read_exact_at(98, 4)
+            .expect_err("Read past end succeeded");
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, e.kind());
+    }
+
+    #[test]
+    fn testretryoninterrupt() {
+        let mock = MockIO {
+            data: Cursor::new(vec![1, 2, 3]),
+            interrupts: 1,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterRand::new_reader(mock);
+        let e = brw.read(&mut [0; 2]).expect_err("Read not interrupted");
+        assert_eq!(std::io::ErrorKind::Interrupted, e.kind());
+
+        brw.get_mut().interrupts = 1;
+        brw.set_retry_on_interrupt(true);
+        let mut buf = [0; 2];
+        assert_eq!(2, brw.read(&mut buf).expect("Read error"));
+        assert_eq!([1, 2], buf);
+
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(1, MockIO::default());
+        brw.set_retry_on_interrupt(true);
+        brw.get_mut().interrupts = 1;
+        assert_eq!(2, brw.write(&[4, 5]).expect("Write error"));
+        assert_eq!(vec![2], brw.get_ref().writes);
+    }
+
+    #[test]
+    fn teststate() {
+        let mut brw = BufReaderWriterRand::reader_with_capacity(16, Cursor::new(vec![0; 32]));
+        brw.read_exact(&mut [0; 4]).expect("Read error");
+        assert_eq!(
+            BufReaderWriterState {
+                mode: Mode::Reader,
+                capacity: 16,
+                pending_write: 0,
+                buffered_read: 12,
+                saved_buffer: 0,
+            },
+            brw.state()
+        );
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(16, Cursor::new(vec![0; 32]));
+        brw.read_exact(&mut [0; 4]).expect("Read error");
+        brw.write_all(&[1, 2, 3]).expect("Write error");
+        assert_eq!(
+            BufReaderWriterState {
+                mode: Mode::Writer,
+                capacity: 16,
+                pending_write: 3,
+                buffered_read: 0,
+                saved_buffer: 12,
+            },
+            brw.state()
+        );
+    }
+
+    #[test]
+    fn testhighwatermark() {
+        let mut brw =
+            BufReaderWriterRand::new_writer(tempfile().expect("Error creating temp file"));
+        brw.write_all(&[1; 10]).expect("Write error");
+        assert_eq!(10, brw.high_water_mark());
+        brw.seek(SeekFrom::Start(50)).expect("Seek error");
+        brw.write_all(&[2; 5]).expect("Write error");
+        assert_eq!(55, brw.high_water_mark());
+        brw.seek(SeekFrom::Start(20)).expect("Seek error");
+        brw.write_all(&[3; 5]).expect("Write error");
+        assert_eq!(55, brw.high_water_mark());
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        brw.write_all_small(vec![4; 3]).expect("Write error");
+        assert_eq!(55, brw.high_water_mark());
+        brw.seek(SeekFrom::Start(54)).expect("Seek error");
+        brw.write_all_small(vec![5; 4]).expect("Write error");
+        assert_eq!(58, brw.high_water_mark());
+    }
+
+    #[test]
+    fn testmaxwritechunk() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut brw = BufReaderWriterSeq::new_writer(MockIO::default());
+        brw.set_observer(Box::new(Recorder(events.clone())));
+        brw.set_max_write_chunk(Some(64 * 1024));
+        let data = vec![7; 1024 * 1024];
+        brw.write_all(&data).expect("Write error");
+        let events = events.lock().unwrap();
+        assert_eq!(32, events.len());
+        assert!(events
+            .chunks(2)
+            .all(|e| e == [Event::Write(64 * 1024), Event::Flush]));
+        assert_eq!(vec![64 * 1024; 16], brw.get_ref().writes);
+    }
+
+    #[test]
+    fn testrefreshlen() {
+        let file = tempfile::NamedTempFile::new().expect("Error creating temp file");
+        let mut appender = std::fs::OpenOptions::new()
+            .append(true)
+            .open(file.path())
+            .expect("Error opening temp file");
+        appender.write_all(b"first").expect("Write error");
+
+        let mut brw =
+            BufReaderWriterRand::new_reader(file.reopen().expect("Error opening temp file"));
+        let mut s = String::new();
+        brw.read_to_string(&mut s).expect("Read error");
+        assert_eq!("first", s);
+        assert_eq!(5, brw.refresh_len().expect("Length error"));
+
+        appender.write_all(b" second").expect("Write error");
+        assert_eq!(12, brw.refresh_len().expect("Length error"));
+        assert_eq!(b" second", brw.fill_buf().expect("Read error"));
+        s.clear();
+        brw.read_to_string(&mut s).expect("Read error");
+        assert_eq!(" second", s);
+        assert_eq!(12, brw.stream_position().expect("Position error"));
+    }
+
+    #[test]
+    fn testnewlazy() {
+        let mut brw = BufReaderWriterRand::new_lazy(Cursor::new(vec![1, 2, 3]));
+        assert_eq!(Mode::Unset, brw.mode());
+        assert_eq!(0, brw.capacity());
+        assert!(brw.get_bufreader_ref().is_none() && brw.get_bufwriter_ref().is_none());
+        assert_eq!(1, brw.seek(SeekFrom::Start(1)).expect("Seek error"));
+        let mut buf = [0; 2];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!([2, 3], buf);
+        assert_eq!(Mode::Reader, brw.mode());
+        assert_eq!(8192, brw.capacity());
+
+        let mut brw = BufReaderWriterSeq::new_lazy(Cursor::new(Vec::new()));
+        assert_eq!(Mode::Unset, brw.mode());
+        assert_eq!(0, brw.capacity());
+        brw.write_all(&[4, 5]).expect("Write error");
+        assert_eq!(Mode::Writer, brw.mode());
+        assert_eq!(8192, brw.capacity());
+        assert_eq!(
+            vec![4, 5],
+            brw.into_inner()
+                .expect("Error extracting underlying cursor")
+                .into_inner()
+        );
+    }
+
+    #[test]
+    fn testisinitialized() {
+        let mut brw = BufReaderWriterSeq::new_lazy(Cursor::new(Vec::new()));
+        assert!(!brw.is_initialized());
+        assert!(!brw.is_reader());
+        brw.write_all(&[1]).expect("Write error");
+        assert!(brw.is_initialized());
+        assert!(!brw.is_reader());
+
+        let brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        assert!(brw.is_initialized());
+    }
+
+    #[test]
+    fn testreadahead() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(data.clone()));
+        brw.set_read_ahead(false);
+        let mut buf = [0; 4];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!([0, 1, 2, 3], buf);
+        assert_eq!(4, brw.get_ref().position());
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!(8, brw.get_ref().position());
+        assert_eq!(0, brw.buffered_read_len());
+
+        brw.set_read_ahead(true);
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!(32, brw.get_ref().position());
+        assert_eq!(20, brw.buffered_read_len());
+    }
+
+    #[test]
+    fn testwritefillthen() {
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(8, MockIO::default());
+        brw.write_all(&[1, 2, 3]).expect("Write error");
+        let e = brw
+            .write_fill_then(&[4; 4], &[9])
+            .expect_err("Short fill succeeded");
+        assert_eq!(std::io::ErrorKind::InvalidInput, e.kind());
+        brw.write_fill_then(&[4; 5], &[9, 9]).expect("Write error");
+        assert_eq!(vec![8], brw.get_ref().writes);
+        assert_eq!(&[9, 9], brw.get_bufwriter_ref().unwrap().buffer());
+
+        let mut brw = BufReaderWriterRand::writer_with_capacity(4, MockIO::default());
+        brw.write_fill_then(&[1; 4], &[2]).expect("Write error");
+        assert_eq!(vec![4], brw.get_ref().writes);
+    }
+
+    #[test]
+    fn testflushdurable() {
+        let file = tempfile().expect("Error creating temp file");
+        let mut brw =
+            BufReaderWriterSeq::new_writer(file.try_clone().expect("Error cloning temp file"));
+        brw.write_all(b"durable").expect("Write error");
+        brw.flush_durable().expect("Flush error");
+        assert_eq!(7, file.metadata().expect("Metadata error").len());
+
+        let mut brw = BufReaderWriterRand::new_writer(file);
+        brw.write_all(b" data").expect("Write error");
+        brw.flush_durable().expect("Flush error");
+        assert_eq!(12, brw.get_ref().metadata().expect("Metadata error").len());
+    }
+
+    #[cfg(feature = "positioned-io")]
+    #[test]
+    fn testwriteat() {
+        use positioned_io::WriteAt;
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(vec![0; 8]));
+        brw.write_all_at(4, &[1, 2]).expect("Write error");
+        brw.write_all_at(1, &[3]).expect("Write error");
+        WriteAt::flush(&mut brw).expect("Flush error");
+        assert_eq!(vec![0, 3, 0, 0, 1, 2, 0, 0], *brw.get_ref().get_ref());
+        assert_eq!(2, brw.stream_position().expect("Position error"));
+    }
+
+    #[cfg(feature = "positioned-io")]
+    #[test]
+    fn testreadat() {
+        use positioned_io::ReadAt;
+
+        let mut brw = BufReaderWriterRand::new_writer(tempfile().expect("tempfile error"));
+        brw.write_all(&[1, 2, 3, 4, 5]).expect("Write error");
+        let mut buf = [0; 2];
+        let e = brw.read_at(1, &mut buf).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, e.kind());
+        brw.flush().expect("Flush error");
+        ReadAt::read_exact_at(&brw, 1, &mut buf).expect("Read error");
+        assert_eq!([2, 3], buf);
+        brw.set_byteswap(2);
+        brw.write_all(&[6]).expect("Write error");
+        assert!(brw.read_at(0, &mut buf).is_err());
+    }
+
+    #[test]
+    fn testensurewritecapacity() {
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(8, MockIO::default());
+        brw.write_all(&[1, 2, 3]).expect("Write error");
+        brw.ensure_write_capacity(32).expect("Capacity error");
+        assert_eq!(vec![3], brw.get_ref().writes);
+        assert_eq!(32, brw.capacity());
+        brw.write_all(&[4; 20]).expect("Write error");
+        assert_eq!(vec![3], brw.get_ref().writes);
+        assert_eq!(20, brw.get_bufwriter_ref().unwrap().buffer().len());
+        brw.ensure_write_capacity(16).expect("Capacity error");
+        assert_eq!(32, brw.capacity());
+
+        let mut brw = BufReaderWriterRand::reader_with_capacity(8, MockIO::default());
+        brw.ensure_write_capacity(64).expect("Capacity error");
+        assert_eq!(8, brw.capacity());
+        brw.write_all(&[5; 40]).expect("Write error");
+        assert_eq!(64, brw.capacity());
+        assert!(brw.get_ref().writes.is_empty());
+    }
+
+    #[test]
+    fn testfillandpeek() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        assert_eq!(&[0, 1, 2], brw.fill_and_peek(3).expect("Peek error"));
+        assert_eq!(
+            &[0, 1, 2, 3, 4, 5, 6, 7],
+            brw.fill_and_peek(20).expect("Peek error")
+        );
+        brw.consume(6);
+        brw.write_all(&[99]).expect("Write error");
+        assert_eq!(&[6], brw.fill_and_peek(1).expect("Peek error"));
+        assert_eq!(
+            &[6, 7, 9, 10, 11],
+            brw.fill_and_peek(5).expect("Peek error")
+        );
+        brw.consume(2);
+        let mut rest = Vec::new();
+        brw.read_to_end(&mut rest).expect("Read error");
+        assert_eq!((9..32).collect::<Vec<u8>>(), rest);
+
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        brw.write_all(&[99]).expect("Write error");
+        assert_eq!(
+            &[2, 3, 4, 5, 6, 7, 9, 10],
+            brw.fill_and_peek(usize::MAX).expect("Peek error")
+        );
+    }
+
+    #[test]
+    fn testwritechunks() {
+        let mut brw =
+            BufReaderWriterRand::new_writer(tempfile().expect("Error creating temp file"));
+        let chunks: Vec<&[u8]> = vec![b"one ", b"two ", b"three"];
+        assert_eq!(13, brw.write_chunks(chunks).expect("Write error"));
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut s = String::new();
+        brw.read_to_string(&mut s).expect("Read error");
+        assert_eq!("one two three", s);
+
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(Vec::new()));
+        let owned = [vec![1, 2], vec![], vec![3]];
+        assert_eq!(
+            3,
+            brw.write_chunks(owned.iter().map(Vec::as_slice))
+                .expect("Write error")
+        );
+        assert_eq!(
+            vec![1, 2, 3],
+            brw.into_inner()
+                .expect("Error extracting underlying cursor")
+                .into_inner()
+        );
+    }
+
+    #[test]
+    fn testseekfailureonswitch() {
+        let mock = MockIO {
+            data: Cursor::new(vec![1, 2, 3, 4, 5, 6]),
+            fail_seeks: true,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterRand::new_reader(mock);
+        let mut buf = [0; 2];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert!(brw.write(&[9]).is_err());
+        assert!(brw.is_reader());
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!([3, 4], buf);
+
+        brw.get_mut().fail_seeks = false;
+        brw.write_all(&[9]).expect("Write error");
+        brw.flush().expect("Flush error");
+        assert_eq!(&vec![1, 2, 3, 4, 9, 6], brw.get_ref().data.get_ref());
+    }
+
+    #[test]
+    fn testcurrentrun() {
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(vec![0; 16]));
+        assert_eq!((Mode::Reader, 0), brw.current_run());
+        for i in 1..=5 {
+            brw.read_exact(&mut [0; 1]).expect("Read error");
+            assert_eq!((Mode::Reader, i), brw.current_run());
+        }
+        for i in 1..=3 {
+            brw.write_all(&[1]).expect("Write error");
+            assert_eq!((Mode::Writer, i), brw.current_run());
+        }
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        assert_eq!((Mode::Reader, 1), brw.current_run());
+
+        let mut brw = BufReaderWriterSeq::new_lazy(Cursor::new(Vec::new()));
+        assert_eq!((Mode::Unset, 0), brw.current_run());
+        brw.write_all(&[1, 2]).expect("Write error");
+        assert_eq!((Mode::Writer, 1), brw.current_run());
+    }
+
+    #[test]
+    fn testemptysink() {
+        let mut brw = BufReaderWriterRand::empty();
+        brw.write_all(&[1; 100_000]).expect("Write error");
+        brw.flush().expect("Flush error");
+        assert_eq!(0, brw.read(&mut [0; 16]).expect("Read error"));
+        assert_eq!(0, brw.seek(SeekFrom::Start(10)).expect("Seek error"));
+
+        let mut brw = BufReaderWriterSeq::new_reader(EmptySink);
+        assert_eq!(0, brw.read(&mut [0; 16]).expect("Read error"));
+        brw.write_all(&[1; 16]).expect("Write error");
+    }
+
+    #[test]
+    fn testonautoflush() {
+        let flushed = Arc::new(Mutex::new(Vec::new()));
+        let mut brw = BufReaderWriterRand::writer_with_capacity(8, MockIO::default());
+        let f = flushed.clone();
+        brw.set_on_auto_flush(move |n| f.lock().unwrap().push(n));
+        brw.write_all(&[1; 5]).expect("Write error");
+        brw.write_all(&[2; 2]).expect("Write error");
+        assert!(flushed.lock().unwrap().is_empty());
+        brw.write_all(&[3; 4]).expect("Write error");
+        assert_eq!(vec![7], *flushed.lock().unwrap());
+        brw.flush().expect("Flush error");
+        brw.write_all(&[4; 20]).expect("Write error");
+        assert_eq!(vec![7], *flushed.lock().unwrap());
+
+        let flushed = Arc::new(Mutex::new(Vec::new()));
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(8, MockIO::default());
+        let f = flushed.clone();
+        brw.set_on_auto_flush(move |n| f.lock().unwrap().push(n));
+        brw.write_all(&[1; 3]).expect("Write error");
+        brw.write_all(&[2; 10]).expect("Write error");
+        assert_eq!(vec![3], *flushed.lock().unwrap());
+    }
+
+    #[test]
+    fn testbuffersequal() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut a = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data.clone()));
+        let mut b = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        for brw in [&mut a, &mut b] {
+            brw.read_exact(&mut [0; 3]).expect("Read error");
+            brw.write_all(&[1, 2]).expect("Write error");
+        }
+        assert!(a.buffers_equal(&b));
+        a.write_all(&[3]).expect("Write error");
+        assert!(!a.buffers_equal(&b));
+
+        let mut a = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        let mut b = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        a.write_all(b"same").expect("Write error");
+        b.write_all(b"same").expect("Write error");
+        assert!(a.buffers_equal(&b));
+        assert_eq!(0, b.read(&mut [0; 1]).expect("Read error"));
+        assert!(!a.buffers_equal(&b));
+    }
+
+    #[test]
+    fn testchunksinto() {
+        let data: Vec<u8> = (0..10).collect();
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(data.clone()));
+        let mut scratch = [0; 4];
+        let mut chunks = brw.chunks_into(&mut scratch);
+        let mut lens = Vec::new();
+        let mut all = Vec::new();
+        while let Some(chunk) = chunks.next_chunk() {
+            let chunk = chunk.expect("Read error");
+            lens.push(chunk.len());
+            all.extend_from_slice(chunk);
+        }
+        assert_eq!(vec![4, 4, 2], lens);
+        assert_eq!(data, all);
+
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(data));
+        let mut chunks = brw.chunks_into(&mut scratch);
+        assert_eq!(
+            &[0, 1, 2, 3],
+            chunks.next_chunk().unwrap().expect("Read error")
+        );
+    }
+
+    #[test]
+    fn testrecords() {
+        let data: Vec<u8> = (0..12).collect();
+        let mut brw = BufReaderWriterRand::new_writer(tempfile().expect("tempfile error"));
+        brw.write_all(&data).expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let records: Vec<Vec<u8>> = brw
+            .records(4)
+            .collect::<std::io::Result<_>>()
+            .expect("Read error");
+        assert_eq!(
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9, 10, 11]],
+            records
+        );
+
+        brw.write_all(&[12, 13]).expect("Write error");
+        brw.seek(SeekFrom::Start(4)).expect("Seek error");
+        let mut records = brw.records(4);
+        assert_eq!(
+            vec![4, 5, 6, 7],
+            records.next().unwrap().expect("Read error")
+        );
+        assert_eq!(
+            vec![8, 9, 10, 11],
+            records.next().unwrap().expect("Read error")
+        );
+        let err = records.next().unwrap().expect_err("Expected error");
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+        assert!(records.next().is_none());
+
+        brw.seek(SeekFrom::Start(8)).expect("Seek error");
+        let records: Vec<Vec<u8>> = brw
+            .records(4)
+            .allow_partial(true)
+            .collect::<std::io::Result<_>>()
+            .expect("Read error");
+        assert_eq!(vec![vec![8, 9, 10, 11], vec![12, 13]], records);
+
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(data));
+        assert_eq!(3, brw.records(4).count());
+    }
+
+    #[test]
+    fn testflushpartial() {
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(128, MockIO::default());
+        let data: Vec<u8> = (0..100).collect();
+        brw.write_all(&data).expect("Write error");
+        assert_eq!(40, brw.flush_partial(40).expect("Flush error"));
+        assert_eq!(vec![40], brw.get_ref().writes);
+        assert_eq!(&data[40..], brw.get_bufwriter_ref().unwrap().buffer());
+        assert_eq!(60, brw.flush_partial(1000).expect("Flush error"));
+        assert_eq!(&data, brw.get_ref().data.get_ref());
+
+        let mut brw = BufReaderWriterRand::writer_with_capacity(128, MockIO::default());
+        brw.write_all(&data).expect("Write error");
+        assert_eq!(40, brw.flush_partial(40).expect("Flush error"));
+        assert_eq!(60, brw.get_bufwriter_ref().unwrap().buffer().len());
+        assert_eq!(100, brw.stream_position().expect("Position error"));
+    }
+
+    #[test]
+    fn testreadmarkingboundary() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        brw.write_all(&[99]).expect("Write error");
+        let mut buf = [0; 2];
+        assert_eq!(
+            (2, 2),
+            brw.read_marking_boundary(&mut buf).expect("Read error")
+        );
+        let mut buf = [0; 6];
+        assert_eq!(
+            (6, 1),
+            brw.read_marking_boundary(&mut buf).expect("Read error")
+        );
+        assert_eq!([7, 9, 10, 11, 12, 13], buf);
+        assert_eq!(
+            (3, 0),
+            brw.read_marking_boundary(&mut buf).expect("Read error")
+        );
+    }
+
+    #[test]
+    fn testwritealignment() {
+        let mut brw = BufReaderWriterRand::writer_with_capacity(1024, MockIO::default());
+        brw.set_write_alignment(Some(512));
+        let data: Vec<u8> = (0..1300).map(|i| i as u8).collect();
+        for chunk in data.chunks(100) {
+            brw.write_all(chunk).expect("Write error");
+        }
+        assert_eq!(vec![1024], brw.get_ref().writes);
+        brw.flush().expect("Flush error");
+        assert_eq!(vec![1024, 276], brw.get_ref().writes);
+        assert_eq!(&data[..], &brw.get_ref().data.get_ref()[..]);
+    }
+
+    #[test]
+    fn testintio() {
+        let mut brw = BufReaderWriterRand::new_writer(tempfile().expect("tempfile error"));
+        brw.write_u8(0xab).expect("Write error");
+        brw.write_u16_be(0x0102).expect("Write error");
+        brw.write_u16_le(0x0102).expect("Write error");
+        brw.write_u32_be(0x01020304).expect("Write error");
+        brw.write_u32_le(0x01020304).expect("Write error");
+        brw.write_u64_be(0x0102030405060708).expect("Write error");
+        brw.write_u64_le(0x0102030405060708).expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut bytes = [0; 5];
+        brw.read_exact(&mut bytes).expect("Read error");
+        assert_eq!([0xab, 1, 2, 2, 1], bytes);
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        assert_eq!(0xab, brw.read_u8().expect("Read error"));
+        assert_eq!(0x0102, brw.read_u16_be().expect("Read error"));
+        assert_eq!(0x0102, brw.read_u16_le().expect("Read error"));
+        assert_eq!(0x01020304, brw.read_u32_be().expect("Read error"));
+        assert_eq!(0x01020304, brw.read_u32_le().expect("Read error"));
+        assert_eq!(0x0102030405060708, brw.read_u64_be().expect("Read error"));
+        assert_eq!(0x0102030405060708, brw.read_u64_le().expect("Read error"));
+        let e = brw.read_u16_be().unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, e.kind());
+
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        brw.write_u8(0xab).expect("Write error");
+        brw.write_u16_be(0x0102).expect("Write error");
+        brw.write_u32_le(0x01020304).expect("Write error");
+        brw.write_u64_be(0x0102030405060708).expect("Write error");
+        brw.flush().expect("Flush error");
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(brw.get_ref().get_ref().clone()));
+        assert_eq!(0xab, brw.read_u8().expect("Read error"));
+        assert_eq!(0x0102, brw.read_u16_be().expect("Read error"));
+        assert_eq!(0x01020304, brw.read_u32_le().expect("Read error"));
+        assert_eq!(0x0102030405060708, brw.read_u64_be().expect("Read error"));
+        let e = brw.read_u8().unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, e.kind());
+    }
+
+    #[test]
+    fn testthrashratio() {
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(vec![0; 100]));
+        assert_eq!(0.0, brw.thrash_ratio());
+        let mut buf = [0; 1];
+        for _ in 0..50 {
+            assert_eq!(1, brw.read(&mut buf).expect("Read error"));
+            assert_eq!(1, brw.write(&buf).expect("Write error"));
+        }
+        assert_eq!(100, brw.total_ops());
+        assert_eq!(99, brw.switch_count());
+        assert!(brw.thrash_ratio() > 0.98);
+
+        let mut brw = BufReaderWriterRand::new_writer(tempfile().expect("tempfile error"));
+        for _ in 0..3 {
+            assert_eq!(1, brw.write(&buf).expect("Write error"));
+        }
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        assert_eq!(1, brw.read(&mut buf).expect("Read error"));
+        assert_eq!(4, brw.total_ops());
+        assert_eq!(1, brw.switch_count());
+        assert_eq!(0.25, brw.thrash_ratio());
+    }
+
+    #[test]
+    fn testreadsequentialinto() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        brw.write_all(&[99]).expect("Write error");
+        let (mut a, mut b, mut c) = ([0; 2], [0; 4], [0; 3]);
+        assert_eq!(
+            9,
+            brw.read_sequential_into(&mut [&mut a, &mut b, &mut c])
+                .expect("Read error")
+        );
+        assert_eq!([5, 6], a);
+        assert_eq!([7, 9, 10, 11], b);
+        assert_eq!([12, 13, 14], c);
+
+        let mut a = [0; 20];
+        assert_eq!(
+            17,
+            brw.read_sequential_into(&mut [&mut a, &mut [0; 4]])
+                .expect("Read error")
+        );
+
+        let mut brw = BufReaderWriterRand::reader_with_capacity(4, Cursor::new(vec![1; 10]));
+        let (mut a, mut b) = ([0; 6], [0; 6]);
+        assert_eq!(
+            10,
+            brw.read_sequential_into(&mut [&mut a, &mut b])
+                .expect("Read error")
+        );
+        assert_eq!([1, 1, 1, 1, 0, 0], b);
+    }
+
+    #[test]
+    fn testwithreader() {
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(b"hello\nworld\n".to_vec()));
+        let mut line = String::new();
+        assert_eq!(
+            6,
+            brw.with_reader(|r| r.read_line(&mut line))
+                .expect("Switch error")
+                .expect("Read error")
+        );
+        assert_eq!("hello\n", line);
+        assert!(brw.is_reader());
+        let mut rest = String::new();
+        brw.read_to_string(&mut rest).expect("Read error");
+        assert_eq!("world\n", rest);
+
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        brw.write_all(&[99]).expect("Write error");
+        assert!(brw.with_reader(|r| r.fill_buf().map(|b| b.len())).is_err());
+        brw.read_exact(&mut [0; 3]).expect("Read error");
+        assert_eq!(
+            [9, 10],
+            brw.with_reader(|r| r.fill_buf().map(|b| [b[0], b[1]]))
+                .expect("Switch error")
+                .expect("Read error")
+        );
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(b"one\ntwo\n").expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut line = String::new();
+        brw.with_reader(|r| r.read_line(&mut line))
+            .expect("Switch error")
+            .expect("Read error");
+        assert_eq!("one\n", line);
+        let mut rest = String::new();
+        brw.read_to_string(&mut rest).expect("Read error");
+        assert_eq!("two\n", rest);
+    }
+
+    #[test]
+    fn testreplaceinner() {
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(b"pending").expect("Write error");
+        let old = brw
+            .replace_inner(Cursor::new(Vec::new()))
+            .expect("Replace error");
+        assert!(old.get_ref().is_empty());
+        brw.flush().expect("Flush error");
+        assert_eq!(b"pending", &brw.get_ref().get_ref()[..]);
+
+        assert_eq!(0, brw.read(&mut [0; 1]).expect("Read error"));
+        let e = brw.replace_inner(Cursor::new(Vec::new())).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, e.kind());
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(vec![0; 4]));
+        brw.write_all(b"ab").expect("Write error");
+        let mut target = Cursor::new(vec![0; 4]);
+        target.set_position(2);
+        let old = brw.replace_inner(target).expect("Replace error");
+        assert_eq!(vec![0; 4], old.into_inner());
+        brw.flush().expect("Flush error");
+        assert_eq!(&[0, 0, b'a', b'b'], &brw.get_ref().get_ref()[..]);
+        assert_eq!(4, brw.stream_position().expect("Seek error"));
+    }
+
+    #[test]
+    fn testswitchtowriterseeks() {
+        let mut brw = BufReaderWriterRand::new_reader(MockIO::default());
+        brw.write_all(b"abc").expect("Write error");
+        assert_eq!(0, brw.get_ref().seeks);
+        assert_eq!(3, brw.high_water_mark());
+
+        let mock = MockIO {
+            data: Cursor::new(vec![0; 4]),
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterRand::reader_with_capacity(4, mock);
+        brw.read_exact(&mut [0; 4]).expect("Read error");
+        brw.write_all(b"ab").expect("Write error");
+        assert_eq!(0, brw.get_ref().seeks);
+        assert_eq!(6, brw.high_water_mark());
+
+        let mock = MockIO {
+            data: Cursor::new(vec![0; 10]),
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterRand::new_reader(mock);
+        brw.read_exact(&mut [0; 3]).expect("Read error");
+        brw.write_all(b"abc").expect("Write error");
+        assert_eq!(1, brw.get_ref().seeks);
+        brw.flush().expect("Flush error");
+        assert_eq!(
+            &[0, 0, 0, b'a', b'b', b'c'],
+            &brw.get_ref().data.get_ref()[..6]
+        );
+        assert_eq!(6, brw.stream_position().expect("Seek error"));
+    }
+
+    #[test]
+    fn testpeekbuffered() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut file = tempfile().expect("tempfile error");
+        file.write_all(&data).expect("Write error");
+        file.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut brw = BufReaderWriterRand::reader_with_capacity(16, file);
+        assert!(brw.peek_buffered().is_empty());
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        assert_eq!(&data[5..16], &brw.peek_buffered()[..]);
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data.clone()));
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        assert_eq!(&data[5..8], &brw.peek_buffered()[..]);
+        brw.write_all(&[99]).expect("Write error");
+        assert_eq!(&data[5..8], &brw.peek_buffered()[..]);
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        assert_eq!(vec![7], brw.peek_buffered());
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        assert_eq!(vec![10, 11, 12, 13, 14, 15, 16], brw.peek_buffered());
+    }
+
+    #[test]
+    fn testresync() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterRand::reader_with_capacity(16, Cursor::new(data));
+        brw.read_exact(&mut [0; 4]).expect("Read error");
+        brw.get_mut().seek(SeekFrom::Start(20)).expect("Seek error");
+        brw.resync().expect("Resync error");
+        assert_eq!(20, brw.stream_position().expect("Seek error"));
+        let mut buf = [0; 4];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!([20, 21, 22, 23], buf);
+
+        brw.write_all(b"ab").expect("Write error");
+        brw.get_mut().seek(SeekFrom::Start(2)).expect("Seek error");
+        brw.resync().expect("Resync error");
+        brw.write_all(b"cd").expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut buf = [0; 6];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!([0, 1, b'c', b'd', 4, 5], buf);
+    }
+
+    #[test]
+    fn testwritefrom() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut src = Cursor::new(data.clone());
+        let mut brw =
+            BufReaderWriterRand::writer_with_capacity(16, tempfile().expect("tempfile error"));
+        assert_eq!(40, brw.write_from(&mut src, Some(40)).expect("Copy error"));
+        assert_eq!(40, src.position());
+        assert_eq!(0, brw.write_from(&mut src, Some(0)).expect("Copy error"));
+        assert_eq!(
+            60,
+            brw.write_from(&mut src, Some(1000)).expect("Copy error")
+        );
+        assert_eq!(0, brw.write_from(&mut src, None).expect("Copy error"));
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut out = Vec::new();
+        brw.read_to_end(&mut out).expect("Read error");
+        assert_eq!(data, out);
+
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(Vec::new()));
+        assert_eq!(
+            100,
+            brw.write_from(&mut Cursor::new(data.clone()), None)
+                .expect("Copy error")
+        );
+        brw.flush().expect("Flush error");
+        assert_eq!(data, *brw.get_ref().get_ref());
+    }
+
+    #[test]
+    fn testsuggestediosize() {
+        let brw = BufReaderWriterSeq::writer_with_capacity(1000, Cursor::new(Vec::new()));
+        assert_eq!(1000, brw.suggested_io_size());
+        let brw = BufReaderWriterRand::reader_with_capacity(300, Cursor::new(Vec::new()));
+        assert_eq!(300, brw.suggested_io_size());
+        let brw = BufReaderWriterSeq::new_lazy(Cursor::new(Vec::new()));
+        assert_eq!(8192, brw.suggested_io_size());
+
+        let brw =
+            BufReaderWriterRand::writer_with_capacity(1000, tempfile().expect("tempfile error"));
+        let size = brw.suggested_block_io_size();
+        assert!(size >= 1000);
+        if let Some(block) = brw.get_ref().block_size() {
+            assert_eq!(0, size % block);
+        }
+    }
+
+    #[test]
+    fn testrecoverpending() {
+        let mock = MockIO {
+            accept: Some(6),
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(10, mock);
+        brw.write_all(b"01234567").expect("Write error");
+        assert_eq!(Some(&b"01234567"[..]), brw.recover_pending());
+        let e = brw.write(b"abc").unwrap_err();
+        assert_eq!(std::io::ErrorKind::BrokenPipe, e.kind());
+        assert_eq!(Some(&b"67"[..]), brw.recover_pending());
+        assert!(brw.retry_flush().is_err());
+        assert_eq!(Some(&b"67"[..]), brw.recover_pending());
+
+        brw.get_mut().accept = None;
+        brw.retry_flush().expect("Flush error");
+        assert_eq!(None, brw.recover_pending());
+        brw.write_all(b"abc").expect("Write error");
+        brw.flush().expect("Flush error");
+        assert_eq!(b"01234567abc", &brw.get_ref().data.get_ref()[..]);
+    }
+
+    #[test]
+    fn testzerocapacity() {
+        let mut brw = BufReaderWriterRand::writer_with_capacity(0, MockIO::default());
+        brw.write_all(b"ab").expect("Write error");
+        assert_eq!(vec![2], brw.get_ref().writes);
+        brw.write_all(b"c").expect("Write error");
+        assert_eq!(vec![2, 1], brw.get_ref().writes);
+        brw.seek(SeekFrom::Start(1)).expect("Seek error");
+        let mut buf = [0; 1];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!(*b"b", buf);
+        assert_eq!(2, brw.get_ref().data.position());
+        brw.write_all(b"d").expect("Write error");
+        assert_eq!(vec![2, 1, 1], brw.get_ref().writes);
+        assert_eq!(b"abd", &brw.get_ref().data.get_ref()[..]);
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(0, MockIO::default());
+        brw.write_all(b"xy").expect("Write error");
+        brw.write_all(b"z").expect("Write error");
+        assert_eq!(vec![2, 1], brw.get_ref().writes);
+        assert_eq!(0, brw.capacity());
+
+        let mock = MockIO {
+            data: Cursor::new(b"abc".to_vec()),
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(0, mock);
+        let mut buf = [0; 1];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!(1, brw.get_ref().data.position());
+        assert_eq!(0, brw.buffered_read_len());
+        assert_eq!(Mode::Reader, brw.mode());
+        let mut cstr = Vec::new();
+        let err = brw.read_cstr(&mut cstr).expect_err("Missing terminator");
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+        assert_eq!(b"bc", &cstr[..]);
+
+        let mock = MockIO {
+            data: Cursor::new(b"ab\ncd\0ef".to_vec()),
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterRand::reader_with_capacity(0, mock);
+        assert_eq!(Mode::Reader, brw.mode());
+        assert_eq!(8, brw.prefetch().expect("Read error"));
+        let mut line = String::new();
+        brw.read_line_limited(&mut line, 8).expect("Read error");
+        assert_eq!("ab\n", line);
+        let mut cstr = Vec::new();
+        brw.read_cstr(&mut cstr).expect("Read error");
+        assert_eq!(b"cd", &cstr[..]);
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        assert_eq!(0, brw.read(&mut [0; 4]).expect("Read error"));
+        assert!(brw.get_bufreader_ref().is_none());
+        let mut rest = Vec::new();
+        assert_eq!(0, brw.copy_to(&mut rest).expect("Read error"));
+        brw.seek(SeekFrom::Start(3)).expect("Seek error");
+        let mut until = Vec::new();
+        brw.read_until_into(0, &mut until).expect("Read error");
+        assert_eq!(b"cd\0", &until[..]);
+
+        let mock = MockIO {
+            data: Cursor::new(b"xy\nz".to_vec()),
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(0, mock);
+        brw.write_all(b"w").expect("Write error");
+        assert_eq!(vec![1], brw.get_ref().writes);
+        assert_eq!(Mode::Writer, brw.mode());
+        let mut found = Vec::new();
+        assert_eq!(
+            (2, Some(b'\n')),
+            brw.read_until_any(b"\n", &mut found).expect("Read error")
+        );
+        assert_eq!(b"y\n", &found[..]);
+        assert_eq!(Mode::Reader, brw.mode());
+        let mut rest = Vec::new();
+        assert_eq!(1, brw.copy_to(&mut rest).expect("Read error"));
+        assert_eq!(b"z", &rest[..]);
+    }
+
+    #[test]
+    fn testinnerandbuffers() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data.clone()));
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        let (inner, state) = brw.inner_and_buffers();
+        assert_eq!(3, state.buffered_read);
+        assert_eq!(0, state.pending_write);
+        inner.set_position(inner.position() - state.buffered_read as u64);
+        assert_eq!(5, brw.get_ref().position());
+
+        let mut brw = BufReaderWriterRand::reader_with_capacity(8, Cursor::new(data));
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        let (inner, state) = brw.inner_and_buffers();
+        assert_eq!(Mode::Reader, state.mode);
+        assert_eq!(6, state.buffered_read);
+        assert_eq!(8, inner.position());
+    }
+
+    #[test]
+    fn testbyteswritten() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let flushed = Arc::new(Mutex::new(Vec::new()));
+        let mut brw = BufReaderWriterRand::writer_with_capacity(8, MockIO::default());
+        brw.set_observer(Box::new(Recorder(events.clone())));
+        let f = flushed.clone();
+        brw.set_on_auto_flush(move |n| f.lock().unwrap().push(n));
+        assert_eq!(20, brw.write(&[1; 20]).expect("Write error"));
+        assert_eq!(20, brw.bytes_written());
+        assert_eq!(vec![20], brw.get_ref().writes);
+        assert_eq!(vec![Event::Write(20)], *events.lock().unwrap());
+        brw.write_all(&[2; 3]).expect("Write error");
+        brw.write_all(&[3; 30]).expect("Write error");
+        assert_eq!(53, brw.bytes_written());
+        assert_eq!(vec![3], *flushed.lock().unwrap());
+        assert_eq!(53, brw.get_ref().data.get_ref().len());
+    }
+
+    #[test]
+    fn testreadallinto() {
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut file = tempfile().expect("tempfile error");
+        file.write_all(&data).expect("Write error");
+        let mut brw = BufReaderWriterRand::reader_with_capacity(64, file);
+        let mut buf = Vec::new();
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        assert_eq!(200, brw.read_all_into(&mut buf).expect("Read error"));
+        assert_eq!(data, buf);
+        let (ptr, capacity) = (buf.as_ptr(), buf.capacity());
+        brw.seek(SeekFrom::Start(10)).expect("Seek error");
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        assert_eq!(185, brw.read_all_into(&mut buf).expect("Read error"));
+        assert_eq!(ptr, buf.as_ptr());
+        assert_eq!(capacity, buf.capacity());
+        assert_eq!(&data[15..], &buf[..]);
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data.clone()));
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        brw.write_all(&[99]).expect("Write error");
+        assert_eq!(194, brw.read_all_into(&mut buf).expect("Read error"));
+        assert_eq!(&data[5..8], &buf[..3]);
+        assert_eq!(&data[9..], &buf[3..]);
+    }
+
+    #[test]
+    fn testwouldblockaseof() {
+        struct NonBlocking(std::collections::VecDeque<Vec<u8>>);
+
+        impl Read for NonBlocking {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match self.0.pop_front() {
+                    Some(chunk) => {
+                        buf[..chunk.len()].copy_from_slice(&chunk);
+                        Ok(chunk.len())
+                    }
+                    None => Err(std::io::ErrorKind::WouldBlock.into()),
+                }
+            }
+        }
+
+        impl Write for NonBlocking {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let chunks = vec![b"abcdef".to_vec(), b"gh".to_vec()];
+        let mut brw = BufReaderWriterSeq::new_reader(NonBlocking(chunks.into()));
+        let e = brw.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(std::io::ErrorKind::WouldBlock, e.kind());
+
+        let chunks = vec![b"abcdef".to_vec(), b"gh".to_vec()];
+        let mut brw = BufReaderWriterSeq::new_reader(NonBlocking(chunks.into()));
+        brw.set_wouldblock_as_eof(true);
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        brw.write_all(b"x").expect("Write error");
+        let mut drained = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            let n = brw.read(&mut buf).expect("Read error");
+            if n == 0 {
+                break;
+            }
+            drained.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(b"cdefgh", &drained[..]);
+        assert!(!brw.is_eof());
+        brw.get_mut().0.push_back(b"ij".to_vec());
+        assert_eq!(2, brw.read(&mut buf).expect("Read error"));
+    }
+
+    #[test]
+    fn testexportimportstate() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        brw.write_all(b"pending").expect("Write error");
+        let state = brw.export_state();
+        assert_eq!(
+            ExportedBuffers {
+                mode: Mode::Writer,
+                pending_write: b"pending".to_vec(),
+                saved_read: vec![5, 6, 7],
+            },
+            state
+        );
+
+        let mut restored = BufReaderWriterSeq::new_reader(Cursor::new(vec![9; 4]));
+        restored.import_state(state).expect("Import error");
+        assert_eq!(Mode::Writer, restored.mode());
+        restored.flush().expect("Flush error");
+        assert_eq!(b"pending", &restored.get_ref().get_ref()[..7]);
+        let mut buf = [0; 3];
+        restored.read_exact(&mut buf).expect("Read error");
+        assert_eq!([5, 6, 7], buf);
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(b"wal").expect("Write error");
+        let state = brw.export_state();
+        let mut restored = BufReaderWriterRand::new_reader(Cursor::new(Vec::new()));
+        restored.import_state(state).expect("Import error");
+        assert_eq!(3, restored.stream_position().expect("Seek error"));
+        restored.flush().expect("Flush error");
+        assert_eq!(b"wal", &restored.get_ref().get_ref()[..]);
+
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(Vec::new()));
+        let e = brw
+            .import_state(ExportedBuffers {
+                mode: Mode::Reader,
+                pending_write: b"x".to_vec(),
+                saved_read: Vec::new(),
+            })
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, e.kind());
+    }
+
+    #[test]
+    fn testseekbounds() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        brw.set_seek_bounds(16);
+        assert_eq!(16, brw.seek(SeekFrom::Start(16)).expect("Seek error"));
+        let e = brw.seek(SeekFrom::Start(17)).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, e.kind());
+        assert_eq!(16, brw.get_ref().position());
+
+        brw.seek(SeekFrom::Start(4)).expect("Seek error");
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        assert_eq!(16, brw.seek(SeekFrom::Current(10)).expect("Seek error"));
+        brw.seek(SeekFrom::Start(10)).expect("Seek error");
+        let e = brw.seek(SeekFrom::Current(7)).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, e.kind());
+        assert_eq!(10, brw.get_ref().position());
+        assert_eq!(2, brw.seek(SeekFrom::Current(-8)).expect("Seek error"));
+
+        let e = brw.seek(SeekFrom::End(0)).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, e.kind());
+        assert_eq!(2, brw.stream_position().expect("Seek error"));
+        assert_eq!(16, brw.seek(SeekFrom::End(-16)).expect("Seek error"));
+        let mut buf = [0; 1];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!([16], buf);
+    }
+
+    #[test]
+    fn testflushtimed() {
+        let mut brw = BufReaderWriterRand::new_writer(tempfile().expect("tempfile error"));
+        brw.write_all(b"timed").expect("Write error");
+        assert!(brw.needs_flush());
+        let elapsed: Duration = brw.flush_timed().expect("Flush error");
+        assert!(elapsed < Duration::from_secs(60));
+        assert_eq!(0, brw.state().pending_write);
+
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(b"timed").expect("Write error");
+        brw.flush_timed().expect("Flush error");
+        assert_eq!(b"timed", &brw.get_ref().get_ref()[..]);
+    }
+
+    #[test]
+    fn testreadlinelimited() {
+        let data = b"short\nthis line is too long\nok\nend".to_vec();
+        let mut brw = BufReaderWriterRand::reader_with_capacity(4, Cursor::new(data.clone()));
+        let mut line = String::new();
+        assert_eq!(6, brw.read_line_limited(&mut line, 8).expect("Read error"));
+        assert_eq!("short\n", line);
+        let e = brw.read_line_limited(&mut line, 8).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, e.kind());
+        assert_eq!("short\n", line);
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data));
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        brw.write_all(b"x").expect("Write error");
+        let mut line = String::new();
+        assert_eq!(4, brw.read_line_limited(&mut line, 3).expect("Read error"));
+        assert_eq!("ort\n", line);
+        let e = brw.read_line_limited(&mut line, 10).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, e.kind());
+        let mut rest = Vec::new();
+        brw.read_until_into(b'\n', &mut rest).expect("Read error");
+        line.clear();
+        assert_eq!(3, brw.read_line_limited(&mut line, 2).expect("Read error"));
+        assert_eq!("ok\n", line);
+        line.clear();
+        assert_eq!(3, brw.read_line_limited(&mut line, 3).expect("Read error"));
+        assert_eq!("end", line);
+        assert_eq!(0, brw.read_line_limited(&mut line, 3).expect("Read error"));
+    }
+
+    #[test]
+    fn testconfig() {
+        let mut brw = BufReaderWriterSeq::reader_with_capacities(16, 32, Cursor::new(Vec::new()));
+        brw.set_retry_on_interrupt(true);
+        brw.set_read_ahead(false);
+        brw.set_max_write_chunk(Some(4));
+        brw.set_seek_bounds(100);
+        let config = brw.config();
+        assert_eq!(Mode::Reader, config.mode);
+        assert_eq!(Some(16), config.read_capacity);
+        assert_eq!(Some(32), config.write_capacity);
+        let built = config.build_seq(Cursor::new(Vec::new()));
+        assert_eq!(config, built.config());
+        assert_eq!(16, built.capacity());
+
+        let mut brw = BufReaderWriterRand::writer_with_capacity(1024, Cursor::new(Vec::new()));
+        brw.set_write_alignment(Some(512));
+        let config = brw.config();
+        let built = config.build_rand(Cursor::new(Vec::new()));
+        assert_eq!(config, built.config());
+        assert_eq!(Mode::Writer, built.mode());
+        assert_eq!(1024, built.capacity());
+
+        let lazy = BufReaderWriterSeq::new_lazy(Cursor::new(Vec::new()));
+        let built = lazy.config().build_seq(Cursor::new(Vec::new()));
+        assert!(!built.is_initialized());
+    }
+
+    #[test]
+    fn testchunkedshortwrites() {
+        let data: Vec<u8> = (0..40).collect();
+        let mock = MockIO {
+            halve_writes: true,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(16, mock);
+        brw.set_max_write_chunk(Some(8));
+        assert_eq!(40, brw.write(&data).expect("Write error"));
+        assert_eq!([4, 2, 1, 1].repeat(5), brw.get_ref().writes);
+        assert_eq!(&data[..], &brw.get_ref().data.get_ref()[..]);
+
+        let mock = MockIO {
+            halve_writes: true,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(4, mock);
+        brw.set_max_write_chunk(Some(8));
+        assert_eq!(4, brw.write(&data).expect("Write error"));
+        brw.write_all(&data[4..]).expect("Write error");
+        brw.flush().expect("Flush error");
+        assert_eq!(&data[..], &brw.get_ref().data.get_ref()[..]);
+
+        let mock = MockIO {
+            accept: Some(6),
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(16, mock);
+        brw.set_max_write_chunk(Some(4));
+        assert_eq!(8, brw.write(&data[..10]).expect("Write error"));
+        assert_eq!(Some(&data[6..8]), brw.recover_pending());
+        brw.get_mut().accept = None;
+        brw.write_all(&data[8..10]).expect("Write error");
+        brw.flush().expect("Flush error");
+        assert_eq!(&data[..10], &brw.get_ref().data.get_ref()[..]);
+    }
+
+    #[test]
+    fn testsharedqueries() {
+        fn describe_seq<RW: Read + Write>(
+            brw: &BufReaderWriterSeq<RW>,
+        ) -> (Mode, bool, usize, usize, usize) {
+            assert!(brw.is_initialized());
+            assert_eq!(brw.needs_flush(), brw.pending_write_len() > 0);
+            assert_eq!(
+                brw.state().saved_buffer,
+                brw.buffer().map_or(0, |b| b.len())
+            );
+            (
+                brw.mode(),
+                brw.is_reader(),
+                brw.capacity(),
+                brw.pending_write_len(),
+                brw.buffered_read_len(),
+            )
+        }
+
+        fn describe_rand<RW: Read + Write + Seek>(
+            brw: &BufReaderWriterRand<RW>,
+        ) -> (Mode, usize, usize) {
+            assert_eq!(brw.current_run().0, brw.mode());
+            (brw.mode(), brw.capacity(), brw.pending_write_len())
+        }
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(vec![1; 16]));
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        assert_eq!((Mode::Reader, true, 8, 0, 3), describe_seq(&brw));
+        brw.write_all(b"ab").expect("Write error");
+        let shared = &brw;
+        assert_eq!((Mode::Writer, false, 8, 2, 3), describe_seq(shared));
+
+        let mut brw = BufReaderWriterRand::writer_with_capacity(64, Cursor::new(Vec::new()));
+        brw.write_all(b"abc").expect("Write error");
+        assert_eq!((Mode::Writer, 64, 3), describe_rand(&brw));
+    }
+
+    #[test]
+    fn testshrinksavedbuffer() {
+        let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let mut brw =
+            BufReaderWriterSeq::reader_with_capacity(64 * 1024, Cursor::new(data.clone()));
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        brw.write_all(b"x").expect("Write error");
+        let mut buf = vec![0; 60 * 1024];
+        brw.read_exact(&mut buf).expect("Read error");
+        let remaining = brw.buffer().expect("No saved buffer").len();
+        assert_eq!(64 * 1024 - 1 - 60 * 1024, remaining);
+
+        brw.shrink_saved_buffer();
+        assert_eq!(Some(&data[data.len() - remaining..]), brw.buffer());
+        brw.shrink_saved_buffer();
+        let mut rest = vec![0; remaining];
+        brw.read_exact(&mut rest).expect("Read error");
+        assert_eq!(&data[data.len() - remaining..], &rest[..]);
+    }
+
+    #[test]
+    fn testreadvectored() {
+        use std::io::IoSliceMut;
+
+        let data: Vec<u8> = (0..32).collect();
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(data.clone()));
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        brw.write_all(&[99]).expect("Write error");
+        let (mut a, mut b) = ([0; 2], [0; 4]);
+        let n = brw
+            .read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)])
+            .expect("Read error");
+        assert_eq!(3, n);
+        assert_eq!([5, 6], a);
+        assert_eq!(7, b[0]);
+        let n = brw
+            .read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)])
+            .expect("Read error");
+        assert_eq!(6, n);
+        assert_eq!([9, 10], a);
+        assert_eq!([11, 12, 13, 14], b);
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(data.clone()));
+        brw.write_all(b"ab").expect("Write error");
+        let (mut a, mut b) = ([0; 3], [0; 3]);
+        let n = brw
+            .read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)])
+            .expect("Read error");
+        assert_eq!(6, n);
+        assert_eq!([2, 3, 4], a);
+        assert_eq!([5, 6, 7], b);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn testisreadvectored() {
+        let file = tempfile().expect("tempfile error");
+        let expected = file.is_read_vectored();
+        #[cfg(unix)]
+        assert!(expected);
+        let brw = BufReaderWriterRand::new_reader(file);
+        assert_eq!(expected, brw.is_read_vectored());
+        let brw = BufReaderWriterSeq::new_writer(tempfile().expect("tempfile error"));
+        assert_eq!(expected, brw.is_read_vectored());
+    }
+
+    #[test]
+    fn testappenddurable() {
+        let mut brw = BufReaderWriterRand::new_writer(tempfile().expect("tempfile error"));
+        brw.append_durable(b"first\n").expect("Append error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        brw.append_durable(b"second\n").expect("Append error");
+        assert!(!brw.needs_flush());
+        brw.append_durable(b"third\n").expect("Append error");
+        assert_eq!(19, brw.get_ref().metadata().expect("Metadata error").len());
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut log = String::new();
+        brw.read_to_string(&mut log).expect("Read error");
+        assert_eq!("first\nsecond\nthird\n", log);
+    }
+
+    #[test]
+    fn testreadready() {
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(8, Cursor::new(vec![1; 10]));
+        assert!(!brw.read_ready());
+        brw.prefetch().expect("Prefetch error");
+        assert!(brw.read_ready());
+        brw.write_all(b"x").expect("Write error");
+        assert!(brw.read_ready());
+        brw.read_exact(&mut [0; 8]).expect("Read error");
+        assert!(!brw.read_ready());
+
+        let mut brw = BufReaderWriterRand::reader_with_capacity(4, Cursor::new(vec![1; 10]));
+        assert!(!brw.read_ready());
+        assert_eq!(4, brw.fill_buf().expect("Read error").len());
+        assert!(brw.read_ready());
+        brw.consume(4);
+        assert!(!brw.read_ready());
+    }
+
+    #[test]
+    fn testasstdbufreader() {
+        fn first_word<R: Read>(r: &mut BufReader<R>) -> String {
+            let mut word = Vec::new();
+            r.read_until(b' ', &mut word).expect("Read error");
+            String::from_utf8(word).expect("UTF-8 error")
+        }
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(b"hello world").expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let r = brw.as_std_bufreader().expect("Switch error");
+        assert_eq!("hello ", first_word(r));
+        let mut rest = String::new();
+        brw.read_to_string(&mut rest).expect("Read error");
+        assert_eq!("world", rest);
+
+        let mut brw =
+            BufReaderWriterSeq::reader_with_capacity(4, Cursor::new(b"ab cd ef gh".to_vec()));
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        brw.write_all(b"XY").expect("Write error");
+        assert!(brw.as_std_bufreader().is_err());
+        brw.read_exact(&mut [0; 3]).expect("Read error");
+        assert_eq!(
+            "ef ",
+            first_word(brw.as_std_bufreader().expect("Switch error"))
+        );
+    }
+
+    #[test]
+    fn testtruncatehere() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut brw = BufReaderWriterRand::new_writer(tempfile().expect("tempfile error"));
+        brw.write_all(&data).expect("Write error");
+        brw.seek(SeekFrom::Start(50)).expect("Seek error");
+        brw.truncate_here().expect("Truncate error");
+        assert_eq!(50, brw.get_ref().metadata().expect("Metadata error").len());
+        assert_eq!(50, brw.stream_position().expect("Seek error"));
+        assert_eq!(0, brw.read(&mut [0; 1]).expect("Read error"));
+
+        brw.seek(SeekFrom::Start(10)).expect("Seek error");
+        brw.read_exact(&mut [0; 5]).expect("Read error");
+        brw.truncate_here().expect("Truncate error");
+        assert_eq!(15, brw.get_ref().metadata().expect("Metadata error").len());
+        assert_eq!(0, brw.read(&mut [0; 1]).expect("Read error"));
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut out = Vec::new();
+        brw.read_to_end(&mut out).expect("Read error");
+        assert_eq!(&data[..15], &out[..]);
+    }
+
+    #[test]
+    fn testname() {
+        struct Named(Arc<Mutex<Vec<String>>>);
+
+        impl IoObserver for Named {
+            fn on_read(&mut self, _n: usize) {}
+
+            fn on_write(&mut self, _n: usize) {}
+
+            fn on_switch(&mut self, _to: Mode) {}
+
+            fn on_name(&mut self, name: &str) {
+                self.0.lock().unwrap().push(name.to_owned());
+            }
+
+            fn on_flush(&mut self) {}
+        }
+
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        assert_eq!(None, brw.name());
+        brw.set_name("conn-1");
+        assert_eq!(Some("conn-1"), brw.name());
+        brw.set_observer(Box::new(Named(names.clone())));
+        brw.set_name("conn-2");
+        assert_eq!(vec!["conn-1", "conn-2"], *names.lock().unwrap());
+        let debug = format!("{:?}", brw);
+        assert!(debug.contains("BufReaderWriterSeq"));
+        assert!(debug.contains("\"conn-2\""));
+
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(Vec::new()));
+        brw.set_name(String::from("file-a"));
+        assert!(format!("{:?}", brw).contains("\"file-a\""));
+    }
+
+    #[test]
+    fn testbyteswap() {
+        let values: Vec<u32> = vec![0x01020304, 0xdeadbeef, 7, 0xffff0000];
+        let be: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.set_byteswap(4);
+        for chunk in be.chunks(3) {
+            brw.write_all(chunk).expect("Write error");
+        }
+        brw.write_all(&[0xaa, 0xbb]).expect("Write error");
+        brw.flush().expect("Flush error");
+        let le: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        assert_eq!(&le[..], &brw.get_ref().get_ref()[..16]);
+        assert_eq!(&[0xaa, 0xbb], &brw.get_ref().get_ref()[16..]);
+
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut out = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            let n = brw.read(&mut buf).expect("Read error");
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(&be[..], &out[..16]);
+        assert_eq!(&[0xaa, 0xbb], &out[16..]);
+
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(le));
+        brw.set_byteswap(4);
+        for v in &values {
+            assert_eq!(*v, brw.read_u32_be().expect("Read error"));
+        }
+    }
+
+    #[test]
+    fn testreaduntilany() {
+        let data = b"a,bc\nd,\nef".to_vec();
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(data.clone()));
+        let mut buf = Vec::new();
+        assert_eq!(
+            (2, Some(b',')),
+            brw.read_until_any(b",\n", &mut buf).expect("Read error")
+        );
+        assert_eq!(
+            (3, Some(b'\n')),
+            brw.read_until_any(b",\n", &mut buf).expect("Read error")
+        );
+        assert_eq!(
+            (2, Some(b',')),
+            brw.read_until_any(b",\n", &mut buf).expect("Read error")
+        );
+        assert_eq!(
+            (1, Some(b'\n')),
+            brw.read_until_any(b",\n", &mut buf).expect("Read error")
+        );
+        assert_eq!(
+            (2, None),
+            brw.read_until_any(b",\n", &mut buf).expect("Read error")
+        );
+        assert_eq!(
+            (0, None),
+            brw.read_until_any(b",\n", &mut buf).expect("Read error")
+        );
+        assert_eq!(data, buf);
+
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(data.clone()));
+        let mut buf = Vec::new();
+        assert_eq!(
+            (2, Some(b',')),
+            brw.read_until_any(b",\n", &mut buf).expect("Read error")
+        );
+        brw.write_all(b"x").expect("Write error");
+        assert_eq!(
+            (3, Some(b'\n')),
+            brw.read_until_any(b",\n", &mut buf).expect("Read error")
+        );
+        assert_eq!(
+            (2, Some(b',')),
+            brw.read_until_any(b"\n,", &mut buf).expect("Read error")
+        );
+        assert_eq!(
+            (3, None),
+            brw.read_until_any(b";", &mut buf).expect("Read error")
+        );
+        assert_eq!(data, buf);
+    }
+
+    #[test]
+    fn testbarrier() {
+        let mut brw = BufReaderWriterRand::new_writer(tempfile().expect("tempfile error"));
+        brw.write_all(b"first segment").expect("Write error");
+        let fence = brw.barrier().expect("Barrier error");
+        assert_eq!(13, fence);
+        assert_eq!(13, brw.get_ref().metadata().expect("Metadata error").len());
+        brw.write_all(b", second").expect("Write error");
+        assert_eq!(21, brw.barrier().expect("Barrier error"));
+
+        brw.seek(SeekFrom::Start(fence)).expect("Seek error");
+        brw.truncate_here().expect("Truncate error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let mut out = Vec::new();
+        brw.read_to_end(&mut out).expect("Read error");
+        assert_eq!(b"first segment", &out[..]);
+    }
+
+    #[test]
+    fn testwritestrict() {
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(vec![1, 2, 3]));
+        let err = brw.write_strict(b"ab").expect_err("Expected error");
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        assert!(brw.is_reader());
+
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(vec![1, 2, 3]));
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        let err = brw.write_strict(b"ab").expect_err("Expected error");
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        assert!(brw.is_reader());
+        brw.seek(SeekFrom::Start(1)).expect("Seek error");
+        brw.write_all(b"a").expect("Write error");
+        assert_eq!(1, brw.write_strict(b"b").expect("Write error"));
+        brw.flush().expect("Flush error");
+        assert_eq!(&[1, b'a', b'b'], &brw.get_ref().get_ref()[..]);
+
+        let mut brw = BufReaderWriterSeq::new_lazy(Cursor::new(Vec::new()));
+        assert_eq!(2, brw.write_strict(b"ab").expect("Write error"));
+    }
+
+    #[test]
+    fn testlasttransitioncapacity() {
+        let mut brw = BufReaderWriterRand::reader_with_capacities(16, 64, Cursor::new(vec![0; 32]));
+        assert_eq!(None, brw.last_transition_capacity());
+        brw.read_exact(&mut [0; 4]).expect("Read error");
+        assert_eq!(None, brw.last_transition_capacity());
+        brw.write_all(b"ab").expect("Write error");
+        assert_eq!(Some(64), brw.last_transition_capacity());
+        brw.read_exact(&mut [0; 4]).expect("Read error");
+        assert_eq!(Some(16), brw.last_transition_capacity());
+
+        let mut brw = BufReaderWriterSeq::new_lazy(Cursor::new(Vec::new()));
+        assert_eq!(None, brw.last_transition_capacity());
+        brw.write_all(b"ab").expect("Write error");
+        assert_eq!(Some(brw.capacity()), brw.last_transition_capacity());
+    }
+
+    #[test]
+    fn testreadcstr() {
+        let data = b"abc\0\0de\0fg".to_vec();
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(data.clone()));
+        let mut buf = Vec::new();
+        assert_eq!(3, brw.read_cstr(&mut buf).expect("Read error"));
+        assert_eq!(b"abc", &buf[..]);
+        buf.clear();
+        assert_eq!(0, brw.read_cstr(&mut buf).expect("Read error"));
+        assert_eq!(2, brw.read_cstr(&mut buf).expect("Read error"));
+        assert_eq!(b"de", &buf[..]);
+        let err = brw.read_cstr(&mut buf).expect_err("Expected error");
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+        assert_eq!(b"defg", &buf[..]);
+
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(data));
+        let mut buf = Vec::new();
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        brw.write_all(b"x").expect("Write error");
+        assert_eq!(2, brw.read_cstr(&mut buf).expect("Read error"));
+        assert_eq!(0, brw.read_cstr(&mut buf).expect("Read error"));
+        assert_eq!(2, brw.read_cstr(&mut buf).expect("Read error"));
+        assert_eq!(b"bcde", &buf[..]);
+    }
+
+    #[test]
+    fn testflushifstale() {
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        assert!(!brw
+            .flush_if_stale(Duration::from_millis(0))
+            .expect("Flush error"));
+        brw.write_all(b"abc").expect("Write error");
+        assert!(!brw
+            .flush_if_stale(Duration::from_secs(60))
+            .expect("Flush error"));
+        assert!(brw.get_ref().get_ref().is_empty());
+        thread::sleep(Duration::from_millis(20));
+        assert!(brw
+            .flush_if_stale(Duration::from_millis(10))
+            .expect("Flush error"));
+        assert_eq!(b"abc", &brw.get_ref().get_ref()[..]);
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.write_all(b"abc").expect("Write error");
+        thread::sleep(Duration::from_millis(20));
+        brw.flush().expect("Flush error");
+        brw.write_all(b"de").expect("Write error");
+        assert!(!brw
+            .flush_if_stale(Duration::from_secs(60))
+            .expect("Flush error"));
+        assert_eq!(b"abc", &brw.get_ref().get_ref()[..]);
+        thread::sleep(Duration::from_millis(20));
+        assert!(brw
+            .flush_if_stale(Duration::from_millis(10))
+            .expect("Flush error"));
+        assert_eq!(b"abcde", &brw.get_ref().get_ref()[..]);
+    }
+
+    #[test]
+    fn testchecktruncation() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut brw =
+            BufReaderWriterRand::reader_with_capacity(64, tempfile().expect("tempfile error"));
+        brw.write_all(&data).expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        brw.read_exact(&mut [0; 10]).expect("Read error");
+        assert!(!brw.check_truncation().expect("Check error"));
+        assert_eq!(54, brw.buffered_read_len());
+
+        let other = brw.get_ref().try_clone().expect("Clone error");
+        other.set_len(30).expect("Truncate error");
+        assert!(brw.check_truncation().expect("Check error"));
+        assert_eq!(0, brw.buffered_read_len());
+        assert_eq!(30, brw.stream_position().expect("Seek error"));
+        assert_eq!(0, brw.read(&mut [0; 1]).expect("Read error"));
+        assert!(!brw.check_truncation().expect("Check error"));
+    }
+
+    #[test]
+    fn testprefillwrite() {
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(16, MockIO::default());
+        brw.prefill_write(b"HDR:").expect("Prefill error");
+        assert!(brw.get_ref().writes.is_empty());
+        brw.write_all(b"body").expect("Write error");
+        brw.flush().expect("Flush error");
+        assert_eq!(vec![8], brw.get_ref().writes);
+        assert_eq!(b"HDR:body", &brw.get_ref().data.get_ref()[..]);
+        let err = brw.prefill_write(&[0; 16]).expect_err("Expected error");
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+
+        let mut brw = BufReaderWriterRand::reader_with_capacities(16, 8, Cursor::new(Vec::new()));
+        brw.prefill_write(b"HDR:").expect("Prefill error");
+        assert!(!brw.is_reader());
+        let err = brw.prefill_write(b"12345").expect_err("Expected error");
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(4, brw.pending_write_len());
+        brw.write_all(b"body").expect("Write error");
+        assert!(brw.get_ref().get_ref().is_empty());
+        brw.flush().expect("Flush error");
+        assert_eq!(b"HDR:body", &brw.get_ref().get_ref()[..]);
+    }
+
+    #[test]
+    fn testcanfinalize() {
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(vec![1, 2, 3]));
+        assert!(brw.can_finalize());
+        brw.write_all(b"ab").expect("Write error");
+        assert!(!brw.can_finalize());
+        brw.flush().expect("Flush error");
+        assert!(brw.can_finalize());
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        assert!(brw.can_finalize());
+        brw.write_all(b"ab").expect("Write error");
+        assert!(!brw.can_finalize());
+        brw.flush().expect("Flush error");
+        assert!(brw.can_finalize());
+        assert_eq!(b"ab", &brw.into_inner().expect("Error").into_inner()[..]);
+    }
+
+    #[test]
+    fn testswitchhysteresis() {
+        let alternate = |hysteresis| {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let mut brw = BufReaderWriterSeq::new_reader(Cursor::new((0..10).collect::<Vec<u8>>()));
+            brw.set_observer(Box::new(Recorder(events.clone())));
+            brw.set_switch_hysteresis(hysteresis);
+            let mut read = Vec::new();
+            for i in 0..5 {
+                let mut b = [0; 1];
+                brw.read_exact(&mut b).expect("Read error");
+                read.push(b[0]);
+                brw.write_all(&[b'a' + i]).expect("Write error");
+            }
+            assert_eq!(vec![0, 1, 2, 3, 4], read);
+            brw.flush().expect("Flush error");
+            assert_eq!(0, brw.pending_write_len());
+            let switches = events
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|e| matches!(e, Event::Switch(_)))
+                .count();
+            let mut expected: Vec<u8> = (0..10).collect();
+            expected.extend_from_slice(b"abcde");
+            assert_eq!(expected, brw.into_inner().expect("Error").into_inner());
+            switches
+        };
+        let without = alternate(0);
+        let with = alternate(100);
+        assert_eq!(9, without);
+        assert_eq!(1, with);
+
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(vec![1, 2, 3]));
+        brw.set_switch_hysteresis(100);
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        brw.write_all(b"xy").expect("Write error");
+        assert!(brw.is_reader());
+        assert_eq!(2, brw.pending_write_len());
+        assert!(!brw.can_finalize());
+        assert_eq!(
+            vec![1, 2, 3, b'x', b'y'],
+            brw.into_inner().expect("Error").into_inner()
+        );
+    }
+
+    #[test]
+    fn testreadarray() {
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(b"\x7fELF\x02rest".to_vec()));
+        let magic: [u8; 4] = brw.read_array().expect("Read error");
+        assert_eq!(*b"\x7fELF", magic);
+        brw.write_all(b"!").expect("Write error");
+        assert_eq!([2], brw.read_array::<1>().expect("Read error"));
+
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(b"\x7fELF".to_vec()));
+        assert_eq!(*b"\x7fELF", brw.read_array::<4>().expect("Read error"));
+        let err = brw.read_array::<1>().expect_err("Expected error");
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn testwritearray() {
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.write_array(*b"MAGC").expect("Write error");
+        brw.write_array([1, 2, 3, 4]).expect("Write error");
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        assert_eq!(*b"MAGC", brw.read_array::<4>().expect("Read error"));
+        assert_eq!([1, 2, 3, 4], brw.read_array::<4>().expect("Read error"));
+
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        brw.write_array([9; 8]).expect("Write error");
+        let data = brw.into_inner().expect("Error").into_inner();
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(data));
+        assert_eq!([9; 8], brw.read_array::<8>().expect("Read error"));
+    }
+
+    #[test]
+    fn testwritebufferspare() {
+        let mut brw = BufReaderWriterRand::writer_with_capacity(16, Cursor::new(Vec::new()));
+        brw.write_all(b"abcd").expect("Write error");
+        let spare = brw.write_buffer_spare();
+        assert_eq!(12, spare.len());
+        spare[..3].copy_from_slice(b"efg");
+        brw.advance_written(3).expect("Advance error");
+        assert_eq!(7, brw.pending_write_len());
+        let err = brw.advance_written(1).expect_err("Expected error");
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        brw.flush().expect("Flush error");
+        assert_eq!(b"abcdefg", &brw.get_ref().get_ref()[..]);
+        let ptr = brw.write_buffer_spare().as_ptr();
+        let spare = brw.write_buffer_spare();
+        assert_eq!(16, spare.len());
+        assert_eq!(ptr, spare.as_ptr());
+        assert_eq!(b"efg", &spare[..3]);
+        spare[..2].copy_from_slice(b"hi");
+        brw.advance_written(2).expect("Advance error");
+        brw.flush().expect("Flush error");
+        assert_eq!(b"abcdefghi", &brw.get_ref().get_ref()[..]);
+
+        let mut brw = BufReaderWriterSeq::reader_with_capacities(4, 8, Cursor::new(Vec::new()));
+        let spare = brw.write_buffer_spare();
+        assert_eq!(8, spare.len());
+        spare.copy_from_slice(b"12345678");
+        let err = brw.advance_written(9).expect_err("Expected error");
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        brw.advance_written(5).expect("Advance error");
+        assert!(!brw.is_reader());
+        brw.flush().expect("Flush error");
+        assert_eq!(b"12345", &brw.get_ref().get_ref()[..]);
+    }
+
+    #[test]
+    fn testinnertypename() {
+        let brw = BufReaderWriterRand::new_reader(tempfile().expect("tempfile error"));
+        assert!(brw.inner_type_name().contains("File"));
+        assert!(format!("{:?}", brw).contains(brw.inner_type_name()));
+
+        let brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::<u8>::new()));
+        assert!(brw.inner_type_name().contains("Cursor"));
+    }
+
+    #[test]
+    fn testbyteswapreadahead() {
+        let mut brw = BufReaderWriterRand::new_reader(Cursor::new(vec![0; 8]));
+        brw.set_byteswap(4);
+        brw.read_exact(&mut [0; 3]).expect("Read error");
+        assert_eq!(3, brw.stream_position().expect("Seek error"));
+        brw.write_all(&[9]).expect("Write error");
+        brw.flush().expect("Flush error");
+        assert_eq!(&[0, 0, 0, 9, 0, 0, 0, 0], &brw.get_ref().get_ref()[..]);
+
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        assert_eq!(5, brw.seek(SeekFrom::Current(4)).expect("Seek error"));
+        let mut buf = [0; 3];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!([0, 0, 0], buf);
+
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(vec![1, 2, 3, 4, 5, 6, 7, 8]));
+        brw.set_byteswap(4);
+        let mut buf = [0; 3];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!([4, 3, 2], buf);
+        assert_eq!(3, brw.stream_position().expect("Seek error"));
+        assert_eq!(6, brw.seek(SeekFrom::Current(3)).expect("Seek error"));
+    }
+
+    #[test]
+    fn testbyteswapwritefailure() {
+        let mock = MockIO {
+            fail_writes: true,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::writer_with_capacity(0, mock);
+        brw.set_byteswap(2);
+        assert_eq!(1, brw.write(&[1]).expect("Write error"));
+        brw.write(&[2, 3]).expect_err("Expected error");
+        brw.get_mut().fail_writes = false;
+        brw.write_all(&[2, 3]).expect("Write error");
+        brw.write_all(&[4]).expect("Write error");
+        assert_eq!(&[2, 1, 4, 3], &brw.get_ref().data.get_ref()[..]);
+    }
+
+    #[test]
+    fn testseekclearseof() {
+        let mut brw = BufReaderWriterSeq::new_reader(Cursor::new(vec![1u8, 2, 3]));
+        let mut out = Vec::new();
+        brw.read_to_end(&mut out).expect("Read error");
+        assert!(brw.is_eof());
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        assert!(!brw.is_eof());
+        out.clear();
+        brw.read_to_end(&mut out).expect("Read error");
+        assert_eq!(vec![1, 2, 3], out);
+    }
+
+    #[test]
+    fn testdeferredwritesseekexport() {
+        let mut brw = BufReaderWriterSeq::reader_with_capacity(4, Cursor::new(vec![0u8; 8]));
+        brw.set_switch_hysteresis(4);
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        brw.write_all(&[9]).expect("Write error");
+        let state = brw.export_state();
+        assert_eq!(Mode::Writer, state.mode);
+        assert_eq!(vec![9], state.pending_write);
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        assert!(!brw.needs_flush());
+        let data = brw.into_inner().expect("Unwrap error").into_inner();
+        assert_eq!(vec![0, 0, 0, 0, 9, 0, 0, 0], data);
+    }
+
+    #[test]
+    fn testensurewritecapacitybudget() {
+        let budget = Arc::new(BufferBudget::new(10000));
+        let mut brw = BufReaderWriterSeq::with_budget(budget.clone(), Cursor::new(Vec::new()));
+        assert_eq!(8192, brw.capacity());
+        assert!(brw.ensure_write_capacity(16384).is_err());
+        assert_eq!(8192, brw.capacity());
+        assert_eq!(8192, budget.used());
+        brw.ensure_write_capacity(10000).expect("Capacity error");
+        assert_eq!(10000, brw.capacity());
+        assert_eq!(10000, budget.used());
+        drop(brw);
+        assert_eq!(0, budget.used());
+    }
+
+    #[test]
+    fn testimportstatebufferedreader() {
+        let mut brw =
+            BufReaderWriterSeq::reader_with_capacity(4, Cursor::new(vec![1u8, 2, 3, 4, 5, 6]));
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        brw.import_state(ExportedBuffers {
+            mode: Mode::Reader,
+            pending_write: Vec::new(),
+            saved_read: vec![9, 9],
+        })
+        .expect("Import error");
+        brw.debug_assert_invariants();
+        assert_eq!(&[9, 9, 2, 3], brw.fill_and_peek(4).expect("Peek error"));
+        let mut out = Vec::new();
+        brw.read_to_end(&mut out).expect("Read error");
+        assert_eq!(vec![9, 9, 2, 3, 4, 5, 6], out);
+    }
+
+    #[test]
+    fn testwritealignmentpartialflush() {
+        let mut brw = BufReaderWriterRand::writer_with_capacity(640, MockIO::default());
+        brw.set_write_alignment(Some(512));
+        let flushed = Arc::new(Mutex::new(Vec::new()));
+        let f = flushed.clone();
+        brw.set_on_auto_flush(move |n| f.lock().unwrap().push(n));
+        let data: Vec<u8> = (0..1800).map(|i| i as u8).collect();
+        brw.write_all(&data[..600]).expect("Write error");
+        brw.write_all(&data[600..700]).expect("Write error");
+        assert_eq!(vec![512], brw.get_ref().writes);
+        assert_eq!(vec![512], *flushed.lock().unwrap());
+        brw.write_all(&data[700..]).expect("Write error");
+        assert_eq!(vec![512, 512, 512], brw.get_ref().writes);
+        assert_eq!(vec![512, 188], *flushed.lock().unwrap());
+        brw.flush().expect("Flush error");
+        assert_eq!(&data[..], &brw.get_ref().data.get_ref()[..]);
+
+        let mut brw = BufReaderWriterRand::writer_with_capacity(256, MockIO::default());
+        brw.set_write_alignment(Some(512));
+        let err = brw
+            .write(&[0; 300])
+            .expect_err("Expected an alignment error");
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn testreadallintorestorefailure() {
+        let mock = MockIO {
+            data: Cursor::new((0..10).collect()),
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterRand::reader_with_capacity(4, mock);
+        brw.read_exact(&mut [0; 2]).expect("Read error");
+        brw.get_mut().fail_seeks_after = Some(2);
+        let mut buf = Vec::new();
+        assert!(brw.read_all_into(&mut buf).is_err());
+        assert_eq!(0, brw.buffered_read_len());
+        brw.get_mut().fail_seeks_after = None;
+        assert_eq!(10, brw.stream_position().expect("Seek error"));
+    }
+
+    #[test]
+    fn testbyteswappartialfinish() {
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.set_byteswap(4);
+        brw.write_all(&[1, 2, 3, 4, 5, 6]).expect("Write error");
+        let inner = brw.into_inner().expect("into_inner error");
+        assert_eq!(&[4, 3, 2, 1, 5, 6], &inner.get_ref()[..]);
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.set_byteswap(4);
+        brw.write_all(&[1, 2, 3, 4, 5]).expect("Write error");
+        assert_eq!(vec![4, 3, 2, 1, 5], brw.export_state().pending_write);
+        brw.write_all(&[6]).expect("Write error");
+        brw.seek_fresh(SeekFrom::Start(0)).expect("Seek error");
+        brw.set_byteswap(0);
+        let mut buf = [0; 6];
+        brw.read_exact(&mut buf).expect("Read error");
+        assert_eq!([4, 3, 2, 1, 5, 6], buf);
+
+        let mut brw = BufReaderWriterRand::new_writer(Cursor::new(Vec::new()));
+        brw.set_byteswap(4);
+        brw.write_all(&[1, 2, 3, 4, 5, 6]).expect("Write error");
+        assert!(brw.fill_buf().expect("Read error").is_empty());
+        assert_eq!(&[4, 3, 2, 1, 5, 6], &brw.get_ref().get_ref()[..]);
+
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(Vec::new()));
+        brw.set_byteswap(4);
+        brw.write_all(&[1, 2, 3, 4, 5, 6]).expect("Write error");
+        let state = brw.export_state();
+        assert_eq!(Mode::Writer, state.mode);
+        assert_eq!(vec![4, 3, 2, 1, 5, 6], state.pending_write);
+        let inner = brw.into_inner().expect("into_inner error");
+        assert_eq!(&[4, 3, 2, 1, 5, 6], &inner.get_ref()[..]);
+
+        let mut data = Vec::new();
+        let mut brw = BufReaderWriterSeq::new_writer(Cursor::new(&mut data));
+        brw.set_byteswap(4);
+        brw.write_all(&[1, 2, 3, 4, 5, 6]).expect("Write error");
+        drop(brw);
+        assert_eq!(vec![4, 3, 2, 1, 5, 6], data);
+    }
+
+    #[test]
+    fn testdeferredwritesfinish() {
+        let mock = MockIO {
+            data: Cursor::new(vec![1; 16]),
+            fail_writes: true,
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::reader_with_capacities(4, 64, mock);
+        brw.set_switch_hysteresis(100);
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        brw.write_all(&[9; 20]).expect("Write error");
+        assert_eq!(Mode::Reader, brw.mode());
+        let e = brw
+            .swap_capacities()
+            .expect_err("Deferred writes should not fit");
+        assert_eq!(std::io::ErrorKind::BrokenPipe, e.kind());
+        let e = match brw.into_inner() {
+            Ok(_) => panic!("into_inner should fail"),
+            Err(e) => e,
+        };
+        assert_eq!(std::io::ErrorKind::BrokenPipe, e.error().kind());
+        assert_eq!(&[9; 20][..], e.into_inner().buffer());
+
+        let mock = MockIO {
+            data: Cursor::new(vec![1; 16]),
+            ..Default::default()
+        };
+        let mut brw = BufReaderWriterSeq::reader_with_capacities(4, 64, mock);
+        brw.set_switch_hysteresis(100);
+        brw.read_exact(&mut [0; 1]).expect("Read error");
+        brw.write_all(&[9; 20]).expect("Write error");
+        brw.swap_capacities().expect("Swap error");
+        assert_eq!(Mode::Reader, brw.mode());
+        assert_eq!(vec![20], brw.get_ref().writes);
+        brw.write_all(&[8; 2]).expect("Write error");
+        let inner = match brw.into_inner() {
+            Ok(inner) => inner,
+            Err(_) => panic!("into_inner error"),
+        };
+        assert_eq!(vec![20, 2], inner.writes);
+    }
+
+    #[test]
+    fn testwriteallsmallbookkeeping() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let flushed = Arc::new(Mutex::new(Vec::new()));
+        let mut brw = BufReaderWriterRand::writer_with_capacity(4, MockIO::default());
+        brw.set_observer(Box::new(Recorder(events.clone())));
+        let f = flushed.clone();
+        brw.set_on_auto_flush(move |n| f.lock().unwrap().push(n));
+        brw.write_all_small(1..=10).expect("Write error");
+        assert_eq!(vec![Event::Write(10)], *events.lock().unwrap());
+        assert_eq!(vec![8], *flushed.lock().unwrap());
+        assert_eq!(10, brw.bytes_written());
+        assert_eq!(1, brw.total_ops());
+        assert_eq!(10, brw.high_water_mark());
+
+        let seeks = brw.get_ref().seeks;
+        brw.begin_write_txn();
+        brw.write_all_small(vec![11, 12]).expect("Write error");
+        assert_eq!(seeks, brw.get_ref().seeks);
+        assert_eq!(12, brw.high_water_mark());
+        assert_eq!(12, brw.bytes_written());
+        assert_eq!(2, brw.total_ops());
+        brw.commit().expect("Commit error");
+    }
+
+    #[test]
+    fn testentrypointcounters() {
+        use std::io::IoSliceMut;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut brw = BufReaderWriterRand::writer_with_capacity(16, Cursor::new(Vec::new()));
+        brw.set_observer(Box::new(Recorder(events.clone())));
+        brw.write_all(b"ab").expect("Write error");
+        brw.write_buffer_spare()[..3].copy_from_slice(b"cde");
+        brw.advance_written(3).expect("Advance error");
+        brw.write_all_small(b"fg".iter().copied())
+            .expect("Write error");
+        assert_eq!(7, brw.bytes_written());
+        assert_eq!(7, brw.high_water_mark());
+        brw.seek(SeekFrom::Start(0)).expect("Seek error");
+        let (mut a, mut b) = ([0; 3], [0; 4]);
+        let n = brw
+            .read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)])
+            .expect("Read error");
+        assert_eq!(7, n);
+        assert_eq!((b"abc", b"defg"), (&a, &b));
+        assert_eq!(4, brw.total_ops());
+        assert_eq!(1, brw.switch_count());
+        assert_eq!(
+            vec![
+                Event::Write(2),
+                Event::Write(3),
+                Event::Write(2),
+                Event::Switch(Mode::Reader),
+                Event::Read(7)
+            ],
+            *events.lock().unwrap()
+        );
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut brw =
+            BufReaderWriterSeq::reader_with_capacity(16, Cursor::new(b"abcdefg".to_vec()));
+        brw.set_observer(Box::new(Recorder(events.clone())));
+        let (mut a, mut b) = ([0; 3], [0; 2]);
+        let n = brw
+            .read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)])
+            .expect("Read error");
+        assert_eq!(5, n);
+        brw.write_buffer_spare()[..2].copy_from_slice(b"xy");
+        brw.advance_written(2).expect("Advance error");
+        brw.read_exact(&mut a[..2]).expect("Read error");
+        assert_eq!(b"fg", &a[..2]);
+        assert_eq!(3, brw.total_ops());
+        assert_eq!(2, brw.switch_count());
+        assert_eq!(
+            vec![
+                Event::Read(5),
+                Event::Switch(Mode::Writer),
+                Event::Write(2),
+                Event::Switch(Mode::Reader),
+                Event::Read(2)
+            ],
+            *events.lock().unwrap()
+        );
+    }
 }
@@ -1,29 +1,57 @@
-use std::io::{self, BufReader, BufWriter, IntoInnerError, Read, Seek, SeekFrom, Write};
+use crate::{
+    poisoned, write_raw, BlockSize, BufReaderWriterConfig, BufReaderWriterState, ByteSwap,
+    EmptySink, ExportedBuffers, IoObserver, Mode, ModeSwitchError, Syncable, Truncate, POISONED,
+};
+use std::fmt;
+use std::fs::File;
+use std::io::{
+    self, BufRead, BufReader, BufWriter, IntoInnerError, IoSliceMut, Read, Seek, SeekFrom, Write,
+};
+use std::time::{Duration, Instant};
 
 enum BufIO<RW: Read + Write + Seek> {
     Reader(BufReader<RW>),
     Writer(BufWriter<RW>),
+    Raw(RW, Mode),
 }
 
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+/// Capacity of the temporary `BufReader` that `fill_buf` uses on an instance with a read capacity of 0.
+const UNBUFFERED_FILL_CAPACITY: usize = 512;
+
 impl<RW: Read + Write + Seek> BufIO<RW> {
+    /// Returns a new `BufWriter`, or with a capacity of 0, the unbuffered writer itself.
     fn new_writer(rw: RW, capacity: Option<usize>) -> BufIO<RW> {
-        BufIO::Writer(match capacity {
-            Some(c) => BufWriter::with_capacity(c, rw),
-            None => BufWriter::new(rw),
-        })
+        match capacity {
+            Some(0) => BufIO::Raw(rw, Mode::Writer),
+            Some(c) => BufIO::Writer(BufWriter::with_capacity(c, rw)),
+            None => BufIO::Writer(BufWriter::new(rw)),
+        }
     }
 
+    /// Returns a new `BufReader`, or with a capacity of 0, the unbuffered reader itself.
     fn new_reader(rw: RW, capacity: Option<usize>) -> BufIO<RW> {
-        BufIO::Reader(match capacity {
-            Some(c) => BufReader::with_capacity(c, rw),
-            None => BufReader::new(rw),
-        })
+        match capacity {
+            Some(0) => BufIO::Raw(rw, Mode::Reader),
+            Some(c) => BufIO::Reader(BufReader::with_capacity(c, rw)),
+            None => BufIO::Reader(BufReader::new(rw)),
+        }
     }
 
     fn get_mut(&mut self) -> &mut RW {
         match self {
             BufIO::Reader(r) => r.get_mut(),
             BufIO::Writer(w) => w.get_mut(),
+            BufIO::Raw(rw, _) => rw,
+        }
+    }
+
+    fn mode(&self) -> Mode {
+        match self {
+            BufIO::Reader(_) => Mode::Reader,
+            BufIO::Writer(_) => Mode::Writer,
+            BufIO::Raw(_, mode) => *mode,
         }
     }
 
@@ -31,6 +59,7 @@ impl<RW: Read + Write + Seek> BufIO<RW> {
         match self {
             BufIO::Reader(r) => r.get_ref(),
             BufIO::Writer(w) => w.get_ref(),
+            BufIO::Raw(rw, _) => rw,
         }
     }
 
@@ -38,6 +67,26 @@ impl<RW: Read + Write + Seek> BufIO<RW> {
         match self {
             BufIO::Reader(r) => Ok(r.into_inner()),
             BufIO::Writer(w) => Ok(w.into_inner()?),
+            BufIO::Raw(rw, _) => Ok(rw),
+        }
+    }
+
+    /// Unwraps the underlying IO instance as `into_inner` does, first adding `tail` to the end of the write buffer so that
+    /// it is flushed with the rest, or returned in the error if the flush fails.  `tail` must be empty unless in write
+    /// mode.
+    fn into_inner_with(self, tail: &[u8]) -> Result<RW, IntoInnerError<BufWriter<RW>>> {
+        match self {
+            BufIO::Writer(w) if !tail.is_empty() => {
+                let capacity = w.capacity();
+                let (rw, buf) = w.into_parts();
+                let mut data = buf.unwrap_or_else(|e| e.into_inner());
+                data.extend_from_slice(tail);
+                let mut w = BufWriter::with_capacity(capacity.max(data.len() + 1), rw);
+                // The data fits in the buffer, so this only copies it and cannot fail.
+                let _ = w.write_all(&data);
+                w.into_inner()
+            }
+            inner => inner.into_inner(),
         }
     }
 
@@ -45,74 +94,593 @@ impl<RW: Read + Write + Seek> BufIO<RW> {
         match self {
             BufIO::Reader(r) => r.capacity(),
             BufIO::Writer(w) => w.capacity(),
+            BufIO::Raw(..) => 0,
         }
     }
 }
 
+/// Buffers `data` in a `BufWriter` without writing to the underlying writer, which `BufWriter::write` would otherwise do
+/// if `data` filled the whole buffer.  `data` must fit in the remaining buffer space.
+fn buffer_pending<W: Write>(w: &mut BufWriter<W>, data: &[u8]) -> io::Result<()> {
+    if data.len() > 1 && data.len() >= w.capacity() {
+        w.write_all(&data[..1])?;
+        w.write_all(&data[1..])
+    } else {
+        w.write_all(data)
+    }
+}
+
 pub struct BufReaderWriterRand<RW: Read + Write + Seek> {
     inner: Option<BufIO<RW>>,
-    capacity: Option<usize>,
+    read_capacity: Option<usize>,
+    write_capacity: Option<usize>,
+    observer: Option<Box<dyn IoObserver + Send>>,
+    name: Option<String>,
+    spare: Vec<u8>,
+    spare_len: usize,
+    last_flush: Instant,
+    last_transition_capacity: Option<usize>,
+    byteswap: Option<ByteSwap>,
+    txn: Option<(usize, usize)>,
+    retry_on_interrupt: bool,
+    scratch: Vec<u8>,
+    run: (Mode, u64),
+    ops: (u64, u64),
+    on_auto_flush: Option<Box<dyn FnMut(usize) + Send>>,
+    write_alignment: Option<usize>,
+    bytes_written: u64,
+    high_water_mark: u64,
+    write_pos: Option<u64>,
 }
 
 impl<RW: Read + Write + Seek> BufReaderWriterRand<RW> {
-    /// Returns a new BufReaderWriterRand instance, expecting a write as the first operation.
-    pub fn new_writer(rw: RW) -> BufReaderWriterRand<RW> {
+    /// Performs a single read for `Read::read`, which retries it on `Interrupted` if enabled.
+    fn read_once(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.release_fill_reader();
+        let n = match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Reader(r) => r.read(buf)?,
+            BufIO::Raw(rw, Mode::Reader) => rw.read(buf)?,
+            _ => {
+                self.switch_to_reader()?;
+                return self.read_once(buf);
+            }
+        };
+        self.advance_read_pos(n);
+        self.count_op(Mode::Reader);
+        if let Some(o) = self.observer.as_mut() {
+            o.on_read(n);
+        }
+        Ok(n)
+    }
+
+    /// Performs a single read for `Read::read_buf`, which retries it on `Interrupted` if enabled.
+    #[cfg(feature = "nightly")]
+    fn read_buf_once(&mut self, mut cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
+        self.release_fill_reader();
+        let start = cursor.written();
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Reader(r) => r.read_buf(cursor.reborrow())?,
+            BufIO::Raw(rw, Mode::Reader) => rw.read_buf(cursor.reborrow())?,
+            _ => {
+                self.switch_to_reader()?;
+                return self.read_buf_once(cursor);
+            }
+        }
+        let n = cursor.written() - start;
+        self.advance_read_pos(n);
+        self.count_op(Mode::Reader);
+        if let Some(o) = self.observer.as_mut() {
+            o.on_read(n);
+        }
+        Ok(())
+    }
+
+    /// Performs a single read for `Read::read_vectored`, which retries it on `Interrupted` if enabled.
+    fn read_vectored_once(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.release_fill_reader();
+        let n = match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Reader(r) => r.read_vectored(bufs)?,
+            BufIO::Raw(rw, Mode::Reader) => rw.read_vectored(bufs)?,
+            _ => {
+                self.switch_to_reader()?;
+                return self.read_vectored_once(bufs);
+            }
+        };
+        self.advance_read_pos(n);
+        self.count_op(Mode::Reader);
+        if let Some(o) = self.observer.as_mut() {
+            o.on_read(n);
+        }
+        Ok(n)
+    }
+
+    /// Performs a single write for `Write::write`, which retries it on `Interrupted` if enabled.
+    fn write_once(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => {
+                let pos = match self.write_pos {
+                    Some(pos) => pos,
+                    None => w.get_mut().stream_position()? + w.buffer().len() as u64,
+                };
+                let pending = w.buffer().len();
+                let capacity = w.capacity();
+                let n = match self.write_alignment {
+                    Some(align) if pending + buf.len() > capacity || buf.len() >= capacity => {
+                        self.write_aligned(buf, align)?
+                    }
+                    _ => {
+                        let n = w.write(buf)?;
+                        if pending > 0 && w.buffer().len() != pending + n {
+                            if let Some(f) = self.on_auto_flush.as_mut() {
+                                f(pending);
+                            }
+                        }
+                        n
+                    }
+                };
+                self.record_write(pos, n);
+                Ok(n)
+            }
+            BufIO::Raw(rw, Mode::Writer) if self.write_alignment.is_none() => {
+                let pos = match self.write_pos {
+                    Some(pos) => pos,
+                    None => rw.stream_position()?,
+                };
+                let n = rw.write(buf)?;
+                self.record_write(pos, n);
+                Ok(n)
+            }
+            BufIO::Raw(_, Mode::Writer) => {
+                self.write_aligned(buf, self.write_alignment.unwrap_or(1))
+            }
+            _ => {
+                self.switch_to_writer()?;
+                self.write_once(buf)
+            }
+        }
+    }
+
+    /// Writes `buf` when it does not fit in the write buffer and a write alignment is set.  The largest multiple of `align`
+    /// bytes of the buffered data followed by `buf` is written to the underlying writer, and the remainder is buffered.
+    /// Only the bytes of `buf` that complete the last block of buffered data are copied; the rest is written in place.
+    fn write_aligned(&mut self, buf: &[u8], align: usize) -> io::Result<usize> {
+        let align = align.max(1);
+        let w = match self.inner.take() {
+            Some(BufIO::Writer(w)) if w.capacity() >= align => w,
+            Some(inner @ (BufIO::Writer(_) | BufIO::Raw(_, Mode::Writer))) => {
+                self.inner = Some(inner);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "write buffer capacity is less than the write alignment",
+                ));
+            }
+            inner => {
+                self.inner = inner;
+                return Err(poisoned());
+            }
+        };
+        let capacity = w.capacity();
+        let (mut rw, pending) = w.into_parts();
+        let mut data = pending.unwrap_or_else(|e| e.into_inner());
+        let pending_len = data.len();
+        let head = ((align - pending_len % align) % align).min(buf.len());
+        data.extend_from_slice(&buf[..head]);
+        let aligned = data.len() - data.len() % align;
+        let body = head + (buf.len() - head) / align * align;
+        let (mut flushed, mut result) = write_raw(&mut rw, &data[..aligned]);
+        if result.is_ok() {
+            let (written, body_result) = write_raw(&mut rw, &buf[head..body]);
+            flushed += written;
+            result = body_result;
+        }
+        let mut w = BufWriter::with_capacity(capacity, rw);
+        let n = match result {
+            Ok(()) => buffer_pending(&mut w, &data[aligned..])
+                .and_then(|_| buffer_pending(&mut w, &buf[body..]))
+                .map(|_| buf.len()),
+            Err(e) if flushed < pending_len => {
+                buffer_pending(&mut w, &data[flushed..pending_len]).and(Err(e))
+            }
+            Err(_) if flushed > pending_len => Ok(flushed - pending_len),
+            Err(e) => Err(e),
+        };
+        self.inner = Some(BufIO::Writer(w));
+        if pending_len > 0 && flushed > 0 {
+            if let Some(f) = self.on_auto_flush.as_mut() {
+                f(flushed.min(pending_len));
+            }
+        }
+        n
+    }
+
+    /// Updates the position, counters, transaction and observer for a write of `n` bytes at logical position `pos`.
+    fn record_write(&mut self, pos: u64, n: usize) {
+        self.advance_write_pos(pos, n);
+        self.bytes_written += n as u64;
+        if let Some((_, written)) = self.txn.as_mut() {
+            *written += n;
+        }
+        self.count_op(Mode::Writer);
+        if let Some(o) = self.observer.as_mut() {
+            o.on_write(n);
+        }
+    }
+
+    /// Records that `n` bytes were written at logical position `pos`, raising the high water mark if needed.
+    fn advance_write_pos(&mut self, pos: u64, n: usize) {
+        let end = pos + n as u64;
+        self.write_pos = Some(end);
+        self.high_water_mark = self.high_water_mark.max(end);
+    }
+
+    /// Moves the logical position past `n` bytes read, if it is known, so that a following switch to write mode with no
+    /// buffered read data needn't query it.
+    fn advance_read_pos(&mut self, n: usize) {
+        if let Some(pos) = self.write_pos.as_mut() {
+            *pos += n as u64;
+        }
+    }
+
+    /// Moves the unbuffered underlying IO instance, of a lazily constructed `BufReaderWriter` or one with a capacity of 0,
+    /// into a new `BufReader` or `BufWriter`, or with a capacity of 0 for the new mode, keeps it unbuffered in that mode.
+    /// Does nothing if already in mode `to`.
+    fn switch_from_raw(&mut self, to: Mode) {
+        match self.inner.take() {
+            Some(BufIO::Raw(rw, mode)) if mode != to => {
+                self.inner = Some(match to {
+                    Mode::Writer => BufIO::new_writer(rw, self.write_capacity),
+                    _ => BufIO::new_reader(rw, self.read_capacity),
+                });
+                self.last_transition_capacity = self.inner.as_ref().map(|b| b.capacity());
+                if let Some(o) = self.observer.as_mut() {
+                    o.on_switch(to);
+                }
+            }
+            inner => self.inner = inner,
+        }
+    }
+
+    /// Gives an unbuffered instance in read mode a small `BufReader`, for `fill_buf` and the methods built on it, which
+    /// `release_fill_reader` drops again once its data has been read.
+    fn attach_fill_reader(&mut self) {
+        self.inner = match self.inner.take() {
+            Some(BufIO::Raw(rw, Mode::Reader)) => Some(BufIO::Reader(BufReader::with_capacity(
+                UNBUFFERED_FILL_CAPACITY,
+                rw,
+            ))),
+            inner => inner,
+        };
+    }
+
+    /// Drops the `BufReader` given by `attach_fill_reader` to an instance with a read capacity of 0 once its buffer is
+    /// empty, so that reads go straight to the underlying reader again.
+    fn release_fill_reader(&mut self) {
+        if self.read_capacity != Some(0) {
+            return;
+        }
+        self.inner = match self.inner.take() {
+            Some(BufIO::Reader(r)) if r.buffer().is_empty() => {
+                Some(BufIO::Raw(r.into_inner(), Mode::Reader))
+            }
+            inner => inner,
+        };
+    }
+
+    /// Counts a read or write towards the current run of operations of the same kind.
+    fn count_op(&mut self, mode: Mode) {
+        self.ops.0 += 1;
+        if self.run.0 == mode {
+            self.run.1 += 1;
+        } else {
+            if self.run.0 != Mode::Unset {
+                self.ops.1 += 1;
+            }
+            self.run = (mode, 1);
+        }
+    }
+
+    /// Takes the logical position of a new reader to be 0, the start of the underlying IO instance, as nothing has been
+    /// read yet.  See `high_water_mark`.
+    fn at_start(mut self) -> Self {
+        self.write_pos = Some(0);
+        self
+    }
+
+    fn from_parts(
+        inner: BufIO<RW>,
+        read_capacity: Option<usize>,
+        write_capacity: Option<usize>,
+    ) -> BufReaderWriterRand<RW> {
+        let mode = inner.mode();
         BufReaderWriterRand {
-            inner: Some(BufIO::new_writer(rw, None)),
-            capacity: None,
+            inner: Some(inner),
+            read_capacity,
+            write_capacity,
+            observer: None,
+            name: None,
+            spare: Vec::new(),
+            spare_len: 0,
+            last_flush: Instant::now(),
+            last_transition_capacity: None,
+            byteswap: None,
+            txn: None,
+            retry_on_interrupt: false,
+            scratch: Vec::new(),
+            run: (mode, 0),
+            ops: (0, 0),
+            on_auto_flush: None,
+            write_alignment: None,
+            bytes_written: 0,
+            high_water_mark: 0,
+            write_pos: None,
+        }
+    }
+
+    /// Flushes the writer and moves the underlying IO instance into a new `BufReader`.  If the flush fails, the writer is
+    /// kept and the returned error carries a `ModeSwitchError` with the unwritten data.
+    fn switch_to_reader(&mut self) -> io::Result<()> {
+        if self
+            .inner
+            .as_ref()
+            .map_or(false, |b| b.mode() == Mode::Writer)
+        {
+            self.write_swap_partial()?;
+        }
+        if let Some(BufIO::Raw(rw, mode)) = self.inner.as_mut() {
+            if *mode == Mode::Writer {
+                rw.flush()?;
+            }
+            self.switch_from_raw(Mode::Reader);
+            return Ok(());
+        }
+        if let Some(BufIO::Writer(w)) = self.inner.as_mut() {
+            if let Err(e) = w.flush() {
+                return Err(ModeSwitchError::wrap(e, w.buffer()));
+            }
+            let rw = match self.inner.take().ok_or_else(poisoned)?.into_inner() {
+                Ok(rw) => rw,
+                Err(e) => {
+                    let (e, w) = e.into_parts();
+                    let err = ModeSwitchError::wrap(e, w.buffer());
+                    self.inner = Some(BufIO::Writer(w));
+                    return Err(err);
+                }
+            };
+            self.inner = Some(BufIO::new_reader(rw, self.read_capacity));
+            self.last_transition_capacity = self.inner.as_ref().map(|b| b.capacity());
+            if let Some(o) = self.observer.as_mut() {
+                o.on_switch(Mode::Reader);
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards any buffered read data, seeking the underlying IO instance back to the current `BufReader` position, and
+    /// moves it into a new `BufWriter`.  The seek happens before anything is moved, so if it fails the reader is kept with
+    /// its buffered data and reading can continue.  With no buffered read data the underlying position is already correct,
+    /// so no seek is made, and the position for the high water mark is kept if known.
+    fn switch_to_writer(&mut self) -> io::Result<()> {
+        if let Some(BufIO::Raw(..)) = self.inner {
+            self.switch_from_raw(Mode::Writer);
+            return Ok(());
+        }
+        if let Some(BufIO::Reader(r)) = self.inner.as_mut() {
+            let remainder = r.buffer().len() as i64;
+            let pos = if remainder > 0 {
+                Some(r.get_mut().seek(SeekFrom::Current(-remainder))?)
+            } else {
+                None
+            };
+            let rw = self.inner.take().ok_or_else(poisoned)?.into_inner()?;
+            self.inner = Some(BufIO::new_writer(rw, self.write_capacity));
+            if pos.is_some() {
+                self.write_pos = pos;
+            }
+            self.last_transition_capacity = self.inner.as_ref().map(|b| b.capacity());
+            if let Some(o) = self.observer.as_mut() {
+                o.on_switch(Mode::Writer);
+            }
         }
+        Ok(())
+    }
+
+    /// Returns a new BufReaderWriterRand instance, expecting a write as the first operation.
+    pub fn new_writer(rw: RW) -> BufReaderWriterRand<RW> {
+        BufReaderWriterRand::from_parts(BufIO::new_writer(rw, None), None, None)
     }
 
     /// Returns a new BufReaderWriterRand instance, expecting a write as the first operation, with specified buffer capacity.
+    /// A capacity of 0 disables buffering, so that every read and write goes straight to the underlying IO instance while
+    /// mode switching works as usual.
     pub fn writer_with_capacity(capacity: usize, rw: RW) -> BufReaderWriterRand<RW> {
-        BufReaderWriterRand {
-            inner: Some(BufIO::new_writer(rw, Some(capacity))),
-            capacity: Some(capacity),
-        }
+        BufReaderWriterRand::from_parts(
+            BufIO::new_writer(rw, Some(capacity)),
+            Some(capacity),
+            Some(capacity),
+        )
     }
 
     /// Returns a new BufReaderWriter instance, expecting a read as the first operation.
     pub fn new_reader(rw: RW) -> BufReaderWriterRand<RW> {
-        BufReaderWriterRand {
-            inner: Some(BufIO::new_reader(rw, None)),
-            capacity: None,
-        }
+        BufReaderWriterRand::from_parts(BufIO::new_reader(rw, None), None, None).at_start()
     }
 
     /// Returns a new BufReaderWriter instance, expecting a read as the first operation, with specified buffer capacity.
+    /// A capacity of 0 disables buffering, so that every read and write goes straight to the underlying IO instance
+    /// while mode switching works as usual.  `fill_buf` and the methods built on it then read through a small temporary
+    /// buffer, which is dropped once its data has been read.
     pub fn reader_with_capacity(capacity: usize, rw: RW) -> BufReaderWriterRand<RW> {
-        BufReaderWriterRand {
-            inner: Some(BufIO::new_reader(rw, Some(capacity))),
-            capacity: Some(capacity),
-        }
+        BufReaderWriterRand::from_parts(
+            BufIO::new_reader(rw, Some(capacity)),
+            Some(capacity),
+            Some(capacity),
+        )
+        .at_start()
+    }
+
+    /// Returns a new BufReaderWriterRand instance, expecting a write as the first operation, with separate read and write buffer
+    /// capacities.
+    pub fn writer_with_capacities(
+        read_capacity: usize,
+        write_capacity: usize,
+        rw: RW,
+    ) -> BufReaderWriterRand<RW> {
+        BufReaderWriterRand::from_parts(
+            BufIO::new_writer(rw, Some(write_capacity)),
+            Some(read_capacity),
+            Some(write_capacity),
+        )
+    }
+
+    /// Returns a new BufReaderWriterRand instance, expecting a read as the first operation, with separate read and write buffer
+    /// capacities.
+    pub fn reader_with_capacities(
+        read_capacity: usize,
+        write_capacity: usize,
+        rw: RW,
+    ) -> BufReaderWriterRand<RW> {
+        BufReaderWriterRand::from_parts(
+            BufIO::new_reader(rw, Some(read_capacity)),
+            Some(read_capacity),
+            Some(write_capacity),
+        )
+        .at_start()
+    }
+
+    /// Returns a new BufReaderWriterRand instance that allocates no buffer until the first operation, which picks the
+    /// mode.  Until then `mode()` returns `Mode::Unset` and `capacity()` returns 0.
+    pub fn new_lazy(rw: RW) -> BufReaderWriterRand<RW> {
+        BufReaderWriterRand::from_parts(BufIO::Raw(rw, Mode::Unset), None, None)
+    }
+
+    /// Returns a new BufReaderWriterRand instance from an existing `BufReader`, keeping its buffered data and capacity.
+    pub fn from_bufreader(r: BufReader<RW>) -> BufReaderWriterRand<RW> {
+        let capacity = r.capacity();
+        BufReaderWriterRand::from_parts(BufIO::Reader(r), Some(capacity), Some(capacity))
+    }
+
+    /// Returns a new BufReaderWriterRand instance from an existing `BufWriter`, keeping its buffered data and capacity.
+    pub fn from_bufwriter(w: BufWriter<RW>) -> BufReaderWriterRand<RW> {
+        let capacity = w.capacity();
+        BufReaderWriterRand::from_parts(BufIO::Writer(w), Some(capacity), Some(capacity))
     }
 
     /// Gets a mutable reference to the underlying reader/writer.
     pub fn get_mut(&mut self) -> &mut RW {
-        self.inner.as_mut().unwrap().get_mut()
+        self.write_pos = None;
+        self.inner.as_mut().expect(POISONED).get_mut()
+    }
+
+    /// Replaces the underlying writer with `rw` and returns the old one, keeping any buffered writes so that they are flushed
+    /// to `rw`, starting at its current position.  Returns an error of kind `InvalidInput` if not in write mode.
+    pub fn replace_inner(&mut self, rw: RW) -> io::Result<RW> {
+        self.write_pos = None;
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => Ok(std::mem::replace(w.get_mut(), rw)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "replace_inner requires write mode",
+            )),
+        }
     }
 
     /// Gets a reference to the underlying reader/writer.
     pub fn get_ref(&self) -> &RW {
-        self.inner.as_ref().unwrap().get_ref()
+        self.inner.as_ref().expect(POISONED).get_ref()
     }
 
     /// Unwraps this `BufReaderWriter`, returning the underlying reader/writer.  Note: the `BufReaderWriter` should be dropped after using this.
-    pub fn into_inner(self) -> Result<RW, IntoInnerError<BufWriter<RW>>> {
-        self.inner.unwrap().into_inner()
+    pub fn into_inner(mut self) -> Result<RW, IntoInnerError<BufWriter<RW>>> {
+        let partial = self
+            .byteswap
+            .as_mut()
+            .map_or_else(Vec::new, |s| s.take_write_partial());
+        self.inner.expect(POISONED).into_inner_with(&partial)
     }
 
-    /// Returns true if the `BufReaderWriter` in read mode, otherwise false for write mode.
-    pub fn is_reader(&self) -> bool {
-        match self.inner.as_ref().unwrap() {
-            BufIO::Reader(_) => true,
-            _ => false,
+    /// Returns true if `into_inner` has nothing to flush, so cannot fail or block on writing, as on a non-blocking
+    /// socket: in read mode, or in write mode with an empty write buffer.
+    pub fn can_finalize(&self) -> bool {
+        !self.needs_flush()
+    }
+
+    /// Returns the current mode of the `BufReaderWriter`.
+    pub fn mode(&self) -> Mode {
+        self.inner.as_ref().expect(POISONED).mode()
+    }
+
+    /// Sets an observer to be notified of reads, writes, flushes, and mode switches, replacing any previous observer.
+    pub fn set_observer(&mut self, mut obs: Box<dyn IoObserver + Send>) {
+        if let Some(name) = &self.name {
+            obs.on_name(name);
         }
+        self.observer = Some(obs);
+    }
+
+    /// Sets a name for this instance, for telling instances apart in diagnostics.  The name is included in the `Debug`
+    /// output and passed to the observer with `IoObserver::on_name`.
+    pub fn set_name(&mut self, name: impl Into<String>) {
+        let name = name.into();
+        if let Some(o) = self.observer.as_mut() {
+            o.on_name(&name);
+        }
+        self.name = Some(name);
+    }
+
+    /// Swaps byte order in groups of `width` bytes as data is read with `read` and written with `write`, for reading
+    /// and writing binary data of a foreign byte order.  A `width` of 0 or 1 turns swapping off, discarding any bytes
+    /// held back by an earlier setting.  Bytes that don't yet make up a whole group are held back: when reading, until
+    /// the rest of the group is read, with an incomplete group at the end of the stream returned as is; when writing,
+    /// until the group is completed, with `flush`, a seek, a read or `into_inner` writing out an incomplete group as
+    /// is.  Data read ahead of the caller to complete a group is excluded from `stream_position`, and discarded by a
+    /// write or a seek, which move the underlying position back to exclude it first.  `BufRead`, `read_vectored` and
+    /// the other read methods that bypass `read` don't swap.
+    pub fn set_byteswap(&mut self, width: usize) {
+        self.byteswap = if width > 1 {
+            Some(ByteSwap::new(width))
+        } else {
+            None
+        };
+    }
+
+    /// Writes out, as is, an incomplete group held back from writing by the byte swap.
+    fn write_swap_partial(&mut self) -> io::Result<()> {
+        match self.byteswap.take() {
+            Some(mut swap) => {
+                let partial = swap.take_write_partial();
+                let result = self.write_all(&partial);
+                self.byteswap = Some(swap);
+                result
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the name set with `set_name`, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the type name of the underlying IO instance, for diagnostics in generic code.  The name is as given by
+    /// `std::any::type_name`, so its exact form is not guaranteed.
+    pub fn inner_type_name(&self) -> &'static str {
+        std::any::type_name::<RW>()
+    }
+
+    /// Returns true if the `BufReaderWriter` in read mode, otherwise false for write mode or before the first operation of
+    /// a lazily constructed instance.
+    pub fn is_reader(&self) -> bool {
+        self.mode() == Mode::Reader
+    }
+
+    /// Returns false for a lazily constructed `BufReaderWriter` before its first read or write, otherwise true.
+    pub fn is_initialized(&self) -> bool {
+        self.mode() != Mode::Unset
     }
 
     /// Gets a reference to the underlying buffered reader, available if in read mode.
     pub fn get_bufreader_ref(&self) -> Option<&BufReader<RW>> {
-        match self.inner.as_ref().unwrap() {
+        match self.inner.as_ref().expect(POISONED) {
             BufIO::Reader(r) => Some(r),
             _ => None,
         }
@@ -120,23 +688,56 @@ impl<RW: Read + Write + Seek> BufReaderWriterRand<RW> {
 
     /// Gets a mutable reference to the underlying buffered reader, available if in read mode.
     pub fn get_bufreader_mut(&mut self) -> Option<&mut BufReader<RW>> {
-        match self.inner.as_mut().unwrap() {
+        self.write_pos = None;
+        match self.inner.as_mut().expect(POISONED) {
             BufIO::Reader(r) => Some(r),
             _ => None,
         }
     }
 
+    /// Switches to read mode and calls `f` with the underlying `BufReader`, returning its result.  The `BufReaderWriter`
+    /// stays in read mode afterwards, with any data the closure left buffered available to subsequent reads.
+    pub fn with_reader<T>(&mut self, f: impl FnOnce(&mut BufReader<RW>) -> T) -> io::Result<T> {
+        Ok(f(self.as_std_bufreader()?))
+    }
+
+    /// Switches to read mode and returns the underlying `BufReader`, for APIs that take a `&mut BufReader`.  The
+    /// `BufReaderWriter` stays in read mode, with any data left in the `BufReader` buffer available to subsequent reads.
+    pub fn as_std_bufreader(&mut self) -> io::Result<&mut BufReader<RW>> {
+        self.switch_to_reader()?;
+        self.attach_fill_reader();
+        self.count_op(Mode::Reader);
+        self.write_pos = None;
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Reader(r) => Ok(r),
+            _ => Err(poisoned()),
+        }
+    }
+
     /// Unwraps this `BufReaderWriter` returning the BufReader, available if in read mode.  Note: the `BufReaderWriter` should be dropped after using this.
     pub fn into_bufreader(self) -> Option<BufReader<RW>> {
-        match self.inner.unwrap() {
+        match self.inner.expect(POISONED) {
             BufIO::Reader(r) => Some(r),
             _ => None,
         }
     }
 
+    /// Unwraps this `BufReaderWriter` returning the `BufReader` if in read mode, otherwise returns the `BufReaderWriter`
+    /// unchanged in the error.
+    #[allow(clippy::result_large_err)]
+    pub fn try_into_bufreader(mut self) -> Result<BufReader<RW>, Self> {
+        match self.inner.take().expect(POISONED) {
+            BufIO::Reader(r) => Ok(r),
+            inner => {
+                self.inner = Some(inner);
+                Err(self)
+            }
+        }
+    }
+
     /// Gets a reference to the underlying buffered writer, available if in write mode.
     pub fn get_bufwriter_ref(&self) -> Option<&BufWriter<RW>> {
-        match self.inner.as_ref().unwrap() {
+        match self.inner.as_ref().expect(POISONED) {
             BufIO::Writer(w) => Some(w),
             _ => None,
         }
@@ -144,7 +745,8 @@ impl<RW: Read + Write + Seek> BufReaderWriterRand<RW> {
 
     /// Gets a mutable reference to the underlying buffered writer, available if in write mode.
     pub fn get_bufwriter_mut(&mut self) -> Option<&mut BufWriter<RW>> {
-        match self.inner.as_mut().unwrap() {
+        self.write_pos = None;
+        match self.inner.as_mut().expect(POISONED) {
             BufIO::Writer(w) => Some(w),
             _ => None,
         }
@@ -152,30 +754,1165 @@ impl<RW: Read + Write + Seek> BufReaderWriterRand<RW> {
 
     /// Unwraps this `BufReaderWriter` returning the `BufWriter`, available if in read mode.  Note: the `BufReaderWriter` should be dropped after using this.
     pub fn into_bufwriter(self) -> Option<BufWriter<RW>> {
-        match self.inner.unwrap() {
+        match self.inner.expect(POISONED) {
             BufIO::Writer(w) => Some(w),
             _ => None,
         }
     }
 
+    /// Unwraps this `BufReaderWriter` returning the `BufWriter` if in write mode, otherwise returns the `BufReaderWriter`
+    /// unchanged in the error.
+    #[allow(clippy::result_large_err)]
+    pub fn try_into_bufwriter(mut self) -> Result<BufWriter<RW>, Self> {
+        match self.inner.take().expect(POISONED) {
+            BufIO::Writer(w) => Ok(w),
+            inner => {
+                self.inner = Some(inner);
+                Err(self)
+            }
+        }
+    }
+
+    /// Returns the position of the underlying reader/writer, without flushing or discarding any buffered data.  In read mode
+    /// this is ahead of the logical position returned by `stream_position()` by the amount of buffered read data, and in
+    /// write mode it is behind it by the amount of data waiting to be written.  Takes `&mut self` because
+    /// `Seek::stream_position` does.
+    pub fn underlying_position(&mut self) -> io::Result<u64> {
+        self.inner
+            .as_mut()
+            .ok_or_else(poisoned)?
+            .get_mut()
+            .stream_position()
+    }
+
+    /// Returns the length of the underlying stream.  Any buffered writes are flushed first so that they are included, while
+    /// buffered read data is kept, and the logical position is unchanged.
+    pub fn stream_len(&mut self) -> io::Result<u64> {
+        self.flush()?;
+        let rw = self.get_mut();
+        let pos = rw.stream_position()?;
+        let len = rw.seek(SeekFrom::End(0))?;
+        if pos != len {
+            rw.seek(SeekFrom::Start(pos))?;
+        }
+        Ok(len)
+    }
+
+    int_io_methods! {
+        u16: read_u16_be, read_u16_le, write_u16_be, write_u16_le;
+        u32: read_u32_be, read_u32_le, write_u32_be, write_u32_le;
+        u64: read_u64_be, read_u64_le, write_u64_be, write_u64_le;
+    }
+
+    /// Reads into each of `bufs` in turn, filling a buffer completely before moving to the next, and returns the total
+    /// number of bytes read.  Stops early only at end of stream, so a short total means every byte up to the end was read.
+    pub fn read_sequential_into(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let mut filled = 0;
+            while filled < buf.len() {
+                match self.read(&mut buf[filled..]) {
+                    Ok(0) => return Ok(total + filled),
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            total += filled;
+        }
+        Ok(total)
+    }
+
+    /// Reads all remaining data until the end of the stream into `buf`, replacing its contents, and returns the number of
+    /// bytes read.  Space for the remaining length is reserved up front, so reusing `buf` for a stream of the same or
+    /// smaller length needs no new allocation.  The length is found by seeking to the end and back.  If the seek back
+    /// fails, its error is returned and the buffered read data is discarded, so that the position reported by
+    /// `stream_position` is where the underlying IO instance was left, rather than reads silently resuming from the end.
+    pub fn read_all_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        buf.clear();
+        self.switch_to_reader()?;
+        if let Some(BufIO::Reader(r)) = self.inner.as_mut() {
+            let buffered = r.buffer().len() as u64;
+            let inner = r.get_mut();
+            let pos = inner.stream_position()?;
+            let end = inner.seek(SeekFrom::End(0))?;
+            if let Err(e) = inner.seek(SeekFrom::Start(pos)) {
+                r.consume(r.buffer().len());
+                return Err(e);
+            }
+            buf.reserve(end.saturating_sub(pos).saturating_add(buffered) as usize);
+        }
+        self.read_to_end(buf)
+    }
+
+    /// Seeks to `offset` and reads exactly `len` bytes into a new `Vec`, leaving the position at `offset + len`.  Returns an
+    /// `UnexpectedEof` error if the stream ends first, in which case the position is unspecified.
+    pub fn read_exact_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; len];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Flushes any buffered writes and seeks to `pos`, discarding any buffered read data, so that the next read starts with
+    /// an empty buffer at exactly `pos`.
+    pub fn seek_fresh(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.write_pos = None;
+        self.write_swap_partial()?;
+        let ahead = self.byteswap.as_ref().map_or(0, |s| s.read_ahead()) as i64;
+        let pos = match pos {
+            SeekFrom::Current(n) => SeekFrom::Current(n - ahead),
+            pos => pos,
+        };
+        let new = match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => {
+                w.flush()?;
+                w.get_mut().seek(pos)?
+            }
+            BufIO::Reader(r) => {
+                let remainder = r.buffer().len() as i64;
+                let new_pos = match pos {
+                    SeekFrom::Current(n) => r.get_mut().seek(SeekFrom::Current(n - remainder))?,
+                    _ => r.get_mut().seek(pos)?,
+                };
+                r.consume(remainder as usize);
+                new_pos
+            }
+            BufIO::Raw(rw, _) => rw.seek(pos)?,
+        };
+        if let Some(swap) = self.byteswap.as_mut() {
+            swap.clear_read();
+        }
+        Ok(new)
+    }
+
+    /// Writes each byte from an iterator into the write buffer, switching to write mode once rather than per byte.  The
+    /// call counts as a single write of all the bytes for the counters, the observer and `set_on_auto_flush`.
+    pub fn write_all_small(&mut self, bytes: impl IntoIterator<Item = u8>) -> io::Result<()> {
+        self.switch_to_writer()?;
+        let known_pos = self.write_pos;
+        let w = match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => w,
+            BufIO::Raw(rw, Mode::Writer) => {
+                let pos = match known_pos {
+                    Some(pos) => pos,
+                    None => rw.stream_position()?,
+                };
+                let data: Vec<u8> = bytes.into_iter().collect();
+                let (n, result) = write_raw(rw, &data);
+                if n > 0 || result.is_ok() {
+                    self.record_write(pos, n);
+                }
+                return result;
+            }
+            _ => return Err(poisoned()),
+        };
+        let pos = match known_pos {
+            Some(pos) => pos,
+            None => w.get_mut().stream_position()? + w.buffer().len() as u64,
+        };
+        let mut n = 0;
+        let mut flushed = 0;
+        let mut result = Ok(());
+        for b in bytes {
+            let pending = w.buffer().len();
+            result = w.write_all(&[b]);
+            if result.is_err() {
+                break;
+            }
+            if w.buffer().len() != pending + 1 {
+                flushed += pending;
+            }
+            n += 1;
+        }
+        if flushed > 0 {
+            if let Some(f) = self.on_auto_flush.as_mut() {
+                f(flushed);
+            }
+        }
+        if n > 0 || result.is_ok() {
+            self.record_write(pos, n);
+        }
+        result
+    }
+
+    /// Removes and returns the data waiting in the write buffer, available if in write mode.  The data is never written to
+    /// the underlying writer, so this effectively un-writes it.
+    pub fn take_write_buffer(&mut self) -> Option<Vec<u8>> {
+        self.write_pos = None;
+        match self.inner.take().expect(POISONED) {
+            BufIO::Writer(w) => {
+                let capacity = w.capacity();
+                let (rw, buf) = w.into_parts();
+                self.inner = Some(BufIO::new_writer(rw, Some(capacity)));
+                Some(buf.unwrap_or_else(|e| e.into_inner()))
+            }
+            inner => {
+                self.inner = Some(inner);
+                None
+            }
+        }
+    }
+
+    /// Discards all buffered read data and pending writes, and takes the current position of the underlying IO instance as
+    /// the new position.  This must be called after seeking or otherwise changing the underlying IO instance through
+    /// `get_mut`, since the buffers would otherwise refer to the old position.  Flush before using `get_mut` to keep
+    /// pending writes.
+    pub fn resync(&mut self) -> io::Result<()> {
+        if self.inner.is_none() {
+            return Err(poisoned());
+        }
+        self.take_write_buffer();
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Reader(r) => {
+                let n = r.buffer().len();
+                r.consume(n);
+            }
+            BufIO::Writer(w) => self.write_pos = Some(w.get_mut().stream_position()?),
+            BufIO::Raw(..) => {}
+        }
+        Ok(())
+    }
+
+    /// Switches to read mode and fills the read buffer, without consuming any data.  Returns the number of bytes buffered.
+    pub fn prefetch(&mut self) -> io::Result<usize> {
+        Ok(self.fill_buf()?.len())
+    }
+
+    /// Appends a line to `buf`, reading until a newline, which is included, or the end of the underlying reader.
+    /// Returns the number of bytes appended.  If more than `max_len` bytes precede the newline, returns an error of kind
+    /// `InvalidData` instead, leaving `buf` unchanged with the bytes read so far consumed, so no more than `max_len` bytes
+    /// are held for a line.  Also returns an error of kind `InvalidData` if the line is not valid UTF-8.
+    pub fn read_line_limited(&mut self, buf: &mut String, max_len: usize) -> io::Result<usize> {
+        let mut line = Vec::new();
+        loop {
+            let (done, used) = {
+                let available = match self.fill_buf() {
+                    Ok(b) => b,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                let (end, done) = match available.iter().position(|&b| b == b'\n') {
+                    Some(i) => (i, true),
+                    None => (available.len(), available.is_empty()),
+                };
+                if line.len() + end > max_len {
+                    (None, end)
+                } else {
+                    let used = if done && end < available.len() {
+                        end + 1
+                    } else {
+                        end
+                    };
+                    line.extend_from_slice(&available[..used]);
+                    (Some(done), used)
+                }
+            };
+            if used > 0 {
+                self.consume(used);
+            }
+            match done {
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "line exceeds the maximum length",
+                    ))
+                }
+                Some(true) => break,
+                Some(false) => {}
+            }
+        }
+        let line = String::from_utf8(line)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8"))?;
+        buf.push_str(&line);
+        Ok(line.len())
+    }
+
+    /// Appends bytes to `buf` until the delimiter `delim` or the end of the underlying reader is reached.  The delimiter is
+    /// included if found.  Returns the number of bytes appended.
+    pub fn read_until_into(&mut self, delim: u8, buf: &mut Vec<u8>) -> io::Result<usize> {
+        BufRead::read_until(self, delim, buf)
+    }
+
+    /// Appends bytes to `buf` until any of the delimiters in `delims` or the end of the underlying reader is reached.
+    /// The delimiter is included if found.  Returns the number of bytes appended and the delimiter found, or `None` at
+    /// the end of the underlying reader.
+    pub fn read_until_any(
+        &mut self,
+        delims: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> io::Result<(usize, Option<u8>)> {
+        let start = buf.len();
+        loop {
+            let (found, done, used) = {
+                let available = match self.fill_buf() {
+                    Ok(b) => b,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                match available.iter().position(|b| delims.contains(b)) {
+                    Some(i) => {
+                        buf.extend_from_slice(&available[..=i]);
+                        (Some(available[i]), true, i + 1)
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        (None, available.is_empty(), available.len())
+                    }
+                }
+            };
+            if used > 0 {
+                self.consume(used);
+            }
+            if done {
+                return Ok((buf.len() - start, found));
+            }
+        }
+    }
+
+    /// Appends a null-terminated string to `buf`, reading up to and consuming the terminating `\0`, which is not
+    /// appended.  Returns the number of bytes appended.  If the end of the underlying reader is reached before a
+    /// terminator, returns an error of kind `UnexpectedEof`, with the bytes read appended to `buf`.
+    pub fn read_cstr(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let n = self.read_until_into(0, buf)?;
+        if n == 0 || buf.last() != Some(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no null terminator before the end of the stream",
+            ));
+        }
+        buf.pop();
+        Ok(n - 1)
+    }
+
+    /// Returns the number of bytes of read data currently buffered.
+    pub fn buffered_read_len(&self) -> usize {
+        self.get_bufreader_ref().map_or(0, |r| r.buffer().len())
+    }
+
+    /// Returns true if read data is buffered, so that the next read returns data without reading from the underlying
+    /// reader, as for scheduling reads in an event loop.
+    pub fn read_ready(&self) -> bool {
+        self.buffered_read_len() > 0
+    }
+
+    /// Returns a copy of the read data currently buffered and not yet consumed, which is the data the next reads will return
+    /// before reading from the underlying reader.
+    pub fn peek_buffered(&self) -> Vec<u8> {
+        self.get_bufreader_ref()
+            .map_or_else(Vec::new, |r| r.buffer().to_vec())
+    }
+
+    /// Copies all remaining data to `w` directly from the read buffer, without an intermediate buffer.  Returns the number
+    /// of bytes copied.  `std::io::copy` cannot detect that this type is buffered, so this is the faster alternative.
+    pub fn copy_to<W: Write + ?Sized>(&mut self, w: &mut W) -> io::Result<u64> {
+        let mut copied = 0;
+        loop {
+            let data = match self.fill_buf() {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if data.is_empty() {
+                return Ok(copied);
+            }
+            let len = data.len();
+            w.write_all(data)?;
+            self.consume(len);
+            copied += len as u64;
+        }
+    }
+
+    /// Copies all data from `r` until its end through the write buffer, returning the number of bytes copied.  The
+    /// transfer buffer is allocated at the write buffer capacity on first use and reused by later calls.
+    pub fn copy_from<R: Read + ?Sized>(&mut self, r: &mut R) -> io::Result<u64> {
+        self.write_from(r, None)
+    }
+
+    /// Copies data from `src` through the write buffer until its end, or until `limit` bytes if given, returning the number
+    /// of bytes copied.  Uses the same reused transfer buffer as `copy_from`, and no more than `limit` bytes are read from
+    /// `src`.
+    pub fn write_from<R: Read + ?Sized>(
+        &mut self,
+        src: &mut R,
+        limit: Option<u64>,
+    ) -> io::Result<u64> {
+        self.switch_to_writer()?;
+        let mut scratch = std::mem::take(&mut self.scratch);
+        if scratch.is_empty() {
+            scratch.resize(self.capacity().max(1), 0);
+        }
+        let mut copied = 0;
+        let result = loop {
+            let len = limit.map_or(scratch.len(), |l| {
+                (l - copied).min(scratch.len() as u64) as usize
+            });
+            if len == 0 {
+                break Ok(copied);
+            }
+            let n = match src.read(&mut scratch[..len]) {
+                Ok(0) => break Ok(copied),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e),
+            };
+            if let Err(e) = self.write_all(&scratch[..n]) {
+                break Err(e);
+            }
+            copied += n as u64;
+        };
+        self.scratch = scratch;
+        result
+    }
+
+    /// Returns the number of bytes waiting in the write buffer, 0 if not in write mode.
+    pub fn pending_write_len(&self) -> usize {
+        self.get_bufwriter_ref().map_or(0, |w| w.buffer().len())
+    }
+
+    /// Returns a staging buffer for the caller to fill with data to write, sized to the spare capacity of the write
+    /// buffer, or to its full capacity if not in write mode, to be followed by `advance_written(n)` to write the first `n`
+    /// bytes.  `BufWriter` does not expose its buffer, so this is not zero-copy: the staging buffer is kept by this
+    /// instance and `advance_written` copies from it into the write buffer.  It is reused from call to call, only growing
+    /// when needed, so no allocation or zero-filling is made once it is large enough.
+    ///
+    /// The contract is as follows.  The returned bytes are initialized but unspecified, and may hold data given to
+    /// earlier calls, so the caller must write every byte it passes to `advance_written` rather than rely on any
+    /// contents.  Nothing is written until `advance_written` is called, at most once per call to this, and calling this
+    /// again discards anything staged before.  Writes made in between are not affected and come first.
+    pub fn write_buffer_spare(&mut self) -> &mut [u8] {
+        let spare = match self.get_bufwriter_ref() {
+            Some(w) => w.capacity() - w.buffer().len(),
+            None => self.write_capacity.unwrap_or(DEFAULT_CAPACITY),
+        };
+        if self.spare.len() < spare {
+            self.spare.resize(spare, 0);
+        }
+        self.spare_len = spare;
+        &mut self.spare[..spare]
+    }
+
+    /// Writes the first `n` bytes of the staging buffer returned by `write_buffer_spare`, switching to write mode if
+    /// needed.  Returns an error of kind `InvalidInput` if `n` exceeds its length, or if it has already been written.
+    pub fn advance_written(&mut self, n: usize) -> io::Result<()> {
+        if n > self.spare_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "advanced beyond the spare write buffer",
+            ));
+        }
+        self.spare_len = 0;
+        let spare = std::mem::take(&mut self.spare);
+        let result = self.write_all(&spare[..n]);
+        self.spare = spare;
+        result
+    }
+
+    /// Returns true if in write mode with data waiting in the write buffer, i.e. if `flush` has something to write.
+    pub fn needs_flush(&self) -> bool {
+        self.get_bufwriter_ref()
+            .map_or(false, |w| !w.buffer().is_empty())
+    }
+
+    /// Returns true if writing `incoming_len` bytes would write to the underlying writer, either by flushing the write
+    /// buffer or by bypassing it, otherwise false.  Always false in read mode.
+    pub fn next_write_will_flush(&self, incoming_len: usize) -> bool {
+        self.get_bufwriter_ref()
+            .map_or(self.mode() == Mode::Writer, |w| {
+                w.buffer().len() + incoming_len > w.capacity() || incoming_len >= w.capacity()
+            })
+    }
+
+    /// Writes up to `max_bytes` of the oldest data in the write buffer directly to the underlying writer, keeping the rest
+    /// buffered, and returns the number of bytes written.  If a write fails, the unwritten data is kept in the write
+    /// buffer.  Does nothing in read mode.
+    pub fn flush_partial(&mut self, max_bytes: usize) -> io::Result<usize> {
+        match self.inner.take().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => {
+                let capacity = w.capacity();
+                let (mut rw, buf) = w.into_parts();
+                let buf = buf.unwrap_or_else(|e| e.into_inner());
+                let (written, result) = write_raw(&mut rw, &buf[..max_bytes.min(buf.len())]);
+                let mut w = BufWriter::with_capacity(capacity, rw);
+                let rebuffered = buffer_pending(&mut w, &buf[written..]);
+                self.inner = Some(BufIO::Writer(w));
+                result.and(rebuffered).map(|_| written)
+            }
+            inner => {
+                self.inner = Some(inner);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Writes each chunk in turn through the write buffer, switching to write mode once.  Returns the total number of bytes
+    /// written.
+    pub fn write_chunks<'a>(
+        &mut self,
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> io::Result<usize> {
+        self.switch_to_writer()?;
+        let mut written = 0;
+        for chunk in chunks {
+            self.write_all(chunk)?;
+            written += chunk.len();
+        }
+        Ok(written)
+    }
+
+    /// Switches to write mode, writes `fill` to fill the write buffer exactly to its capacity, flushes it, then writes
+    /// `rest`.  Returns an `InvalidInput` error, writing nothing, if `fill` is not the length of the remaining space in the
+    /// write buffer.  This makes the flush boundary deterministic, for testing.
+    pub fn write_fill_then(&mut self, fill: &[u8], rest: &[u8]) -> io::Result<()> {
+        self.switch_to_writer()?;
+        let remaining = self
+            .get_bufwriter_ref()
+            .map_or(0, |w| w.capacity() - w.buffer().len());
+        if fill.len() != remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "fill is {} bytes but the write buffer has {} bytes remaining",
+                    fill.len(),
+                    remaining
+                ),
+            ));
+        }
+        self.write_all(fill)?;
+        self.flush()?;
+        self.write_all(rest)
+    }
+
+    /// Switches to write mode and buffers all of `data` without writing to the underlying writer, so that it goes out
+    /// together with the data written after it, as for a header.  Where `write` would flush, returns an error of kind
+    /// `InvalidInput` instead, leaving the write buffer unchanged: if `data` does not fit in the spare capacity of the
+    /// write buffer, or is at least its capacity, which `BufWriter` writes directly.  `ensure_write_capacity` can make
+    /// room first.
+    pub fn prefill_write(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.switch_to_writer()?;
+        let (capacity, pending) = self
+            .get_bufwriter_ref()
+            .map_or((0, 0), |w| (w.capacity(), w.buffer().len()));
+        if data.len() >= capacity || data.len() > capacity - pending {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "prefill data does not fit in the write buffer",
+            ));
+        }
+        self.write_once(data)?;
+        Ok(())
+    }
+
+    /// Switches to write mode, writes all of `buf` and flushes, so the data reaches the underlying writer.
+    pub fn write_all_flushed(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf)?;
+        self.flush()
+    }
+
+    /// Writes `buf` as `write` does, but returns an error of kind `InvalidInput` instead of switching to write mode if in
+    /// read mode, for callers that consider a switch at that point a bug.
+    pub fn write_strict(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.inner.as_ref().ok_or_else(poisoned)?.mode() == Mode::Reader {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write_strict called in read mode",
+            ));
+        }
+        self.write(buf)
+    }
+
+    /// Flushes any buffered writes if the last flush, or construction if there has been none, was more than `max_age`
+    /// ago, for bounding how long written data waits in the buffer.  Returns true if it flushed, or false if the last
+    /// flush is recent enough or there is nothing to flush.
+    pub fn flush_if_stale(&mut self, max_age: Duration) -> io::Result<bool> {
+        if !self.needs_flush() || self.last_flush.elapsed() <= max_age {
+            return Ok(false);
+        }
+        self.flush()?;
+        Ok(true)
+    }
+
+    /// Flushes any buffered writes, returning the wall clock time the flush took, for spotting slow storage.
+    pub fn flush_timed(&mut self) -> io::Result<Duration> {
+        let start = Instant::now();
+        self.flush()?;
+        Ok(start.elapsed())
+    }
+
+    /// Flushes any buffered writes and truncates the underlying IO instance at the current position, discarding everything
+    /// after it.  Any buffered read data is discarded, and the position is unchanged.
+    pub fn truncate_here(&mut self) -> io::Result<()>
+    where
+        RW: Truncate,
+    {
+        self.flush()?;
+        let pos = self.stream_position()?;
+        self.get_ref().set_len(pos)?;
+        self.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+
+    /// Flushes any buffered writes and returns the current position as a fence, marking a commit point that later writes
+    /// can be rolled back to by seeking to it and calling `truncate_here`.
+    pub fn barrier(&mut self) -> io::Result<u64> {
+        self.flush()?;
+        self.stream_position()
+    }
+
+    /// Flushes any buffered writes, then syncs the underlying IO instance to durable storage.
+    pub fn flush_durable(&mut self) -> io::Result<()>
+    where
+        RW: Syncable,
+    {
+        self.flush()?;
+        self.get_ref().sync_all()
+    }
+
+    /// Flushes the write buffer by writing all of its data to the underlying writer in one `write_all`-style pass, then
+    /// flushing the underlying writer, returning the number of bytes written.  If a write fails, the unwritten data is
+    /// kept in the write buffer.  Does nothing in read mode.
+    pub fn flush_coalesced(&mut self) -> io::Result<usize> {
+        match self.inner.take().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => {
+                let capacity = w.capacity();
+                let (mut rw, buf) = w.into_parts();
+                let buf = buf.unwrap_or_else(|e| e.into_inner());
+                let (written, result) = write_raw(&mut rw, &buf);
+                let mut w = BufWriter::with_capacity(capacity, rw);
+                match result {
+                    Ok(()) => {
+                        let result = w.get_mut().flush();
+                        self.inner = Some(BufIO::Writer(w));
+                        result?;
+                        if let Some(o) = self.observer.as_mut() {
+                            o.on_flush();
+                        }
+                        Ok(written)
+                    }
+                    Err(e) => {
+                        buffer_pending(&mut w, &buf[written..])?;
+                        self.inner = Some(BufIO::Writer(w));
+                        Err(e)
+                    }
+                }
+            }
+            inner => {
+                self.inner = Some(inner);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Begins a write transaction, marking the current end of the write buffer.  Data written through `write` until the
+    /// transaction ends can be discarded with `rollback`, provided it has not yet been flushed to the underlying writer.
+    /// Rollback is limited to un-flushed data, so the transaction should fit in the write buffer.
+    pub fn begin_write_txn(&mut self) {
+        let mark = self.get_bufwriter_ref().map_or(0, |w| w.buffer().len());
+        self.txn = Some((mark, 0));
+    }
+
+    /// Ends the write transaction, if any, and flushes the write buffer.
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.txn = None;
+        self.flush()
+    }
+
+    /// Ends the write transaction, discarding the data written since `begin_write_txn` from the write buffer and keeping
+    /// the data buffered before it.  Returns an error, leaving the buffer unchanged, if any of the transaction's data has
+    /// already been flushed to the underlying writer, or if no transaction is in progress.
+    pub fn rollback(&mut self) -> io::Result<()> {
+        let (mark, written) = self.txn.take().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "no write transaction in progress")
+        })?;
+        if written == 0 {
+            return Ok(());
+        }
+        self.write_pos = None;
+        match self.inner.take().ok_or_else(poisoned)? {
+            BufIO::Writer(w) if w.buffer().len() == mark + written => {
+                let capacity = w.capacity();
+                let (rw, buf) = w.into_parts();
+                let buf = buf.unwrap_or_else(|e| e.into_inner());
+                let mut w = BufWriter::with_capacity(capacity, rw);
+                let result = buffer_pending(&mut w, &buf[..mark]);
+                self.inner = Some(BufIO::Writer(w));
+                result
+            }
+            inner => {
+                self.inner = Some(inner);
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "write transaction data has already been flushed",
+                ))
+            }
+        }
+    }
+
+    /// Grows the write buffer capacity to at least `needed` bytes, so that data up to that size can be buffered without a
+    /// flush.  In write mode the write buffer is flushed and rebuilt with the new capacity, otherwise the new capacity
+    /// applies from the next switch to write mode.  Does nothing if the capacity is already large enough.
+    pub fn ensure_write_capacity(&mut self, needed: usize) -> io::Result<()> {
+        let current = match self.get_bufwriter_ref() {
+            Some(w) => w.capacity(),
+            None => self.write_capacity.unwrap_or(DEFAULT_CAPACITY),
+        };
+        if needed > current {
+            if let Some(BufIO::Writer(w)) = self.inner.as_mut() {
+                w.flush()?;
+            }
+            self.write_capacity = Some(needed);
+            self.inner = match self.inner.take() {
+                Some(BufIO::Writer(w)) => {
+                    Some(BufIO::new_writer(w.into_parts().0, self.write_capacity))
+                }
+                Some(BufIO::Raw(rw, Mode::Writer)) => {
+                    Some(BufIO::new_writer(rw, self.write_capacity))
+                }
+                inner => inner,
+            };
+        }
+        Ok(())
+    }
+
+    /// Sets the alignment of writes to the underlying writer.  When set, a write that does not fit in the write buffer
+    /// writes only the largest multiple of `align` bytes of the buffered data and the new data, keeping the unaligned tail
+    /// buffered until more data arrives.  An explicit flush, or a switch to read mode, writes the tail as is, without
+    /// padding.  The write buffer capacity must be at least `align`, otherwise a write that does not fit in it returns an
+    /// error of kind `InvalidInput`.
+    pub fn set_write_alignment(&mut self, align: Option<usize>) {
+        self.write_alignment = align;
+    }
+
+    /// Sets a callback invoked when a write flushes the write buffer because the data does not fit, with the number of
+    /// bytes flushed.  Explicit flushes do not invoke it, and neither do writes that bypass an empty write buffer because
+    /// they are at least its capacity; `bytes_written` and `IoObserver::on_write` include those.
+    pub fn set_on_auto_flush(&mut self, f: impl FnMut(usize) + Send + 'static) {
+        self.on_auto_flush = Some(Box::new(f));
+    }
+
+    /// Sets whether reads and writes are retried when the underlying IO instance returns an `Interrupted` error, rather
+    /// than returning the error.  Off by default.
+    pub fn set_retry_on_interrupt(&mut self, retry: bool) {
+        self.retry_on_interrupt = retry;
+    }
+
+    /// Returns true if both instances are in the same mode with the same data waiting in the write buffer, for comparing
+    /// two instances in tests.
+    pub fn buffers_equal(&self, other: &Self) -> bool {
+        self.mode() == other.mode()
+            && self.get_bufwriter_ref().map(|w| w.buffer())
+                == other.get_bufwriter_ref().map(|w| w.buffer())
+    }
+
+    /// Returns a reader of successive chunks of the remaining data, each read into `scratch` and filling it except at the
+    /// end, without allocating.  Each chunk borrows `scratch` until the next is read, so `ChunksInto` is not an `Iterator`
+    /// and is driven with `while let Some(chunk) = chunks.next_chunk()`.  Both `self` and `scratch` stay borrowed for as
+    /// long as the `ChunksInto` is in use.
+    pub fn chunks_into<'b>(&'b mut self, scratch: &'b mut [u8]) -> ChunksInto<'b, RW> {
+        ChunksInto { brw: self, scratch }
+    }
+
+    /// Returns an iterator over the remaining data as records of `record_len` bytes each, as for record-oriented binary
+    /// files.  A trailing partial record yields an error of kind `UnexpectedEof`, unless allowed with
+    /// `Records::allow_partial`.  Iteration ends after the first error, and yields nothing if `record_len` is 0.
+    pub fn records(&mut self, record_len: usize) -> Records<'_, RW> {
+        Records {
+            brw: self,
+            record_len,
+            allow_partial: false,
+            done: false,
+        }
+    }
+
+    /// Returns a mutable reference to the underlying reader/writer together with a snapshot of the buffering state, taken
+    /// before the reference is handed out, so both are available from a single borrow.
+    pub fn inner_and_buffers(&mut self) -> (&mut RW, BufReaderWriterState) {
+        let state = self.state();
+        self.write_pos = None;
+        (self.inner.as_mut().expect(POISONED).get_mut(), state)
+    }
+
+    /// Returns owned copies of the buffered write data, for restoring into a new instance with `import_state`, such as
+    /// after a restart with a new underlying IO instance.  An incomplete group held back by the byte swap is included at
+    /// the end of the pending write data, as is.
+    pub fn export_state(&self) -> ExportedBuffers {
+        let mut pending_write = self
+            .get_bufwriter_ref()
+            .map_or_else(Vec::new, |w| w.buffer().to_vec());
+        if let Some(swap) = self.byteswap.as_ref() {
+            pending_write.extend_from_slice(swap.write_partial());
+        }
+        ExportedBuffers {
+            mode: self.mode(),
+            pending_write,
+            saved_read: Vec::new(),
+        }
+    }
+
+    /// Restores buffered write data exported with `export_state`, switching to the exported mode.  The write data is added
+    /// to the write buffer at the current position, or written if it does not fit.  Exported read data is ignored, as it
+    /// is always empty for `BufReaderWriterRand`.
+    pub fn import_state(&mut self, state: ExportedBuffers) -> io::Result<()> {
+        match state.mode {
+            Mode::Reader => self.switch_to_reader()?,
+            Mode::Writer => self.switch_to_writer()?,
+            Mode::Unset => {}
+        }
+        self.write_pos = None;
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => buffer_pending(w, &state.pending_write),
+            BufIO::Raw(rw, Mode::Writer) => rw.write_all(&state.pending_write),
+            _ if state.pending_write.is_empty() => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pending write data requires write mode",
+            )),
+        }
+    }
+
+    /// Returns the configuration of this instance, with the current mode as the starting mode.
+    pub fn config(&self) -> BufReaderWriterConfig {
+        BufReaderWriterConfig {
+            mode: self.mode(),
+            read_capacity: self.read_capacity,
+            write_capacity: self.write_capacity,
+            retry_on_interrupt: self.retry_on_interrupt,
+            write_alignment: self.write_alignment,
+            max_saved_buffer: None,
+            max_write_chunk: None,
+            read_ahead: true,
+            wouldblock_as_eof: false,
+            seek_bound: None,
+        }
+    }
+
+    /// Returns a snapshot of the buffering state.
+    pub fn state(&self) -> BufReaderWriterState {
+        BufReaderWriterState {
+            mode: self.mode(),
+            capacity: self.capacity(),
+            pending_write: self.pending_write_len(),
+            buffered_read: self.get_bufreader_ref().map_or(0, |r| r.buffer().len()),
+            saved_buffer: 0,
+        }
+    }
+
+    /// Returns the total number of bytes accepted by writes, whether buffered or written straight to the underlying writer
+    /// because they were too large for the write buffer.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Returns the high water mark, the furthest position written to through this `BufReaderWriterRand`, including data
+    /// still in the write buffer.  Seeking does not lower it.  Positions are those of the underlying IO instance, except
+    /// that an instance created with `new_reader`, `reader_with_capacity` or `reader_with_capacities` takes itself to
+    /// start at position 0, so that a write after only reads from there needn't query the position.
+    pub fn high_water_mark(&self) -> u64 {
+        self.high_water_mark
+    }
+
+    /// Returns the kind of the current run of reads or writes, and the number of operations in it, counting each `read` or
+    /// `write` call since the last call of the other kind.  Before any operation the count is 0, with the initial mode.
+    pub fn current_run(&self) -> (Mode, u64) {
+        self.run
+    }
+
+    /// Returns the number of `read` and `write` calls made so far.
+    pub fn total_ops(&self) -> u64 {
+        self.ops.0
+    }
+
+    /// Returns the number of times a `read` or `write` call followed a call of the other kind, or, for the first call,
+    /// did not match the initial mode.
+    pub fn switch_count(&self) -> u64 {
+        self.ops.1
+    }
+
+    /// Returns the ratio of `switch_count` to `total_ops`, or 0.0 before any operation.  A ratio close to 1.0 means reads
+    /// and writes alternate on almost every call, so little use is made of the buffers.
+    pub fn thrash_ratio(&self) -> f64 {
+        if self.ops.0 == 0 {
+            0.0
+        } else {
+            self.ops.1 as f64 / self.ops.0 as f64
+        }
+    }
+
     /// Returns the buffer capacity of the underlying reader or writer.
     pub fn capacity(&self) -> usize {
         self.inner.as_ref().map_or(0, |b| b.capacity())
     }
+
+    /// Returns the capacity of the `BufReader` or `BufWriter` created by the most recent switch between modes, including
+    /// the first operation of an instance created with `new_lazy`, or `None` if there has been no switch.
+    pub fn last_transition_capacity(&self) -> Option<usize> {
+        self.last_transition_capacity
+    }
+
+    /// Returns a suggested size for the chunks passed to reads and writes, the capacity of the current buffer, or before the
+    /// first operation on an instance created with `new_lazy`, the capacity the first buffer will have.
+    pub fn suggested_io_size(&self) -> usize {
+        match self.inner.as_ref() {
+            Some(BufIO::Raw(_, Mode::Unset)) => self
+                .read_capacity
+                .max(self.write_capacity)
+                .unwrap_or(DEFAULT_CAPACITY),
+            inner => inner.map_or(0, |b| b.capacity()),
+        }
+    }
+
+    /// Returns `suggested_io_size` rounded up to a multiple of the block size of the underlying IO instance, if it reports
+    /// one.
+    pub fn suggested_block_io_size(&self) -> usize
+    where
+        RW: BlockSize,
+    {
+        let size = self.suggested_io_size();
+        match self.get_ref().block_size() {
+            Some(block) if block > 0 => ((size + block - 1) / block).max(1) * block,
+            _ => size,
+        }
+    }
+
+    /// Swaps the read and write buffer capacities and rebuilds the current buffer with its new capacity.  In write mode
+    /// the write buffer is flushed first, in read mode any buffered read data is discarded by seeking the underlying IO
+    /// instance back to the current position.
+    pub fn swap_capacities(&mut self) -> io::Result<()> {
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => w.flush()?,
+            BufIO::Reader(r) => {
+                let remainder = r.buffer().len() as i64;
+                r.get_mut().seek(SeekFrom::Current(-remainder))?;
+            }
+            BufIO::Raw(..) => {}
+        }
+        std::mem::swap(&mut self.read_capacity, &mut self.write_capacity);
+        self.inner = Some(match self.inner.take().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => BufIO::new_writer(w.into_parts().0, self.write_capacity),
+            BufIO::Reader(r) => BufIO::new_reader(r.into_inner(), self.read_capacity),
+            BufIO::Raw(rw, Mode::Writer) => BufIO::new_writer(rw, self.write_capacity),
+            BufIO::Raw(rw, Mode::Reader) => BufIO::new_reader(rw, self.read_capacity),
+            raw => raw,
+        });
+        Ok(())
+    }
+}
+
+impl BufReaderWriterConfig {
+    /// Returns a new `BufReaderWriterRand` over `rw` with this configuration.
+    pub fn build_rand<RW: Read + Write + Seek>(&self, rw: RW) -> BufReaderWriterRand<RW> {
+        let inner = match self.mode {
+            Mode::Reader => BufIO::new_reader(rw, self.read_capacity),
+            Mode::Writer => BufIO::new_writer(rw, self.write_capacity),
+            Mode::Unset => BufIO::Raw(rw, Mode::Unset),
+        };
+        let mut brw =
+            BufReaderWriterRand::from_parts(inner, self.read_capacity, self.write_capacity);
+        brw.retry_on_interrupt = self.retry_on_interrupt;
+        brw.write_alignment = self.write_alignment;
+        brw
+    }
+}
+
+impl BufReaderWriterRand<EmptySink> {
+    /// Returns a new BufReaderWriterRand instance over an `EmptySink`, expecting a write as the first operation.
+    pub fn empty() -> BufReaderWriterRand<EmptySink> {
+        BufReaderWriterRand::new_writer(EmptySink)
+    }
+}
+
+impl BufReaderWriterRand<File> {
+    /// Flushes any buffered writes and returns the current length of the file, as reported by its metadata.  Data
+    /// appended to the file by others since a read reached the end is returned by subsequent reads, as the `BufReader` is
+    /// refilled from the file once its buffer is exhausted.
+    pub fn refresh_len(&mut self) -> io::Result<u64> {
+        self.flush()?;
+        Ok(self.get_ref().metadata()?.len())
+    }
+
+    /// Seeks to the end of the file, writes all of `data`, flushes, and syncs the file to durable storage, for appending a
+    /// record to a log.  When `Ok` is returned, the record and everything written before it is durable.
+    pub fn append_durable(&mut self, data: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::End(0))?;
+        self.write_all(data)?;
+        self.flush_durable()
+    }
+
+    /// Returns true if the file has been truncated, by another handle or process, below the position of the underlying
+    /// file, which would leave buffered read data stale.  In read mode the buffered read data is then discarded and the
+    /// position moved to the new end of the file.  Buffered writes are kept.
+    pub fn check_truncation(&mut self) -> io::Result<bool> {
+        let len = self.get_ref().metadata()?.len();
+        if len >= self.underlying_position()? {
+            return Ok(false);
+        }
+        if self.is_reader() {
+            self.seek(SeekFrom::Start(len))?;
+        }
+        Ok(true)
+    }
+}
+
+/// Reads successive chunks of a `BufReaderWriterRand` into a caller-provided scratch buffer, returned by `chunks_into`.
+pub struct ChunksInto<'b, RW: Read + Write + Seek> {
+    brw: &'b mut BufReaderWriterRand<RW>,
+    scratch: &'b mut [u8],
+}
+
+impl<'b, RW: Read + Write + Seek> ChunksInto<'b, RW> {
+    /// Reads the next chunk into the scratch buffer, filling it unless the end of the data is reached first, and returns
+    /// the filled part.  Returns `None` at the end of the data.
+    pub fn next_chunk(&mut self) -> Option<io::Result<&[u8]>> {
+        let mut filled = 0;
+        while filled < self.scratch.len() {
+            match self.brw.read(&mut self.scratch[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        if filled == 0 {
+            None
+        } else {
+            Some(Ok(&self.scratch[..filled]))
+        }
+    }
+}
+
+/// Iterates over fixed-size records of a `BufReaderWriterRand`, returned by `records`.  Reading switches to read mode.
+pub struct Records<'a, RW: Read + Write + Seek> {
+    brw: &'a mut BufReaderWriterRand<RW>,
+    record_len: usize,
+    allow_partial: bool,
+    done: bool,
+}
+
+impl<'a, RW: Read + Write + Seek> Records<'a, RW> {
+    /// Sets whether a trailing partial record is yielded as a shorter record rather than as an error.
+    pub fn allow_partial(mut self, allow: bool) -> Self {
+        self.allow_partial = allow;
+        self
+    }
+}
+
+impl<'a, RW: Read + Write + Seek> Iterator for Records<'a, RW> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done || self.record_len == 0 {
+            return None;
+        }
+        let mut record = vec![0; self.record_len];
+        let mut filled = 0;
+        while filled < record.len() {
+            match self.brw.read(&mut record[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        if filled == record.len() {
+            return Some(Ok(record));
+        }
+        self.done = true;
+        if filled == 0 {
+            None
+        } else if self.allow_partial {
+            record.truncate(filled);
+            Some(Ok(record))
+        } else {
+            Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "trailing partial record",
+            )))
+        }
+    }
+}
+
+impl<RW: Read + Write + Seek + fmt::Debug> fmt::Debug for BufReaderWriterRand<RW> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufReaderWriterRand")
+            .field("name", &self.name)
+            .field("inner_type", &self.inner_type_name())
+            .field("inner", &self.inner.as_ref().map(|b| b.get_ref()))
+            .field("state", &self.inner.as_ref().map(|_| self.state()))
+            .finish_non_exhaustive()
+    }
 }
 
 impl<RW: Read + Write + Seek> Read for BufReaderWriterRand<RW> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.inner.as_mut().unwrap() {
-            BufIO::Reader(r) => r.read(buf),
-            BufIO::Writer(w) => {
-                w.flush()?;
-                let rw = self.inner.take().unwrap().into_inner()?;
-                self.inner = match self.capacity {
-                    Some(c) => Some(BufIO::Reader(BufReader::with_capacity(c, rw))),
-                    None => Some(BufIO::Reader(BufReader::new(rw))),
-                };
-                self.read(buf)
+        if let Some(mut swap) = self.byteswap.take() {
+            let partial = swap.take_write_partial();
+            let result = self
+                .write_all(&partial)
+                .and_then(|_| swap.read(buf, |b| self.read(b)));
+            self.byteswap = Some(swap);
+            return result;
+        }
+        loop {
+            match self.read_once(buf) {
+                Err(e) if self.retry_on_interrupt && e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Reads into `bufs` through the `BufReader`, which reads directly into them from the underlying reader when its
+    /// buffer is empty and they are at least its capacity in total.
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        loop {
+            match self.read_vectored_once(bufs) {
+                Err(e) if self.retry_on_interrupt && e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    fn is_read_vectored(&self) -> bool {
+        self.get_ref().is_read_vectored()
+    }
+
+    /// Reads through `read` while a byte swap is set, as swapping works on initialized data, otherwise reads into the
+    /// cursor through the `BufReader`.
+    #[cfg(feature = "nightly")]
+    fn read_buf(&mut self, mut cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
+        if self.byteswap.is_some() {
+            let n = self.read(cursor.ensure_init().init_mut())?;
+            cursor.advance(n);
+            return Ok(());
+        }
+        loop {
+            match self.read_buf_once(cursor.reborrow()) {
+                Err(e) if self.retry_on_interrupt && e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<RW: Read + Write + Seek> BufRead for BufReaderWriterRand<RW> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.switch_to_reader()?;
+        self.attach_fill_reader();
+        self.get_bufreader_mut().ok_or_else(poisoned)?.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(BufIO::Reader(r)) = self.inner.as_mut() {
+            r.consume(amt);
+            self.advance_read_pos(amt);
+            if let Some(o) = self.observer.as_mut() {
+                o.on_read(amt);
             }
         }
     }
@@ -183,33 +1920,106 @@ impl<RW: Read + Write + Seek> Read for BufReaderWriterRand<RW> {
 
 impl<RW: Read + Write + Seek> Write for BufReaderWriterRand<RW> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.inner.as_mut().unwrap() {
-            BufIO::Writer(w) => w.write(buf),
-            BufIO::Reader(r) => {
-                r.seek(SeekFrom::Current(0))?;
-                let rw = self.inner.take().unwrap().into_inner()?;
-                self.inner = match self.capacity {
-                    Some(c) => Some(BufIO::Writer(BufWriter::with_capacity(c, rw))),
-                    None => Some(BufIO::Writer(BufWriter::new(rw))),
-                };
-                self.write(buf)
+        if let Some(mut swap) = self.byteswap.take() {
+            let ahead = swap.read_ahead() as i64;
+            if ahead > 0 {
+                if let Err(e) = self.seek(SeekFrom::Current(-ahead)) {
+                    self.byteswap = Some(swap);
+                    return Err(e);
+                }
+                swap.clear_read();
+            }
+            let result = swap.write(buf, |b| self.write_all(b));
+            self.byteswap = Some(swap);
+            return result;
+        }
+        loop {
+            match self.write_once(buf) {
+                Err(e) if self.retry_on_interrupt && e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
             }
         }
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        self.write_swap_partial()?;
         match self.inner.as_mut() {
-            Some(BufIO::Writer(w)) => Ok(w.flush()?),
-            _ => Ok(()),
+            Some(BufIO::Writer(w)) => w.flush()?,
+            Some(BufIO::Raw(rw, Mode::Writer)) => rw.flush()?,
+            _ => return Ok(()),
         }
+        self.last_flush = Instant::now();
+        if let Some(o) = self.observer.as_mut() {
+            o.on_flush();
+        }
+        Ok(())
     }
 }
 
 impl<RW: Read + Write + Seek> Seek for BufReaderWriterRand<RW> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        match self.inner.as_mut().unwrap() {
+        self.write_pos = None;
+        self.write_swap_partial()?;
+        let ahead = self.byteswap.as_ref().map_or(0, |s| s.read_ahead()) as i64;
+        let pos = match pos {
+            SeekFrom::Current(n) => SeekFrom::Current(n - ahead),
+            pos => pos,
+        };
+        let new = match self.inner.as_mut().ok_or_else(poisoned)? {
             BufIO::Writer(w) => w.seek(pos),
             BufIO::Reader(r) => r.seek(pos),
+            BufIO::Raw(rw, _) => rw.seek(pos),
+        }?;
+        if let Some(swap) = self.byteswap.as_mut() {
+            swap.clear_read();
+        }
+        Ok(new)
+    }
+
+    /// Takes `&mut self`, as required by `Seek`, since the position is queried from the underlying IO instance.
+    fn stream_position(&mut self) -> io::Result<u64> {
+        let ahead = self.byteswap.as_ref().map_or(0, |s| s.read_ahead()) as u64;
+        let pos = match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => w.stream_position(),
+            BufIO::Reader(r) => r.stream_position(),
+            BufIO::Raw(rw, _) => rw.stream_position(),
+        }?;
+        Ok(pos - ahead)
+    }
+}
+
+/// Positioned writes, for use with the `positioned-io` crate.  Each write seeks to its position first, which flushes
+/// any buffered writes and discards any buffered read data, so positioned writes get little benefit from buffering.
+#[cfg(feature = "positioned-io")]
+impl<RW: Read + Write + Seek> positioned_io::WriteAt for BufReaderWriterRand<RW> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        self.seek(SeekFrom::Start(pos))?;
+        self.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+/// Positioned reads, for use with the `positioned-io` crate, passed straight to the underlying IO instance.  As `read_at`
+/// takes `&self`, it can neither use nor flush the buffers, so it returns an error of kind `InvalidInput` if writes are
+/// waiting to be written, rather than read data they would overwrite.
+#[cfg(feature = "positioned-io")]
+impl<RW: Read + Write + Seek + positioned_io::ReadAt> positioned_io::ReadAt
+    for BufReaderWriterRand<RW>
+{
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let partial = self
+            .byteswap
+            .as_ref()
+            .map_or(0, |s| s.write_partial().len());
+        if self.needs_flush() || partial > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "read_at requires no pending writes",
+            ));
         }
+        self.get_ref().read_at(pos, buf)
     }
 }
@@ -1,29 +1,53 @@
-use std::io::{self, BufReader, BufWriter, IntoInnerError, Read, Write};
+use crate::{
+    poisoned, write_raw, BlockSize, BufReaderWriterConfig, BufReaderWriterState, ByteSwap,
+    ExportedBuffers, IoObserver, Mode, ModeSwitchError, Syncable, POISONED,
+};
+use std::fmt;
+use std::io::{
+    self, BufRead, BufReader, BufWriter, IntoInnerError, IoSliceMut, Read, Seek, SeekFrom, Write,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 enum BufIO<RW: Read + Write> {
     Reader(BufReader<RW>),
     Writer(BufWriter<RW>),
+    Raw(RW, Mode),
 }
 
 impl<RW: Read + Write> BufIO<RW> {
+    /// Returns a new `BufWriter`, or with a capacity of 0, the unbuffered writer itself.
     fn new_writer(rw: RW, capacity: Option<usize>) -> BufIO<RW> {
-        BufIO::Writer(match capacity {
-            Some(c) => BufWriter::with_capacity(c, rw),
-            None => BufWriter::new(rw),
-        })
+        match capacity {
+            Some(0) => BufIO::Raw(rw, Mode::Writer),
+            Some(c) => BufIO::Writer(BufWriter::with_capacity(c, rw)),
+            None => BufIO::Writer(BufWriter::new(rw)),
+        }
     }
 
+    /// Returns a new `BufReader`, or with a capacity of 0, the unbuffered reader itself.
     fn new_reader(rw: RW, capacity: Option<usize>) -> BufIO<RW> {
-        BufIO::Reader(match capacity {
-            Some(c) => BufReader::with_capacity(c, rw),
-            None => BufReader::new(rw),
-        })
+        match capacity {
+            Some(0) => BufIO::Raw(rw, Mode::Reader),
+            Some(c) => BufIO::Reader(BufReader::with_capacity(c, rw)),
+            None => BufIO::Reader(BufReader::new(rw)),
+        }
     }
 
     fn get_mut(&mut self) -> &mut RW {
         match self {
             BufIO::Reader(r) => r.get_mut(),
             BufIO::Writer(w) => w.get_mut(),
+            BufIO::Raw(rw, _) => rw,
+        }
+    }
+
+    fn mode(&self) -> Mode {
+        match self {
+            BufIO::Reader(_) => Mode::Reader,
+            BufIO::Writer(_) => Mode::Writer,
+            BufIO::Raw(_, mode) => *mode,
         }
     }
 
@@ -31,6 +55,7 @@ impl<RW: Read + Write> BufIO<RW> {
         match self {
             BufIO::Reader(r) => r.get_ref(),
             BufIO::Writer(w) => w.get_ref(),
+            BufIO::Raw(rw, _) => rw,
         }
     }
 
@@ -38,162 +63,341 @@ impl<RW: Read + Write> BufIO<RW> {
         match self {
             BufIO::Reader(r) => Ok(r.into_inner()),
             BufIO::Writer(w) => Ok(w.into_inner()?),
+            BufIO::Raw(rw, _) => Ok(rw),
+        }
+    }
+
+    /// Unwraps the underlying IO instance as `into_inner` does, first adding `tail` to the end of the write buffer so that
+    /// it is flushed with the rest, or returned in the error if the flush fails.  In read mode `tail` is put in a new write
+    /// buffer, as reads and writes are independent.
+    fn into_inner_with(self, tail: &[u8]) -> Result<RW, IntoInnerError<BufWriter<RW>>> {
+        if tail.is_empty() {
+            return self.into_inner();
         }
+        let (rw, mut data, capacity) = match self {
+            BufIO::Writer(w) => {
+                let capacity = w.capacity();
+                let (rw, buf) = w.into_parts();
+                (rw, buf.unwrap_or_else(|e| e.into_inner()), capacity)
+            }
+            BufIO::Reader(r) => (r.into_inner(), Vec::new(), 0),
+            BufIO::Raw(rw, _) => (rw, Vec::new(), 0),
+        };
+        data.extend_from_slice(tail);
+        let mut w = BufWriter::with_capacity(capacity.max(data.len() + 1), rw);
+        // The data fits in the buffer, so this only copies it and cannot fail.
+        let _ = w.write_all(&data);
+        w.into_inner()
     }
 
     fn capacity(&self) -> usize {
         match self {
             BufIO::Reader(r) => r.capacity(),
             BufIO::Writer(w) => w.capacity(),
+            BufIO::Raw(..) => 0,
         }
     }
 }
 
-pub struct BufReaderWriterSeq<RW: Read + Write> {
-    inner: Option<BufIO<RW>>,
-    buffer: Option<Box<Vec<u8>>>,
-    pos: usize,
-    capacity: Option<usize>,
-}
-
-impl<RW: Read + Write> BufReaderWriterSeq<RW> {
-    /// Returns a new BufReaderWriterSeq instance, expecting a write as the first operation.
-    pub fn new_writer(rw: RW) -> BufReaderWriterSeq<RW> {
-        BufReaderWriterSeq {
-            inner: Some(BufIO::new_writer(rw, None)),
-            buffer: None,
-            pos: 0,
-            capacity: None,
+impl<RW: Read + Write + Seek> BufIO<RW> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            BufIO::Reader(r) => r.seek(pos),
+            BufIO::Writer(w) => w.seek(pos),
+            BufIO::Raw(rw, _) => rw.seek(pos),
         }
     }
 
-    /// Returns a new BufReaderWriterSeq instance, expecting a write as the first operation, with specified buffer capacity.
-    pub fn writer_with_capacity(capacity: usize, rw: RW) -> BufReaderWriterSeq<RW> {
-        BufReaderWriterSeq {
-            inner: Some(BufIO::new_writer(rw, Some(capacity))),
-            buffer: None,
-            pos: 0,
-            capacity: Some(capacity),
+    fn stream_position(&mut self) -> io::Result<u64> {
+        match self {
+            BufIO::Reader(r) => r.stream_position(),
+            BufIO::Writer(w) => w.stream_position(),
+            BufIO::Raw(rw, _) => rw.stream_position(),
         }
     }
+}
 
-    /// Returns a new BufReaderWriter instance, expecting a read as the first operation.
-    pub fn new_reader(rw: RW) -> BufReaderWriterSeq<RW> {
-        BufReaderWriterSeq {
-            inner: Some(BufIO::new_reader(rw, None)),
-            buffer: None,
-            pos: 0,
-            capacity: None,
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+const MIN_BUDGET_CAPACITY: usize = 512;
+
+/// Capacity of the temporary `BufReader` that `fill_read_buf` uses on an instance with a read capacity of 0.
+const UNBUFFERED_FILL_CAPACITY: usize = 512;
+
+/// A limit on the total buffer capacity of the `BufReaderWriterSeq` instances created with `with_budget`.  When the budget
+/// is tight new instances start with a smaller capacity, down to a minimum of 512 bytes, or unbuffered if even that is not
+/// available, and grow towards the default capacity on later mode switches as budget becomes available.  The total never
+/// exceeds the limit.  Capacity is returned to the budget when an instance is dropped.
+#[derive(Debug)]
+pub struct BufferBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl BufferBudget {
+    /// Returns a new BufferBudget allowing up to `limit` bytes of buffer capacity.
+    pub fn new(limit: usize) -> BufferBudget {
+        BufferBudget {
+            limit,
+            used: AtomicUsize::new(0),
         }
     }
 
-    /// Returns a new BufReaderWriter instance, expecting a read as the first operation, with specified buffer capacity.
-    pub fn reader_with_capacity(capacity: usize, rw: RW) -> BufReaderWriterSeq<RW> {
-        BufReaderWriterSeq {
-            inner: Some(BufIO::new_reader(rw, Some(capacity))),
-            buffer: None,
-            pos: 0,
-            capacity: Some(capacity),
-        }
+    /// Returns the total buffer capacity allowed by the budget.
+    pub fn limit(&self) -> usize {
+        self.limit
     }
 
-    /// Gets a mutable reference to the underlying reader/writer.
-    pub fn get_mut(&mut self) -> &mut RW {
-        self.inner.as_mut().unwrap().get_mut()
+    /// Returns the buffer capacity currently allocated from the budget.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
     }
 
-    /// Gets a reference to the underlying reader/writer.
-    pub fn get_ref(&self) -> &RW {
-        self.inner.as_ref().unwrap().get_ref()
+    /// Takes up to `want` bytes from the budget, or nothing if less than `min` bytes are available, and returns the amount
+    /// granted.
+    fn acquire(&self, want: usize, min: usize) -> usize {
+        let mut grant = 0;
+        let _ = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                grant = want.min(self.limit.saturating_sub(used));
+                if grant < min {
+                    grant = 0;
+                }
+                Some(used + grant)
+            });
+        grant
     }
 
-    /// Unwraps this `BufReaderWriter`, returning the underlying reader/writer.  Note: the `BufReaderWriter` should be dropped after using this.
-    pub fn into_inner(self) -> Result<RW, IntoInnerError<BufWriter<RW>>> {
-        self.inner.unwrap().into_inner()
+    fn release(&self, amt: usize) {
+        self.used.fetch_sub(amt, Ordering::AcqRel);
     }
+}
 
-    /// Returns true if the `BufReaderWriter` in read mode, otherwise false for write mode.
-    pub fn is_reader(&self) -> bool {
-        match self.inner.as_ref().unwrap() {
-            BufIO::Reader(_) => true,
-            _ => false,
-        }
+/// Buffers `data` in a `BufWriter` without writing to the underlying writer, which `BufWriter::write` would otherwise do
+/// if `data` filled the whole buffer.  `data` must fit in the remaining buffer space.
+fn buffer_pending<W: Write>(w: &mut BufWriter<W>, data: &[u8]) -> io::Result<()> {
+    if data.len() > 1 && data.len() >= w.capacity() {
+        w.write_all(&data[..1])?;
+        w.write_all(&data[1..])
+    } else {
+        w.write_all(data)
     }
+}
 
-    /// Gets a reference to the underlying buffered reader, available if in read mode.
-    pub fn get_bufreader_ref(&self) -> Option<&BufReader<RW>> {
-        match self.inner.as_ref().unwrap() {
-            BufIO::Reader(r) => Some(r),
-            _ => None,
+/// Writes all of `data` to `w`, removing it only as it is written, so that none is lost or repeated on an error.
+fn write_draining<W: Write>(w: &mut W, data: &mut Vec<u8>) -> io::Result<()> {
+    while !data.is_empty() {
+        match w.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                data.drain(..n);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
+}
 
-    /// Gets a mutable reference to the underlying buffered reader, available if in read mode.
-    pub fn get_bufreader_mut(&mut self) -> Option<&mut BufReader<RW>> {
-        match self.inner.as_mut().unwrap() {
-            BufIO::Reader(r) => Some(r),
-            _ => None,
-        }
+/// Reads from a `BufReader`, or with `read_ahead` off and an empty `BufReader` buffer, directly from the underlying reader
+/// so that no more than `buf.len()` bytes are read from it, as always when unbuffered.
+fn read_from<RW: Read + Write>(
+    inner: &mut BufIO<RW>,
+    buf: &mut [u8],
+    read_ahead: bool,
+) -> io::Result<usize> {
+    match inner {
+        BufIO::Reader(r) if read_ahead || !r.buffer().is_empty() => r.read(buf),
+        inner => inner.get_mut().read(buf),
     }
+}
 
-    /// Unwraps this `BufReaderWriter` returning the BufReader, available if in read mode.  Note: the `BufReaderWriter` should be dropped after using this.
-    pub fn into_bufreader(self) -> Option<BufReader<RW>> {
-        match self.inner.unwrap() {
-            BufIO::Reader(r) => Some(r),
-            _ => None,
-        }
+/// Reads into `cursor` as `read_from` does.
+#[cfg(feature = "nightly")]
+fn read_buf_from<RW: Read + Write>(
+    inner: &mut BufIO<RW>,
+    cursor: io::BorrowedCursor<'_>,
+    read_ahead: bool,
+) -> io::Result<()> {
+    match inner {
+        BufIO::Reader(r) if read_ahead || !r.buffer().is_empty() => r.read_buf(cursor),
+        inner => inner.get_mut().read_buf(cursor),
     }
+}
 
-    /// Gets a reference to the underlying buffered writer, available if in write mode.
-    pub fn get_bufwriter_ref(&self) -> Option<&BufWriter<RW>> {
-        match self.inner.as_ref().unwrap() {
-            BufIO::Writer(w) => Some(w),
-            _ => None,
+pub struct BufReaderWriterSeq<RW: Read + Write> {
+    inner: Option<BufIO<RW>>,
+    buffer: Option<Vec<u8>>,
+    pos: usize,
+    read_capacity: Option<usize>,
+    write_capacity: Option<usize>,
+    max_saved_buffer: Option<usize>,
+    eof: bool,
+    buffer_hit_bytes: u64,
+    inner_read_bytes: u64,
+    observer: Option<Box<dyn IoObserver + Send>>,
+    name: Option<String>,
+    spare: Vec<u8>,
+    spare_len: usize,
+    switch_hysteresis: u32,
+    ops_since_switch: u32,
+    deferred_writes: Vec<u8>,
+    last_flush: Instant,
+    last_transition_capacity: Option<usize>,
+    byteswap: Option<ByteSwap>,
+    txn: Option<(usize, usize)>,
+    retry_on_interrupt: bool,
+    scratch: Vec<u8>,
+    run: (Mode, u64),
+    wouldblock_as_eof: bool,
+    seek_bound: Option<u64>,
+    ops: (u64, u64),
+    on_auto_flush: Option<Box<dyn FnMut(usize) + Send>>,
+    max_write_chunk: Option<usize>,
+    read_ahead: bool,
+    budget: Option<(Arc<BufferBudget>, usize)>,
+}
+
+impl<RW: Read + Write> BufReaderWriterSeq<RW> {
+    /// Flushes the writer and moves the underlying IO instance into a new `BufReader`.  If the flush fails, the writer is
+    /// kept and the returned error carries a `ModeSwitchError` with the unwritten data.
+    fn switch_to_reader(&mut self) -> io::Result<()> {
+        if let Some(BufIO::Raw(rw, mode)) = self.inner.as_mut() {
+            if *mode == Mode::Writer {
+                rw.flush()?;
+            }
+            self.switch_from_raw(Mode::Reader);
+            return Ok(());
+        }
+        if let Some(BufIO::Writer(w)) = self.inner.as_mut() {
+            if let Err(e) = w.flush() {
+                return Err(ModeSwitchError::wrap(e, w.buffer()));
+            }
+            let rw = match self.inner.take().ok_or_else(poisoned)?.into_inner() {
+                Ok(rw) => rw,
+                Err(e) => {
+                    let (e, w) = e.into_parts();
+                    let err = ModeSwitchError::wrap(e, w.buffer());
+                    self.inner = Some(BufIO::Writer(w));
+                    return Err(err);
+                }
+            };
+            self.grow_budget();
+            self.inner = Some(BufIO::new_reader(rw, self.read_capacity));
+            self.last_transition_capacity = self.inner.as_ref().map(|b| b.capacity());
+            self.ops_since_switch = 0;
+            if let Some(o) = self.observer.as_mut() {
+                o.on_switch(Mode::Reader);
+            }
         }
+        Ok(())
     }
 
-    /// Gets a mutable reference to the underlying buffered writer, available if in write mode.
-    pub fn get_bufwriter_mut(&mut self) -> Option<&mut BufWriter<RW>> {
-        match self.inner.as_mut().unwrap() {
-            BufIO::Writer(w) => Some(w),
-            _ => None,
+    /// Grows the capacity used for the next buffer towards the default capacity, if below it and the budget allows.
+    fn grow_budget(&mut self) {
+        if let Some((budget, granted)) = self.budget.as_mut() {
+            if *granted < DEFAULT_CAPACITY {
+                *granted += budget.acquire(DEFAULT_CAPACITY - *granted, 0);
+                self.read_capacity = Some(*granted);
+                self.write_capacity = Some(*granted);
+            }
         }
     }
 
-    /// Unwraps this `BufReaderWriter` returning the `BufWriter`, available if in read mode.  Note: the `BufReaderWriter` should be dropped after using this.
-    pub fn into_bufwriter(self) -> Option<BufWriter<RW>> {
-        match self.inner.unwrap() {
-            BufIO::Writer(w) => Some(w),
-            _ => None,
+    /// Saves any buffered read data for subsequent reads and moves the underlying IO instance into a new `BufWriter`.
+    fn switch_to_writer(&mut self) -> io::Result<()> {
+        if let Some(BufIO::Raw(..)) = self.inner {
+            self.switch_from_raw(Mode::Writer);
+        }
+        if let Some(BufIO::Reader(r)) = self.inner.as_mut() {
+            let rb = r.buffer();
+            if let Some(max) = self.max_saved_buffer {
+                if rb.len() > max {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "buffered read data exceeds the maximum saved buffer size",
+                    ));
+                }
+            }
+            if !rb.is_empty() {
+                self.buffer = Some(rb.to_vec());
+                self.pos = 0;
+            }
+            let rw = self.inner.take().ok_or_else(poisoned)?.into_inner()?;
+            self.grow_budget();
+            self.inner = Some(BufIO::new_writer(rw, self.write_capacity));
+            self.last_transition_capacity = self.inner.as_ref().map(|b| b.capacity());
+            if let Some(o) = self.observer.as_mut() {
+                o.on_switch(Mode::Writer);
+            }
+        }
+        match self.inner.as_mut() {
+            Some(BufIO::Writer(w)) => write_draining(w, &mut self.deferred_writes),
+            Some(BufIO::Raw(rw, Mode::Writer)) => write_draining(rw, &mut self.deferred_writes),
+            _ => Ok(()),
         }
     }
 
-    /// Returns a reference to the current `BufReaderWriter` read buffer data, if any.
-    pub fn buffer(&self) -> Option<&[u8]> {
-        self.buffer.as_ref().map(|b| &b[self.pos..])
+    /// Takes the data to be written after the write buffer when `self` is unwrapped or dropped: the writes deferred by the
+    /// switch hysteresis, followed by any incomplete group held back by the byte swap, written out as is.
+    fn take_unbuffered_writes(&mut self) -> Vec<u8> {
+        let mut data = std::mem::take(&mut self.deferred_writes);
+        if let Some(swap) = self.byteswap.as_mut() {
+            data.extend_from_slice(&swap.take_write_partial());
+        }
+        data
     }
 
-    /// Returns the buffer capacity of the underlying reader or writer.
-    pub fn capacity(&self) -> usize {
-        self.inner.as_ref().map_or(0, |b| b.capacity())
+    /// Switches to read mode and returns the next available read data, from the saved buffer if any, otherwise from the
+    /// `BufReader`, filling it if empty.  An empty slice indicates the end of the underlying reader.
+    fn fill_read_buf(&mut self) -> io::Result<&[u8]> {
+        self.switch_to_reader()?;
+        if self.buffer.is_none() && !self.eof {
+            self.attach_fill_reader();
+        }
+        if let Some(b) = &self.buffer {
+            return Ok(&b[self.pos..]);
+        }
+        if self.eof {
+            return Ok(&[]);
+        }
+        match self.inner.as_mut() {
+            Some(BufIO::Reader(r)) => {
+                let data = r.fill_buf()?;
+                if data.is_empty() {
+                    self.eof = true;
+                }
+                Ok(data)
+            }
+            _ => Err(poisoned()),
+        }
     }
 
-    /// Low level function that indicates an amount of data has been consumed from the buffer and is not to be returned by the next read.  The buffer is dropped if all data has been consumed.
-    pub fn consume(&mut self, amt: usize) {
+    /// Consumes `amt` bytes of the data returned by `fill_read_buf`.
+    fn consume_read_buf(&mut self, amt: usize) {
         if let Some(b) = self.buffer.as_ref() {
             self.pos += amt;
             if self.pos >= b.len() {
-                self.buffer = None
+                self.buffer = None;
             }
+            self.buffer_hit_bytes += amt as u64;
+        } else if let Some(BufIO::Reader(r)) = self.inner.as_mut() {
+            r.consume(amt);
+            self.inner_read_bytes += amt as u64;
+        }
+        if let Some(o) = self.observer.as_mut() {
+            o.on_read(amt);
         }
     }
-}
 
-impl<RW: Read + Write> Read for BufReaderWriterSeq<RW> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.inner.as_mut().unwrap() {
-            BufIO::Reader(r) => {
-                if let Some(b) = &mut self.buffer {
+    /// Performs a single read for `Read::read`, which retries it on `Interrupted` if enabled.
+    fn read_once(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(debug_assertions)]
+        self.debug_assert_invariants();
+        self.release_fill_reader();
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            r if r.mode() == Mode::Reader => {
+                let n = if let Some(b) = &mut self.buffer {
                     let datalen = b.len() - self.pos;
                     let readlen = buf.len();
                     if datalen >= readlen {
@@ -203,58 +407,2053 @@ impl<RW: Read + Write> Read for BufReaderWriterSeq<RW> {
                         } else {
                             self.buffer = None;
                         }
-                        Ok(readlen)
+                        self.buffer_hit_bytes += readlen as u64;
+                        readlen
                     } else {
                         buf[..datalen].copy_from_slice(&b[self.pos..self.pos + datalen]);
-                        match r.read(&mut buf[datalen..]) {
-                            Ok(n) => {
-                                self.buffer = None;
-                                Ok(datalen + n)
+                        let n = match read_from(r, &mut buf[datalen..], self.read_ahead) {
+                            Err(e)
+                                if self.wouldblock_as_eof
+                                    && e.kind() == io::ErrorKind::WouldBlock =>
+                            {
+                                0
+                            }
+                            n => n?,
+                        };
+                        self.buffer = None;
+                        self.buffer_hit_bytes += datalen as u64;
+                        self.inner_read_bytes += n as u64;
+                        datalen + n
+                    }
+                } else if self.eof {
+                    0
+                } else {
+                    let n = match read_from(r, buf, self.read_ahead) {
+                        Err(e)
+                            if self.wouldblock_as_eof && e.kind() == io::ErrorKind::WouldBlock =>
+                        {
+                            0
+                        }
+                        n => {
+                            let n = n?;
+                            if n == 0 && !buf.is_empty() {
+                                self.eof = true;
+                            }
+                            n
+                        }
+                    };
+                    self.inner_read_bytes += n as u64;
+                    n
+                };
+                self.count_op(Mode::Reader);
+                if let Some(o) = self.observer.as_mut() {
+                    o.on_read(n);
+                }
+                Ok(n)
+            }
+            _ => {
+                self.switch_to_reader()?;
+                self.read_once(buf)
+            }
+        }
+    }
+
+    /// Performs a single read for `Read::read_buf`, which retries it on `Interrupted` if enabled.
+    #[cfg(feature = "nightly")]
+    fn read_buf_once(&mut self, mut cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
+        #[cfg(debug_assertions)]
+        self.debug_assert_invariants();
+        self.release_fill_reader();
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            r if r.mode() == Mode::Reader => {
+                let start = cursor.written();
+                if let Some(b) = &mut self.buffer {
+                    let datalen = b.len() - self.pos;
+                    let readlen = cursor.capacity();
+                    if datalen > readlen {
+                        cursor.append(&b[self.pos..self.pos + readlen]);
+                        self.pos += readlen;
+                        self.buffer_hit_bytes += readlen as u64;
+                    } else {
+                        cursor.append(&b[self.pos..]);
+                        self.buffer = None;
+                        self.buffer_hit_bytes += datalen as u64;
+                        if datalen < readlen {
+                            match read_buf_from(r, cursor.reborrow(), self.read_ahead) {
+                                Err(e)
+                                    if self.wouldblock_as_eof
+                                        && e.kind() == io::ErrorKind::WouldBlock => {}
+                                result => result?,
+                            }
+                            self.inner_read_bytes += (cursor.written() - start - datalen) as u64;
+                        }
+                    }
+                } else if !self.eof {
+                    match read_buf_from(r, cursor.reborrow(), self.read_ahead) {
+                        Err(e)
+                            if self.wouldblock_as_eof && e.kind() == io::ErrorKind::WouldBlock => {}
+                        result => {
+                            result?;
+                            if cursor.written() == start && cursor.capacity() > 0 {
+                                self.eof = true;
                             }
-                            Err(e) => Err(e),
                         }
                     }
+                    self.inner_read_bytes += (cursor.written() - start) as u64;
+                }
+                self.count_op(Mode::Reader);
+                if let Some(o) = self.observer.as_mut() {
+                    o.on_read(cursor.written() - start);
+                }
+                Ok(())
+            }
+            _ => {
+                self.switch_to_reader()?;
+                self.read_buf_once(cursor)
+            }
+        }
+    }
+
+    /// Performs a single read for `Read::read_vectored`, which retries it on `Interrupted` if enabled.
+    fn read_vectored_once(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        #[cfg(debug_assertions)]
+        self.debug_assert_invariants();
+        self.release_fill_reader();
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            r if r.mode() == Mode::Reader => {
+                let n = if let Some(b) = &self.buffer {
+                    let mut data = &b[self.pos..];
+                    let n = data.read_vectored(bufs)?;
+                    if n < data.len() {
+                        self.pos += n;
+                    } else {
+                        self.buffer = None;
+                    }
+                    self.buffer_hit_bytes += n as u64;
+                    n
+                } else if self.eof {
+                    0
                 } else {
-                    r.read(buf)
+                    let result = match r {
+                        BufIO::Reader(r) if self.read_ahead || !r.buffer().is_empty() => {
+                            r.read_vectored(bufs)
+                        }
+                        r => r.get_mut().read_vectored(bufs),
+                    };
+                    let n = match result {
+                        Err(e)
+                            if self.wouldblock_as_eof && e.kind() == io::ErrorKind::WouldBlock =>
+                        {
+                            0
+                        }
+                        n => {
+                            let n = n?;
+                            if n == 0 && bufs.iter().any(|b| !b.is_empty()) {
+                                self.eof = true;
+                            }
+                            n
+                        }
+                    };
+                    self.inner_read_bytes += n as u64;
+                    n
+                };
+                self.count_op(Mode::Reader);
+                if let Some(o) = self.observer.as_mut() {
+                    o.on_read(n);
                 }
+                Ok(n)
+            }
+            _ => {
+                self.switch_to_reader()?;
+                self.read_vectored_once(bufs)
+            }
+        }
+    }
+
+    /// Performs a single write for `Write::write`, which retries it on `Interrupted` if enabled.
+    fn write_once(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(debug_assertions)]
+        self.debug_assert_invariants();
+        if self
+            .inner
+            .as_ref()
+            .map_or(false, |b| b.mode() == Mode::Reader)
+            && self.defers_write(buf.len())
+        {
+            self.deferred_writes.extend_from_slice(buf);
+            self.count_op(Mode::Writer);
+            if let Some(o) = self.observer.as_mut() {
+                o.on_write(buf.len());
+            }
+            return Ok(buf.len());
+        }
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(_) if self.max_write_chunk.map_or(false, |max| buf.len() > max) => {
+                self.write_chunked(buf)
             }
             BufIO::Writer(w) => {
-                w.flush()?;
-                let rw = self.inner.take().unwrap().into_inner()?;
-                self.inner = match self.capacity {
-                    Some(c) => Some(BufIO::Reader(BufReader::with_capacity(c, rw))),
-                    None => Some(BufIO::Reader(BufReader::new(rw))),
-                };
-                self.read(buf)
+                let pending = w.buffer().len();
+                let n = w.write(buf)?;
+                if pending > 0 && w.buffer().len() != pending + n {
+                    if let Some(f) = self.on_auto_flush.as_mut() {
+                        f(pending);
+                    }
+                }
+                if let Some((_, written)) = self.txn.as_mut() {
+                    *written += n;
+                }
+                self.count_op(Mode::Writer);
+                if let Some(o) = self.observer.as_mut() {
+                    o.on_write(n);
+                }
+                Ok(n)
+            }
+            BufIO::Raw(rw, Mode::Writer) => {
+                let len = self
+                    .max_write_chunk
+                    .map_or(buf.len(), |max| buf.len().min(max.max(1)));
+                let n = rw.write(&buf[..len])?;
+                if let Some((_, written)) = self.txn.as_mut() {
+                    *written += n;
+                }
+                self.count_op(Mode::Writer);
+                if let Some(o) = self.observer.as_mut() {
+                    o.on_write(n);
+                }
+                Ok(n)
+            }
+            _ => {
+                self.switch_to_writer()?;
+                self.write_once(buf)
             }
         }
     }
-}
 
-impl<RW: Read + Write> Write for BufReaderWriterSeq<RW> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.inner.as_mut().unwrap() {
-            BufIO::Writer(w) => w.write(buf),
-            BufIO::Reader(r) => {
-                let rb = r.buffer();
-                if !rb.is_empty() {
-                    self.buffer = Some(Box::new(rb.to_vec()));
-                    self.pos = 0;
+    /// Returns true if a write of `len` bytes in read mode is to be deferred by the switch hysteresis rather than switch to
+    /// write mode.  Deferred writes are kept to less than the write buffer capacity, so that replaying them only buffers
+    /// them.
+    fn defers_write(&self, len: usize) -> bool {
+        self.ops_since_switch < self.switch_hysteresis
+            && self.txn.is_none()
+            && self.deferred_writes.len() + len < self.write_capacity.unwrap_or(DEFAULT_CAPACITY)
+    }
+
+    /// Writes `buf` in chunks of at most the maximum write chunk size, flushing after each one.  Returns the number of
+    /// bytes accepted, or the error if nothing was.  Stops early if the writer accepts only part of a chunk, or if a flush
+    /// fails, in which case the unwritten part of the chunk stays in the write buffer and is counted as accepted.
+    fn write_chunked(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let max = self.max_write_chunk.unwrap_or(usize::MAX).max(1);
+        let mut written = 0;
+        for chunk in buf.chunks(max) {
+            let (n, flushed) = match self.inner.as_mut() {
+                Some(BufIO::Writer(w)) => match w.write(chunk) {
+                    Ok(n) => (n, w.flush()),
+                    Err(e) => (0, Err(e)),
+                },
+                _ => (0, Err(poisoned())),
+            };
+            if n == 0 {
+                if written == 0 {
+                    return flushed.map(|_| 0);
+                }
+                break;
+            }
+            written += n;
+            if let Some((_, txn_written)) = self.txn.as_mut() {
+                *txn_written += n;
+            }
+            if let Some(o) = self.observer.as_mut() {
+                o.on_write(n);
+                if flushed.is_ok() {
+                    o.on_flush();
                 }
-                let rw = self.inner.take().unwrap().into_inner()?;
-                self.inner = match self.capacity {
-                    Some(c) => Some(BufIO::Writer(BufWriter::with_capacity(c, rw))),
-                    None => Some(BufIO::Writer(BufWriter::new(rw))),
-                };
-                self.write(buf)
+            }
+            if flushed.is_err() || n < chunk.len() {
+                break;
             }
         }
+        self.count_op(Mode::Writer);
+        Ok(written)
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        match self.inner.as_mut() {
-            Some(BufIO::Writer(w)) => Ok(w.flush()?),
-            _ => Ok(()),
+    /// Moves the unbuffered underlying IO instance, of a lazily constructed `BufReaderWriter` or one with a capacity of 0,
+    /// into a new `BufReader` or `BufWriter`, or with a capacity of 0 for the new mode, keeps it unbuffered in that mode.
+    /// Does nothing if already in mode `to`.
+    fn switch_from_raw(&mut self, to: Mode) {
+        match self.inner.take() {
+            Some(BufIO::Raw(rw, mode)) if mode != to => {
+                if mode != Mode::Unset {
+                    self.grow_budget();
+                }
+                self.inner = Some(match to {
+                    Mode::Writer => BufIO::new_writer(rw, self.write_capacity),
+                    _ => BufIO::new_reader(rw, self.read_capacity),
+                });
+                self.last_transition_capacity = self.inner.as_ref().map(|b| b.capacity());
+                if to == Mode::Reader {
+                    self.ops_since_switch = 0;
+                }
+                if let Some(o) = self.observer.as_mut() {
+                    o.on_switch(to);
+                }
+            }
+            inner => self.inner = inner,
+        }
+    }
+
+    /// Gives an unbuffered instance in read mode a small `BufReader`, for the methods that need buffered read data,
+    /// which `release_fill_reader` drops again once its data has been read.
+    fn attach_fill_reader(&mut self) {
+        self.inner = match self.inner.take() {
+            Some(BufIO::Raw(rw, Mode::Reader)) => Some(BufIO::Reader(BufReader::with_capacity(
+                UNBUFFERED_FILL_CAPACITY,
+                rw,
+            ))),
+            inner => inner,
+        };
+    }
+
+    /// Drops the `BufReader` given by `attach_fill_reader` to an instance with a read capacity of 0 once its buffer is
+    /// empty, so that reads go straight to the underlying reader again.
+    fn release_fill_reader(&mut self) {
+        if self.read_capacity != Some(0) {
+            return;
+        }
+        self.inner = match self.inner.take() {
+            Some(BufIO::Reader(r)) if r.buffer().is_empty() => {
+                Some(BufIO::Raw(r.into_inner(), Mode::Reader))
+            }
+            inner => inner,
+        };
+    }
+
+    /// Counts a read or write towards the current run of operations of the same kind.
+    fn count_op(&mut self, mode: Mode) {
+        self.ops.0 += 1;
+        self.ops_since_switch = self.ops_since_switch.saturating_add(1);
+        if self.run.0 == mode {
+            self.run.1 += 1;
+        } else {
+            if self.run.0 != Mode::Unset {
+                self.ops.1 += 1;
+            }
+            self.run = (mode, 1);
+        }
+    }
+
+    fn from_parts(
+        inner: BufIO<RW>,
+        read_capacity: Option<usize>,
+        write_capacity: Option<usize>,
+    ) -> BufReaderWriterSeq<RW> {
+        let mode = inner.mode();
+        BufReaderWriterSeq {
+            inner: Some(inner),
+            buffer: None,
+            pos: 0,
+            read_capacity,
+            write_capacity,
+            max_saved_buffer: None,
+            eof: false,
+            buffer_hit_bytes: 0,
+            inner_read_bytes: 0,
+            observer: None,
+            name: None,
+            spare: Vec::new(),
+            spare_len: 0,
+            switch_hysteresis: 0,
+            ops_since_switch: 0,
+            deferred_writes: Vec::new(),
+            last_flush: Instant::now(),
+            last_transition_capacity: None,
+            byteswap: None,
+            txn: None,
+            retry_on_interrupt: false,
+            scratch: Vec::new(),
+            run: (mode, 0),
+            wouldblock_as_eof: false,
+            seek_bound: None,
+            ops: (0, 0),
+            on_auto_flush: None,
+            max_write_chunk: None,
+            read_ahead: true,
+            budget: None,
+        }
+    }
+
+    /// Returns a new BufReaderWriterSeq instance, expecting a write as the first operation.
+    pub fn new_writer(rw: RW) -> BufReaderWriterSeq<RW> {
+        BufReaderWriterSeq::from_parts(BufIO::new_writer(rw, None), None, None)
+    }
+
+    /// Returns a new BufReaderWriterSeq instance, expecting a write as the first operation, with specified buffer capacity.
+    /// A capacity of 0 disables buffering, so that every read and write goes straight to the underlying IO instance while
+    /// mode switching works as usual.
+    pub fn writer_with_capacity(capacity: usize, rw: RW) -> BufReaderWriterSeq<RW> {
+        BufReaderWriterSeq::from_parts(
+            BufIO::new_writer(rw, Some(capacity)),
+            Some(capacity),
+            Some(capacity),
+        )
+    }
+
+    /// Returns a new BufReaderWriter instance, expecting a read as the first operation.
+    pub fn new_reader(rw: RW) -> BufReaderWriterSeq<RW> {
+        BufReaderWriterSeq::from_parts(BufIO::new_reader(rw, None), None, None)
+    }
+
+    /// Returns a new BufReaderWriter instance, expecting a read as the first operation, with specified buffer capacity.
+    /// A capacity of 0 disables buffering, so that every read and write goes straight to the underlying IO instance
+    /// while mode switching works as usual.  `prefetch`, `fill_and_peek` and the methods that search the read data,
+    /// such as `read_until_into`, then read through a small temporary buffer, which is dropped once its data has been
+    /// read.
+    pub fn reader_with_capacity(capacity: usize, rw: RW) -> BufReaderWriterSeq<RW> {
+        BufReaderWriterSeq::from_parts(
+            BufIO::new_reader(rw, Some(capacity)),
+            Some(capacity),
+            Some(capacity),
+        )
+    }
+
+    /// Returns a new BufReaderWriterSeq instance, expecting a write as the first operation, with its buffer capacity taken
+    /// from a shared `BufferBudget`.
+    pub fn with_budget(budget: Arc<BufferBudget>, rw: RW) -> BufReaderWriterSeq<RW> {
+        let capacity = budget.acquire(DEFAULT_CAPACITY, MIN_BUDGET_CAPACITY);
+        let mut brw = BufReaderWriterSeq::from_parts(
+            BufIO::new_writer(rw, Some(capacity)),
+            Some(capacity),
+            Some(capacity),
+        );
+        brw.budget = Some((budget, capacity));
+        brw
+    }
+
+    /// Returns a new BufReaderWriterSeq instance, expecting a write as the first operation, with separate read and write buffer
+    /// capacities.
+    pub fn writer_with_capacities(
+        read_capacity: usize,
+        write_capacity: usize,
+        rw: RW,
+    ) -> BufReaderWriterSeq<RW> {
+        BufReaderWriterSeq::from_parts(
+            BufIO::new_writer(rw, Some(write_capacity)),
+            Some(read_capacity),
+            Some(write_capacity),
+        )
+    }
+
+    /// Returns a new BufReaderWriterSeq instance, expecting a read as the first operation, with separate read and write buffer
+    /// capacities.
+    pub fn reader_with_capacities(
+        read_capacity: usize,
+        write_capacity: usize,
+        rw: RW,
+    ) -> BufReaderWriterSeq<RW> {
+        BufReaderWriterSeq::from_parts(
+            BufIO::new_reader(rw, Some(read_capacity)),
+            Some(read_capacity),
+            Some(write_capacity),
+        )
+    }
+
+    /// Returns a new BufReaderWriterSeq instance that allocates no buffer until the first operation, which picks the
+    /// mode.  Until then `mode()` returns `Mode::Unset` and `capacity()` returns 0.
+    pub fn new_lazy(rw: RW) -> BufReaderWriterSeq<RW> {
+        BufReaderWriterSeq::from_parts(BufIO::Raw(rw, Mode::Unset), None, None)
+    }
+
+    /// Returns a new BufReaderWriterSeq instance from an existing `BufReader`, keeping its capacity.  Any data already
+    /// buffered by the `BufReader` is moved into the `BufReaderWriterSeq` read buffer and returned by subsequent reads.
+    pub fn from_bufreader(mut r: BufReader<RW>) -> BufReaderWriterSeq<RW> {
+        let capacity = r.capacity();
+        let rb = r.buffer();
+        let buffer = if rb.is_empty() {
+            None
+        } else {
+            Some(rb.to_vec())
+        };
+        let len = rb.len();
+        r.consume(len);
+        let mut brw =
+            BufReaderWriterSeq::from_parts(BufIO::Reader(r), Some(capacity), Some(capacity));
+        brw.buffer = buffer;
+        brw
+    }
+
+    /// Returns a new BufReaderWriterSeq instance from an existing `BufWriter`, keeping its buffered data and capacity.
+    pub fn from_bufwriter(w: BufWriter<RW>) -> BufReaderWriterSeq<RW> {
+        let capacity = w.capacity();
+        BufReaderWriterSeq::from_parts(BufIO::Writer(w), Some(capacity), Some(capacity))
+    }
+
+    /// Gets a mutable reference to the underlying reader/writer.
+    pub fn get_mut(&mut self) -> &mut RW {
+        self.inner.as_mut().expect(POISONED).get_mut()
+    }
+
+    /// Replaces the underlying writer with `rw` and returns the old one, keeping any buffered writes so that they are flushed
+    /// to `rw`.  Returns an error of kind `InvalidInput` if not in write mode.
+    pub fn replace_inner(&mut self, rw: RW) -> io::Result<RW> {
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => Ok(std::mem::replace(w.get_mut(), rw)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "replace_inner requires write mode",
+            )),
+        }
+    }
+
+    /// Gets a reference to the underlying reader/writer.
+    pub fn get_ref(&self) -> &RW {
+        self.inner.as_ref().expect(POISONED).get_ref()
+    }
+
+    /// Unwraps this `BufReaderWriter`, returning the underlying reader/writer.  Note: the `BufReaderWriter` should be dropped after using this.
+    pub fn into_inner(mut self) -> Result<RW, IntoInnerError<BufWriter<RW>>> {
+        let data = self.take_unbuffered_writes();
+        self.inner.take().expect(POISONED).into_inner_with(&data)
+    }
+
+    /// Returns true if `into_inner` has nothing to flush, so cannot fail or block on writing, as on a non-blocking
+    /// socket: in read mode, or in write mode with an empty write buffer.
+    pub fn can_finalize(&self) -> bool {
+        !self.needs_flush()
+    }
+
+    /// Returns the current mode of the `BufReaderWriter`.
+    pub fn mode(&self) -> Mode {
+        self.inner.as_ref().expect(POISONED).mode()
+    }
+
+    /// Sets an observer to be notified of reads, writes, flushes, and mode switches, replacing any previous observer.
+    pub fn set_observer(&mut self, mut obs: Box<dyn IoObserver + Send>) {
+        if let Some(name) = &self.name {
+            obs.on_name(name);
+        }
+        self.observer = Some(obs);
+    }
+
+    /// Sets a name for this instance, for telling instances apart in diagnostics.  The name is included in the `Debug`
+    /// output and passed to the observer with `IoObserver::on_name`.
+    pub fn set_name(&mut self, name: impl Into<String>) {
+        let name = name.into();
+        if let Some(o) = self.observer.as_mut() {
+            o.on_name(&name);
+        }
+        self.name = Some(name);
+    }
+
+    /// Swaps byte order in groups of `width` bytes as data is read with `read` and written with `write`, for reading
+    /// and writing binary data of a foreign byte order.  A `width` of 0 or 1 turns swapping off, discarding any bytes
+    /// held back by an earlier setting.  Bytes that don't yet make up a whole group are held back: when reading, until
+    /// the rest of the group is read, with an incomplete group at the end of the stream returned as is; when writing,
+    /// until the group is completed, with `flush`, a seek, a read, `into_inner` or dropping writing out an incomplete
+    /// group as is.  Data read ahead of the caller to complete a group is kept across writes, as reading and writing
+    /// are independent, excluded from `stream_position` in read mode, and discarded by a seek.  `BufRead`,
+    /// `read_vectored` and the other read methods that bypass `read` don't swap.
+    pub fn set_byteswap(&mut self, width: usize) {
+        self.byteswap = if width > 1 {
+            Some(ByteSwap::new(width))
+        } else {
+            None
+        };
+    }
+
+    /// Writes out, as is, an incomplete group held back from writing by the byte swap.
+    fn write_swap_partial(&mut self) -> io::Result<()> {
+        match self.byteswap.take() {
+            Some(mut swap) => {
+                let partial = swap.take_write_partial();
+                let result = self.write_all(&partial);
+                self.byteswap = Some(swap);
+                result
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the name set with `set_name`, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the type name of the underlying IO instance, for diagnostics in generic code.  The name is as given by
+    /// `std::any::type_name`, so its exact form is not guaranteed.
+    pub fn inner_type_name(&self) -> &'static str {
+        std::any::type_name::<RW>()
+    }
+
+    /// Returns true if the `BufReaderWriter` in read mode, otherwise false for write mode or before the first operation of
+    /// a lazily constructed instance.
+    pub fn is_reader(&self) -> bool {
+        self.mode() == Mode::Reader
+    }
+
+    /// Returns false for a lazily constructed `BufReaderWriter` before its first read or write, otherwise true.
+    pub fn is_initialized(&self) -> bool {
+        self.mode() != Mode::Unset
+    }
+
+    /// Gets a reference to the underlying buffered reader, available if in read mode.
+    pub fn get_bufreader_ref(&self) -> Option<&BufReader<RW>> {
+        match self.inner.as_ref().expect(POISONED) {
+            BufIO::Reader(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    /// Gets a mutable reference to the underlying buffered reader, available if in read mode.
+    pub fn get_bufreader_mut(&mut self) -> Option<&mut BufReader<RW>> {
+        match self.inner.as_mut().expect(POISONED) {
+            BufIO::Reader(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    /// Switches to read mode and calls `f` with the underlying `BufReader`, returning its result.  The `BufReaderWriter`
+    /// stays in read mode afterwards, with any data the closure left buffered available to subsequent reads.  As with
+    /// `as_std_bufreader`, an error is returned without calling `f` if saved read data is pending.
+    pub fn with_reader<T>(&mut self, f: impl FnOnce(&mut BufReader<RW>) -> T) -> io::Result<T> {
+        Ok(f(self.as_std_bufreader()?))
+    }
+
+    /// Switches to read mode and returns the underlying `BufReader`, for APIs that take a `&mut BufReader`.  The
+    /// `BufReaderWriter` stays in read mode, with any data left in the `BufReader` buffer available to subsequent reads.
+    /// Read data saved across a switch to write mode cannot be placed in front of the `BufReader`, so if any is pending an
+    /// error is returned instead; drain it first with `read` or `consume`.
+    pub fn as_std_bufreader(&mut self) -> io::Result<&mut BufReader<RW>> {
+        self.switch_to_reader()?;
+        if self.buffer.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "saved read data is pending and cannot be passed to the BufReader",
+            ));
+        }
+        self.attach_fill_reader();
+        self.count_op(Mode::Reader);
+        self.eof = false;
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Reader(r) => Ok(r),
+            _ => Err(poisoned()),
+        }
+    }
+
+    /// Unwraps this `BufReaderWriter` returning the BufReader, available if in read mode.  Note: the `BufReaderWriter` should be dropped after using this.
+    pub fn into_bufreader(mut self) -> Option<BufReader<RW>> {
+        match self.inner.take().expect(POISONED) {
+            BufIO::Reader(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    /// Unwraps this `BufReaderWriter` returning the `BufReader` if in read mode, otherwise returns the `BufReaderWriter`
+    /// unchanged in the error.
+    #[allow(clippy::result_large_err)]
+    pub fn try_into_bufreader(mut self) -> Result<BufReader<RW>, Self> {
+        match self.inner.take().expect(POISONED) {
+            BufIO::Reader(r) => Ok(r),
+            inner => {
+                self.inner = Some(inner);
+                Err(self)
+            }
+        }
+    }
+
+    /// Gets a reference to the underlying buffered writer, available if in write mode.
+    pub fn get_bufwriter_ref(&self) -> Option<&BufWriter<RW>> {
+        match self.inner.as_ref().expect(POISONED) {
+            BufIO::Writer(w) => Some(w),
+            _ => None,
+        }
+    }
+
+    /// Gets a mutable reference to the underlying buffered writer, available if in write mode.
+    pub fn get_bufwriter_mut(&mut self) -> Option<&mut BufWriter<RW>> {
+        match self.inner.as_mut().expect(POISONED) {
+            BufIO::Writer(w) => Some(w),
+            _ => None,
+        }
+    }
+
+    /// Unwraps this `BufReaderWriter` returning the `BufWriter`, available if in read mode.  Note: the `BufReaderWriter` should be dropped after using this.
+    pub fn into_bufwriter(mut self) -> Option<BufWriter<RW>> {
+        match self.inner.take().expect(POISONED) {
+            BufIO::Writer(w) => Some(w),
+            _ => None,
+        }
+    }
+
+    /// Unwraps this `BufReaderWriter` returning the `BufWriter` if in write mode, otherwise returns the `BufReaderWriter`
+    /// unchanged in the error.
+    #[allow(clippy::result_large_err)]
+    pub fn try_into_bufwriter(mut self) -> Result<BufWriter<RW>, Self> {
+        match self.inner.take().expect(POISONED) {
+            BufIO::Writer(w) => Ok(w),
+            inner => {
+                self.inner = Some(inner);
+                Err(self)
+            }
+        }
+    }
+
+    /// Returns a reference to the current `BufReaderWriter` read buffer data, if any.
+    pub fn buffer(&self) -> Option<&[u8]> {
+        self.buffer.as_ref().map(|b| &b[self.pos..])
+    }
+
+    /// Reallocates the saved read buffer to fit its unconsumed data, if it holds more than twice as much memory as that
+    /// data needs, such as after most of a large saved buffer has been read.
+    pub fn shrink_saved_buffer(&mut self) {
+        if let Some(b) = self.buffer.as_mut() {
+            let remaining = b.len() - self.pos;
+            if b.capacity() > 2 * remaining {
+                *b = b[self.pos..].to_vec();
+                self.pos = 0;
+            }
+        }
+    }
+
+    /// Sets the maximum amount of unconsumed read data that may be saved when switching from reading to writing.  If more
+    /// data than this is buffered, the write returns an error and the `BufReaderWriter` stays in read mode.  By default all
+    /// buffered data is saved.
+    pub fn set_max_saved_buffer(&mut self, max: usize) {
+        self.max_saved_buffer = Some(max);
+    }
+
+    /// Removes and returns the data waiting in the write buffer, available if in write mode.  The data is never written to
+    /// the underlying writer, so this effectively un-writes it.
+    pub fn take_write_buffer(&mut self) -> Option<Vec<u8>> {
+        match self.inner.take().expect(POISONED) {
+            BufIO::Writer(w) => {
+                let capacity = w.capacity();
+                let (rw, buf) = w.into_parts();
+                self.inner = Some(BufIO::new_writer(rw, Some(capacity)));
+                Some(buf.unwrap_or_else(|e| e.into_inner()))
+            }
+            inner => {
+                self.inner = Some(inner);
+                None
+            }
+        }
+    }
+
+    /// Switches to read mode and, if the `BufReaderWriter` read buffer is empty, fills the `BufReader` buffer, without
+    /// consuming any data.  Returns the number of bytes buffered.
+    pub fn prefetch(&mut self) -> io::Result<usize> {
+        self.switch_to_reader()?;
+        self.attach_fill_reader();
+        match self.buffer() {
+            Some(b) => Ok(b.len()),
+            None => Ok(self
+                .get_bufreader_mut()
+                .ok_or_else(poisoned)?
+                .fill_buf()?
+                .len()),
+        }
+    }
+
+    /// Returns the number of bytes of read data currently buffered, including the `BufReaderWriter` read buffer.
+    pub fn buffered_read_len(&self) -> usize {
+        self.buffer().map_or(0, |b| b.len())
+            + self.get_bufreader_ref().map_or(0, |r| r.buffer().len())
+    }
+
+    /// Returns true if read data is buffered, either saved or in the `BufReader` buffer, so that the next read returns
+    /// data without reading from the underlying reader, as for scheduling reads in an event loop.
+    pub fn read_ready(&self) -> bool {
+        self.buffered_read_len() > 0
+    }
+
+    /// Returns a copy of the read data currently buffered and not yet consumed, the saved read data followed by the
+    /// `BufReader` buffer, which is the data the next reads will return before reading from the underlying reader.
+    pub fn peek_buffered(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.buffered_read_len());
+        data.extend_from_slice(self.buffer().unwrap_or(&[]));
+        if let Some(r) = self.get_bufreader_ref() {
+            data.extend_from_slice(r.buffer());
+        }
+        data
+    }
+
+    /// Copies all remaining data to `w` directly from the read buffer, starting with the saved read data, without an
+    /// intermediate buffer.  Returns the number of bytes copied.  `std::io::copy` cannot detect that this type is
+    /// buffered, so this is the faster alternative.
+    pub fn copy_to<W: Write + ?Sized>(&mut self, w: &mut W) -> io::Result<u64> {
+        let mut copied = 0;
+        loop {
+            let data = match self.fill_read_buf() {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if data.is_empty() {
+                return Ok(copied);
+            }
+            let len = data.len();
+            w.write_all(data)?;
+            self.consume_read_buf(len);
+            copied += len as u64;
+        }
+    }
+
+    /// Copies all data from `r` until its end through the write buffer, returning the number of bytes copied.  The
+    /// transfer buffer is allocated at the write buffer capacity on first use and reused by later calls.
+    pub fn copy_from<R: Read + ?Sized>(&mut self, r: &mut R) -> io::Result<u64> {
+        self.write_from(r, None)
+    }
+
+    /// Copies data from `src` through the write buffer until its end, or until `limit` bytes if given, returning the number
+    /// of bytes copied.  Uses the same reused transfer buffer as `copy_from`, and no more than `limit` bytes are read from
+    /// `src`.
+    pub fn write_from<R: Read + ?Sized>(
+        &mut self,
+        src: &mut R,
+        limit: Option<u64>,
+    ) -> io::Result<u64> {
+        self.switch_to_writer()?;
+        let mut scratch = std::mem::take(&mut self.scratch);
+        if scratch.is_empty() {
+            scratch.resize(self.capacity().max(1), 0);
+        }
+        let mut copied = 0;
+        let result = loop {
+            let len = limit.map_or(scratch.len(), |l| {
+                (l - copied).min(scratch.len() as u64) as usize
+            });
+            if len == 0 {
+                break Ok(copied);
+            }
+            let n = match src.read(&mut scratch[..len]) {
+                Ok(0) => break Ok(copied),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e),
+            };
+            if let Err(e) = self.write_all(&scratch[..n]) {
+                break Err(e);
+            }
+            copied += n as u64;
+        };
+        self.scratch = scratch;
+        result
+    }
+
+    /// Returns the data still waiting in the write buffer, if in write mode with any pending, such as the unwritten tail left
+    /// by a flush that failed part way.  The `BufReaderWriter` remains usable after such a failure, so the data can be
+    /// written later with `retry_flush`, or taken with `take_write_buffer` to be kept elsewhere.
+    pub fn recover_pending(&self) -> Option<&[u8]> {
+        self.get_bufwriter_ref()
+            .map(|w| w.buffer())
+            .filter(|b| !b.is_empty())
+    }
+
+    /// Retries writing the data still waiting in the write buffer after a failed flush, keeping whatever remains unwritten
+    /// if it fails again.
+    pub fn retry_flush(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Returns the number of bytes waiting in the write buffer, 0 if not in write mode, plus any writes deferred by the
+    /// switch hysteresis.
+    pub fn pending_write_len(&self) -> usize {
+        self.get_bufwriter_ref().map_or(0, |w| w.buffer().len()) + self.deferred_writes.len()
+    }
+
+    /// Returns a staging buffer for the caller to fill with data to write, sized to the spare capacity of the write
+    /// buffer, or to its full capacity if not in write mode, to be followed by `advance_written(n)` to write the first `n`
+    /// bytes.  `BufWriter` does not expose its buffer, so this is not zero-copy: the staging buffer is kept by this
+    /// instance and `advance_written` copies from it into the write buffer.  It is reused from call to call, only growing
+    /// when needed, so no allocation or zero-filling is made once it is large enough.
+    ///
+    /// The contract is as follows.  The returned bytes are initialized but unspecified, and may hold data given to
+    /// earlier calls, so the caller must write every byte it passes to `advance_written` rather than rely on any
+    /// contents.  Nothing is written until `advance_written` is called, at most once per call to this, and calling this
+    /// again discards anything staged before.  Writes made in between are not affected and come first.
+    pub fn write_buffer_spare(&mut self) -> &mut [u8] {
+        let spare = match self.get_bufwriter_ref() {
+            Some(w) => w.capacity() - w.buffer().len(),
+            None => self.write_capacity.unwrap_or(DEFAULT_CAPACITY),
+        };
+        if self.spare.len() < spare {
+            self.spare.resize(spare, 0);
+        }
+        self.spare_len = spare;
+        &mut self.spare[..spare]
+    }
+
+    /// Writes the first `n` bytes of the staging buffer returned by `write_buffer_spare`, switching to write mode if
+    /// needed.  Returns an error of kind `InvalidInput` if `n` exceeds its length, or if it has already been written.
+    pub fn advance_written(&mut self, n: usize) -> io::Result<()> {
+        if n > self.spare_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "advanced beyond the spare write buffer",
+            ));
+        }
+        self.spare_len = 0;
+        let spare = std::mem::take(&mut self.spare);
+        let result = self.write_all(&spare[..n]);
+        self.spare = spare;
+        result
+    }
+
+    /// Returns true if in write mode with data waiting in the write buffer, or if writes are deferred by the switch
+    /// hysteresis, i.e. if `flush` has something to write.
+    pub fn needs_flush(&self) -> bool {
+        !self.deferred_writes.is_empty()
+            || self
+                .get_bufwriter_ref()
+                .map_or(false, |w| !w.buffer().is_empty())
+    }
+
+    /// Returns true if writing `incoming_len` bytes would write to the underlying writer, either by flushing the write
+    /// buffer or by bypassing it, otherwise false.  Always false in read mode.
+    pub fn next_write_will_flush(&self, incoming_len: usize) -> bool {
+        self.get_bufwriter_ref()
+            .map_or(self.mode() == Mode::Writer, |w| {
+                w.buffer().len() + incoming_len > w.capacity() || incoming_len >= w.capacity()
+            })
+    }
+
+    /// Reads into each of `bufs` in turn, filling a buffer completely before moving to the next, and returns the total
+    /// number of bytes read.  The saved read data is read first, as with `read`.  Stops early only at end of stream, so
+    /// a short total means every byte up to the end was read.
+    pub fn read_sequential_into(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let mut filled = 0;
+            while filled < buf.len() {
+                match self.read(&mut buf[filled..]) {
+                    Ok(0) => return Ok(total + filled),
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            total += filled;
+        }
+        Ok(total)
+    }
+
+    /// Reads all remaining data until the end of the stream into `buf`, replacing its contents, and returns the number of
+    /// bytes read, starting with the saved read data.  `buf` grows as needed, so reusing it for a stream of the same or
+    /// smaller length needs no new allocation.
+    pub fn read_all_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        buf.clear();
+        self.read_to_end(buf)
+    }
+
+    /// Reads like `read`, returning the total number of bytes read and how many of them came from the saved read data, which
+    /// always precede any read from the underlying reader.
+    pub fn read_marking_boundary(&mut self, buf: &mut [u8]) -> io::Result<(usize, usize)> {
+        let hits = self.buffer_hit_bytes;
+        let n = self.read(buf)?;
+        Ok((n, (self.buffer_hit_bytes - hits) as usize))
+    }
+
+    /// Appends a line to `buf`, reading until a newline, which is included, or the end of the underlying reader, with
+    /// the saved read data read first.  Returns the number of bytes appended.  If more than `max_len` bytes precede the
+    /// newline, returns an error of kind `InvalidData` instead, leaving `buf` unchanged with the bytes read so far
+    /// consumed, so no more than `max_len` bytes are held for a line.  Also returns an error of kind `InvalidData` if
+    /// the line is not valid UTF-8.
+    pub fn read_line_limited(&mut self, buf: &mut String, max_len: usize) -> io::Result<usize> {
+        let mut line = Vec::new();
+        loop {
+            let (done, used) = {
+                let available = match self.fill_read_buf() {
+                    Ok(b) => b,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                let (end, done) = match available.iter().position(|&b| b == b'\n') {
+                    Some(i) => (i, true),
+                    None => (available.len(), available.is_empty()),
+                };
+                if line.len() + end > max_len {
+                    (None, end)
+                } else {
+                    let used = if done && end < available.len() {
+                        end + 1
+                    } else {
+                        end
+                    };
+                    line.extend_from_slice(&available[..used]);
+                    (Some(done), used)
+                }
+            };
+            if used > 0 {
+                self.consume_read_buf(used);
+            }
+            match done {
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "line exceeds the maximum length",
+                    ))
+                }
+                Some(true) => break,
+                Some(false) => {}
+            }
+        }
+        let line = String::from_utf8(line)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8"))?;
+        buf.push_str(&line);
+        Ok(line.len())
+    }
+
+    /// Appends bytes to `buf` until the delimiter `delim` or the end of the underlying reader is reached, reading the
+    /// saved read data first.  The delimiter is included if found.  Returns the number of bytes appended.
+    pub fn read_until_into(&mut self, delim: u8, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        loop {
+            let (done, used) = {
+                let available = match self.fill_read_buf() {
+                    Ok(b) => b,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                match available.iter().position(|&b| b == delim) {
+                    Some(i) => {
+                        buf.extend_from_slice(&available[..=i]);
+                        (true, i + 1)
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        (available.is_empty(), available.len())
+                    }
+                }
+            };
+            if used > 0 {
+                self.consume_read_buf(used);
+            }
+            if done {
+                return Ok(buf.len() - start);
+            }
+        }
+    }
+
+    /// Appends bytes to `buf` until any of the delimiters in `delims` or the end of the underlying reader is reached,
+    /// reading the saved read data first.  The delimiter is included if found.  Returns the number of bytes appended and
+    /// the delimiter found, or `None` at the end of the underlying reader.
+    pub fn read_until_any(
+        &mut self,
+        delims: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> io::Result<(usize, Option<u8>)> {
+        let start = buf.len();
+        loop {
+            let (found, done, used) = {
+                let available = match self.fill_read_buf() {
+                    Ok(b) => b,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                match available.iter().position(|b| delims.contains(b)) {
+                    Some(i) => {
+                        buf.extend_from_slice(&available[..=i]);
+                        (Some(available[i]), true, i + 1)
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        (None, available.is_empty(), available.len())
+                    }
+                }
+            };
+            if used > 0 {
+                self.consume_read_buf(used);
+            }
+            if done {
+                return Ok((buf.len() - start, found));
+            }
+        }
+    }
+
+    /// Appends a null-terminated string to `buf`, reading up to and consuming the terminating `\0`, which is not
+    /// appended, reading the saved read data first.  Returns the number of bytes appended.  If the end of the underlying
+    /// reader is reached before a terminator, returns an error of kind `UnexpectedEof`, with the bytes read appended to
+    /// `buf`.
+    pub fn read_cstr(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let n = self.read_until_into(0, buf)?;
+        if n == 0 || buf.last() != Some(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no null terminator before the end of the stream",
+            ));
+        }
+        buf.pop();
+        Ok(n - 1)
+    }
+
+    /// Returns true if a read has reached the end of the underlying reader.  Once set, reads return `Ok(0)` without reading
+    /// the underlying reader until `clear_eof` is called or a seek succeeds.  Writes do not clear it.
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Sets the furthest position that seeks may reach.  A seek to a position beyond `max` returns an error of kind
+    /// `InvalidInput`, without seeking the underlying IO instance for `SeekFrom::Start` and `SeekFrom::Current` seeks.  A
+    /// `SeekFrom::End` seek depends on the length of the stream, so it is made and then undone if it goes beyond `max`.
+    pub fn set_seek_bounds(&mut self, max: u64) {
+        self.seek_bound = Some(max);
+    }
+
+    /// Sets whether `read` returns `Ok(0)` instead of an error of kind `WouldBlock` from the underlying reader, so that a
+    /// loop draining a non-blocking socket ends once no more data is available.  Saved read data is always returned first.
+    /// Unlike the end of the stream, this does not set the end of file state, so later reads try the reader again.  Off by
+    /// default.
+    pub fn set_wouldblock_as_eof(&mut self, enabled: bool) {
+        self.wouldblock_as_eof = enabled;
+    }
+
+    /// Clears the end of file state set by a read, so that subsequent reads try the underlying reader again.
+    pub fn clear_eof(&mut self) {
+        self.eof = false;
+    }
+
+    int_io_methods! {
+        u16: read_u16_be, read_u16_le, write_u16_be, write_u16_le;
+        u32: read_u32_be, read_u32_le, write_u32_be, write_u32_le;
+        u64: read_u64_be, read_u64_le, write_u64_be, write_u64_le;
+    }
+
+    /// Reads a frame prefixed with its length as a big-endian `u32`, returning the frame payload.  Returns an error of
+    /// kind `UnexpectedEof` if the stream ends before the full frame is read.  The payload buffer grows as the data
+    /// arrives, rather than being allocated up front from the untrusted length.
+    pub fn read_frame_u32_be(&mut self) -> io::Result<Vec<u8>> {
+        let mut len = [0_u8; 4];
+        self.read_exact(&mut len)?;
+        let len = u64::from(u32::from_be_bytes(len));
+        let mut frame = Vec::new();
+        Read::take(&mut *self, len).read_to_end(&mut frame)?;
+        if (frame.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(frame)
+    }
+
+    /// Writes a frame prefixed with its length as a big-endian `u32`.  Returns an error of kind `InvalidInput` if the
+    /// frame is longer than `u32::MAX` bytes.
+    pub fn write_frame_u32_be(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame length exceeds u32::MAX",
+            ));
+        }
+        self.write_all(&(frame.len() as u32).to_be_bytes())?;
+        self.write_all(frame)
+    }
+
+    /// Returns the number of bytes returned by reads from the `BufReaderWriter` read buffer.
+    pub fn buffer_hit_bytes(&self) -> u64 {
+        self.buffer_hit_bytes
+    }
+
+    /// Returns the number of bytes returned by reads from the `BufReader`.
+    pub fn inner_read_bytes(&self) -> u64 {
+        self.inner_read_bytes
+    }
+
+    /// Returns the fraction of bytes returned by reads that came from the `BufReaderWriter` read buffer, or 0 if nothing
+    /// has been read.
+    pub fn read_hit_ratio(&self) -> f64 {
+        let total = self.buffer_hit_bytes + self.inner_read_bytes;
+        if total == 0 {
+            0.0
+        } else {
+            self.buffer_hit_bytes as f64 / total as f64
+        }
+    }
+
+    /// Writes up to `max_bytes` of the oldest data in the write buffer directly to the underlying writer, keeping the rest
+    /// buffered, and returns the number of bytes written.  If a write fails, the unwritten data is kept in the write
+    /// buffer.  Does nothing in read mode.
+    pub fn flush_partial(&mut self, max_bytes: usize) -> io::Result<usize> {
+        match self.inner.take().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => {
+                let capacity = w.capacity();
+                let (mut rw, buf) = w.into_parts();
+                let buf = buf.unwrap_or_else(|e| e.into_inner());
+                let (written, result) = write_raw(&mut rw, &buf[..max_bytes.min(buf.len())]);
+                let mut w = BufWriter::with_capacity(capacity, rw);
+                let rebuffered = buffer_pending(&mut w, &buf[written..]);
+                self.inner = Some(BufIO::Writer(w));
+                result.and(rebuffered).map(|_| written)
+            }
+            inner => {
+                self.inner = Some(inner);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Writes each chunk in turn through the write buffer, switching to write mode once.  Returns the total number of bytes
+    /// written.
+    pub fn write_chunks<'a>(
+        &mut self,
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> io::Result<usize> {
+        self.switch_to_writer()?;
+        let mut written = 0;
+        for chunk in chunks {
+            self.write_all(chunk)?;
+            written += chunk.len();
+        }
+        Ok(written)
+    }
+
+    /// Switches to write mode, writes `fill` to fill the write buffer exactly to its capacity, flushes it, then writes
+    /// `rest`.  Returns an `InvalidInput` error, writing nothing, if `fill` is not the length of the remaining space in the
+    /// write buffer.  This makes the flush boundary deterministic, for testing.
+    pub fn write_fill_then(&mut self, fill: &[u8], rest: &[u8]) -> io::Result<()> {
+        self.switch_to_writer()?;
+        let remaining = self
+            .get_bufwriter_ref()
+            .map_or(0, |w| w.capacity() - w.buffer().len());
+        if fill.len() != remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "fill is {} bytes but the write buffer has {} bytes remaining",
+                    fill.len(),
+                    remaining
+                ),
+            ));
+        }
+        self.write_all(fill)?;
+        self.flush()?;
+        self.write_all(rest)
+    }
+
+    /// Switches to write mode and buffers all of `data` without writing to the underlying writer, so that it goes out
+    /// together with the data written after it, as for a header.  Where `write` would flush, returns an error of kind
+    /// `InvalidInput` instead, leaving the write buffer unchanged: if `data` does not fit in the spare capacity of the
+    /// write buffer, or is at least its capacity, which `BufWriter` writes directly.  `ensure_write_capacity` can make
+    /// room first.  The maximum write chunk size does not apply.
+    pub fn prefill_write(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.switch_to_writer()?;
+        let (capacity, pending) = self
+            .get_bufwriter_ref()
+            .map_or((0, 0), |w| (w.capacity(), w.buffer().len()));
+        if data.len() >= capacity || data.len() > capacity - pending {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "prefill data does not fit in the write buffer",
+            ));
+        }
+        let max_write_chunk = self.max_write_chunk.take();
+        let result = self.write_once(data);
+        self.max_write_chunk = max_write_chunk;
+        result?;
+        Ok(())
+    }
+
+    /// Switches to write mode, writes all of `buf` and flushes, so the data reaches the underlying writer.  Over a
+    /// socket this only guarantees the data has left the local buffer, not that the peer has received it.
+    pub fn write_all_flushed(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf)?;
+        self.flush()
+    }
+
+    /// Writes `buf` as `write` does, but returns an error of kind `InvalidInput` instead of switching to write mode if in
+    /// read mode, for callers that consider a switch at that point a bug.
+    pub fn write_strict(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.inner.as_ref().ok_or_else(poisoned)?.mode() == Mode::Reader {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write_strict called in read mode",
+            ));
+        }
+        self.write(buf)
+    }
+
+    /// Flushes any buffered writes if the last flush, or construction if there has been none, was more than `max_age`
+    /// ago, for bounding how long written data waits in the buffer.  Returns true if it flushed, or false if the last
+    /// flush is recent enough or there is nothing to flush.
+    pub fn flush_if_stale(&mut self, max_age: Duration) -> io::Result<bool> {
+        if !self.needs_flush() || self.last_flush.elapsed() <= max_age {
+            return Ok(false);
+        }
+        self.flush()?;
+        Ok(true)
+    }
+
+    /// Flushes any buffered writes, returning the wall clock time the flush took, for spotting slow storage.
+    pub fn flush_timed(&mut self) -> io::Result<Duration> {
+        let start = Instant::now();
+        self.flush()?;
+        Ok(start.elapsed())
+    }
+
+    /// Flushes any buffered writes, then syncs the underlying IO instance to durable storage.
+    pub fn flush_durable(&mut self) -> io::Result<()>
+    where
+        RW: Syncable,
+    {
+        self.flush()?;
+        self.get_ref().sync_all()
+    }
+
+    /// Flushes the write buffer by writing all of its data to the underlying writer in one `write_all`-style pass, then
+    /// flushing the underlying writer, returning the number of bytes written.  If a write fails, the unwritten data is
+    /// kept in the write buffer.  Does nothing in read mode.
+    pub fn flush_coalesced(&mut self) -> io::Result<usize> {
+        match self.inner.take().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => {
+                let capacity = w.capacity();
+                let (mut rw, buf) = w.into_parts();
+                let buf = buf.unwrap_or_else(|e| e.into_inner());
+                let (written, result) = write_raw(&mut rw, &buf);
+                let mut w = BufWriter::with_capacity(capacity, rw);
+                match result {
+                    Ok(()) => {
+                        let result = w.get_mut().flush();
+                        self.inner = Some(BufIO::Writer(w));
+                        result?;
+                        if let Some(o) = self.observer.as_mut() {
+                            o.on_flush();
+                        }
+                        Ok(written)
+                    }
+                    Err(e) => {
+                        buffer_pending(&mut w, &buf[written..])?;
+                        self.inner = Some(BufIO::Writer(w));
+                        Err(e)
+                    }
+                }
+            }
+            inner => {
+                self.inner = Some(inner);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Seeks as described for `Seek::seek`, without checking the seek bound.
+    fn seek_unbounded(&mut self, pos: SeekFrom) -> io::Result<u64>
+    where
+        RW: Seek,
+    {
+        self.write_swap_partial()?;
+        if !self.deferred_writes.is_empty() {
+            self.switch_to_writer()?;
+        }
+        let ahead = self.byteswap.as_ref().map_or(0, |s| s.read_ahead()) as i64;
+        let pos = match pos {
+            SeekFrom::Current(n) if self.is_reader() => SeekFrom::Current(n - ahead),
+            pos => pos,
+        };
+        let new = self.seek_buffers(pos)?;
+        if let Some(swap) = self.byteswap.as_mut() {
+            swap.clear_read();
+        }
+        self.eof = false;
+        Ok(new)
+    }
+
+    /// Seeks the underlying IO instance, discarding or moving within the read buffers as described for `Seek::seek`.
+    fn seek_buffers(&mut self, pos: SeekFrom) -> io::Result<u64>
+    where
+        RW: Seek,
+    {
+        let remaining = self.buffer().map_or(0, |b| b.len()) as i64;
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => {
+                self.buffer = None;
+                w.seek(pos)
+            }
+            r if r.mode() == Mode::Reader => {
+                if let SeekFrom::Current(n) = pos {
+                    if remaining > 0
+                        && ((n < 0 && -n <= self.pos as i64) || (n >= 0 && n < remaining))
+                    {
+                        self.pos = (self.pos as i64 + n) as usize;
+                        return Ok(r.stream_position()? - (remaining - n) as u64);
+                    }
+                }
+                self.buffer = None;
+                match pos {
+                    SeekFrom::Current(n) => r.seek(SeekFrom::Current(n - remaining)),
+                    _ => r.seek(pos),
+                }
+            }
+            r => r.seek(pos),
+        }
+    }
+
+    /// Flushes any buffered writes and returns the position of the underlying IO instance, for debugging when it is
+    /// seekable.  In read mode this is ahead of the logical position by the amount of buffered and saved read data.  Takes
+    /// `&mut self` because of the flush, and because `Seek::stream_position` does.
+    pub fn inner_stream_position(&mut self) -> io::Result<u64>
+    where
+        RW: Seek,
+    {
+        self.flush()?;
+        self.get_mut().stream_position()
+    }
+
+    /// Begins a write transaction, marking the current end of the write buffer.  Data written through `write` until the
+    /// transaction ends can be discarded with `rollback`, provided it has not yet been flushed to the underlying writer.
+    /// Rollback is limited to un-flushed data, so the transaction should fit in the write buffer.
+    pub fn begin_write_txn(&mut self) {
+        let mark = self.get_bufwriter_ref().map_or(0, |w| w.buffer().len());
+        self.txn = Some((mark, 0));
+    }
+
+    /// Ends the write transaction, if any, and flushes the write buffer.
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.txn = None;
+        self.flush()
+    }
+
+    /// Ends the write transaction, discarding the data written since `begin_write_txn` from the write buffer and keeping
+    /// the data buffered before it.  Returns an error, leaving the buffer unchanged, if any of the transaction's data has
+    /// already been flushed to the underlying writer, or if no transaction is in progress.
+    pub fn rollback(&mut self) -> io::Result<()> {
+        let (mark, written) = self.txn.take().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "no write transaction in progress")
+        })?;
+        if written == 0 {
+            return Ok(());
+        }
+        match self.inner.take().ok_or_else(poisoned)? {
+            BufIO::Writer(w) if w.buffer().len() == mark + written => {
+                let capacity = w.capacity();
+                let (rw, buf) = w.into_parts();
+                let buf = buf.unwrap_or_else(|e| e.into_inner());
+                let mut w = BufWriter::with_capacity(capacity, rw);
+                let result = buffer_pending(&mut w, &buf[..mark]);
+                self.inner = Some(BufIO::Writer(w));
+                result
+            }
+            inner => {
+                self.inner = Some(inner);
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "write transaction data has already been flushed",
+                ))
+            }
+        }
+    }
+
+    /// Panics if the internal buffering state is inconsistent: the saved buffer is empty or fully consumed, or data is
+    /// buffered in the `BufReader` while a saved buffer is pending.  Only available in debug builds, where it is checked
+    /// at the start of every read and write.
+    #[cfg(debug_assertions)]
+    pub fn debug_assert_invariants(&self) {
+        if let Some(b) = &self.buffer {
+            assert!(
+                self.pos < b.len(),
+                "saved buffer position {} is not below its length {}",
+                self.pos,
+                b.len()
+            );
+            if let Some(BufIO::Reader(r)) = &self.inner {
+                assert!(
+                    r.buffer().is_empty(),
+                    "BufReader holds data while a saved buffer is pending"
+                );
+            }
+        }
+    }
+
+    /// Grows the write buffer capacity to at least `needed` bytes, so that data up to that size can be buffered without a
+    /// flush.  In write mode the write buffer is flushed and rebuilt with the new capacity, otherwise the new capacity
+    /// applies from the next switch to write mode.  Does nothing if the capacity is already large enough.  For an instance
+    /// created with `with_budget`, returns an error, leaving the capacity unchanged, if the budget cannot grant the extra
+    /// capacity.
+    pub fn ensure_write_capacity(&mut self, needed: usize) -> io::Result<()> {
+        let current = match self.get_bufwriter_ref() {
+            Some(w) => w.capacity(),
+            None => self.write_capacity.unwrap_or(DEFAULT_CAPACITY),
+        };
+        if needed > current {
+            if let Some((budget, granted)) = self.budget.as_mut() {
+                let grant = budget.acquire(needed - current, needed - current);
+                if grant == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "write capacity exceeds the remaining buffer budget",
+                    ));
+                }
+                *granted += grant;
+            }
+            self.write_capacity = Some(needed);
+            if let Some(BufIO::Writer(w)) = self.inner.as_mut() {
+                w.flush()?;
+            }
+            self.inner = match self.inner.take() {
+                Some(BufIO::Writer(w)) => {
+                    Some(BufIO::new_writer(w.into_parts().0, self.write_capacity))
+                }
+                Some(BufIO::Raw(rw, Mode::Writer)) => {
+                    Some(BufIO::new_writer(rw, self.write_capacity))
+                }
+                inner => inner,
+            };
+        }
+        Ok(())
+    }
+
+    /// Sets a callback invoked when a write flushes the write buffer because the data does not fit, with the number of
+    /// bytes flushed.  Explicit flushes do not invoke it.
+    pub fn set_on_auto_flush(&mut self, f: impl FnMut(usize) + Send + 'static) {
+        self.on_auto_flush = Some(Box::new(f));
+    }
+
+    /// Sets whether reads and writes are retried when the underlying IO instance returns an `Interrupted` error, rather
+    /// than returning the error.  Off by default.
+    pub fn set_retry_on_interrupt(&mut self, retry: bool) {
+        self.retry_on_interrupt = retry;
+    }
+
+    /// Sets the number of operations after a switch to read mode during which small writes do not switch back to write
+    /// mode, to reduce thrashing under alternating reads and writes.  Such writes are deferred in a side buffer, up to
+    /// the write buffer capacity in total, and replayed into the write buffer on the next switch to write mode, which
+    /// `flush` forces.  Reads are unaffected, since reading and writing a sequential stream are independent.  Writes are
+    /// not deferred during a write transaction.  0, the default, turns this off.
+    pub fn set_switch_hysteresis(&mut self, ops: u32) {
+        self.switch_hysteresis = ops;
+    }
+
+    /// Returns true if both instances are in the same mode with the same data waiting in the write buffer and the same
+    /// saved read data, for comparing two instances in tests.
+    pub fn buffers_equal(&self, other: &Self) -> bool {
+        self.mode() == other.mode()
+            && self.get_bufwriter_ref().map(|w| w.buffer())
+                == other.get_bufwriter_ref().map(|w| w.buffer())
+            && self.buffer() == other.buffer()
+    }
+
+    /// Returns a reader of successive chunks of the remaining data, each read into `scratch` and filling it except at the
+    /// end, without allocating.  Each chunk borrows `scratch` until the next is read, so `ChunksInto` is not an `Iterator`
+    /// and is driven with `while let Some(chunk) = chunks.next_chunk()`.  Both `self` and `scratch` stay borrowed for as
+    /// long as the `ChunksInto` is in use.
+    pub fn chunks_into<'b>(&'b mut self, scratch: &'b mut [u8]) -> ChunksInto<'b, RW> {
+        ChunksInto { brw: self, scratch }
+    }
+
+    /// Returns an iterator over the remaining data as records of `record_len` bytes each, as for record-oriented binary
+    /// files.  A trailing partial record yields an error of kind `UnexpectedEof`, unless allowed with
+    /// `Records::allow_partial`.  Iteration ends after the first error, and yields nothing if `record_len` is 0.
+    pub fn records(&mut self, record_len: usize) -> Records<'_, RW> {
+        Records {
+            brw: self,
+            record_len,
+            allow_partial: false,
+            done: false,
+        }
+    }
+
+    /// Returns a mutable reference to the underlying reader/writer together with a snapshot of the buffering state, taken
+    /// before the reference is handed out, so both are available from a single borrow.
+    pub fn inner_and_buffers(&mut self) -> (&mut RW, BufReaderWriterState) {
+        let state = self.state();
+        (self.inner.as_mut().expect(POISONED).get_mut(), state)
+    }
+
+    /// Returns owned copies of the buffered data, for restoring into a new instance with `import_state`, such as after a
+    /// restart with a new underlying IO instance.  Writes deferred by the switch hysteresis are included in the pending
+    /// write data, followed by any incomplete group held back by the byte swap, as is, with write mode as the exported
+    /// mode whenever there is pending write data, so that importing replays it.
+    pub fn export_state(&self) -> ExportedBuffers {
+        let mut pending_write = self
+            .get_bufwriter_ref()
+            .map_or_else(Vec::new, |w| w.buffer().to_vec());
+        pending_write.extend_from_slice(&self.deferred_writes);
+        if let Some(swap) = self.byteswap.as_ref() {
+            pending_write.extend_from_slice(swap.write_partial());
+        }
+        ExportedBuffers {
+            mode: if pending_write.is_empty() {
+                self.mode()
+            } else {
+                Mode::Writer
+            },
+            pending_write,
+            saved_read: self.peek_buffered(),
+        }
+    }
+
+    /// Restores buffered data exported with `export_state`, switching to the exported mode.  The exported read data is
+    /// returned by subsequent reads ahead of any read data already buffered, and the exported write data is added to the
+    /// write buffer, or written if it does not fit.
+    pub fn import_state(&mut self, state: ExportedBuffers) -> io::Result<()> {
+        match state.mode {
+            Mode::Reader => self.switch_to_reader()?,
+            Mode::Writer => self.switch_to_writer()?,
+            Mode::Unset => {}
+        }
+        if !state.saved_read.is_empty() {
+            let mut saved = state.saved_read;
+            if let Some(b) = self.buffer.take() {
+                saved.extend_from_slice(&b[self.pos..]);
+            }
+            // The saved buffer is read before the `BufReader`, so data the `BufReader` still holds is moved in behind it.
+            if let Some(BufIO::Reader(r)) = self.inner.as_mut() {
+                let rb = r.buffer();
+                let n = rb.len();
+                saved.extend_from_slice(rb);
+                r.consume(n);
+            }
+            self.buffer = Some(saved);
+            self.pos = 0;
+        }
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => buffer_pending(w, &state.pending_write),
+            BufIO::Raw(rw, Mode::Writer) => rw.write_all(&state.pending_write),
+            _ if state.pending_write.is_empty() => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pending write data requires write mode",
+            )),
+        }
+    }
+
+    /// Returns the configuration of this instance, with the current mode as the starting mode.
+    pub fn config(&self) -> BufReaderWriterConfig {
+        BufReaderWriterConfig {
+            mode: self.mode(),
+            read_capacity: self.read_capacity,
+            write_capacity: self.write_capacity,
+            retry_on_interrupt: self.retry_on_interrupt,
+            write_alignment: None,
+            max_saved_buffer: self.max_saved_buffer,
+            max_write_chunk: self.max_write_chunk,
+            read_ahead: self.read_ahead,
+            wouldblock_as_eof: self.wouldblock_as_eof,
+            seek_bound: self.seek_bound,
+        }
+    }
+
+    /// Returns a snapshot of the buffering state.
+    pub fn state(&self) -> BufReaderWriterState {
+        BufReaderWriterState {
+            mode: self.mode(),
+            capacity: self.capacity(),
+            pending_write: self.pending_write_len(),
+            buffered_read: self.get_bufreader_ref().map_or(0, |r| r.buffer().len()),
+            saved_buffer: self.buffer().map_or(0, |b| b.len()),
+        }
+    }
+
+    /// Sets the maximum size of a single write to the underlying writer.  When set, a write longer than `max` is split into
+    /// chunks of `max` bytes, each written and flushed in turn, for progress reporting through an `IoObserver`.
+    pub fn set_max_write_chunk(&mut self, max: Option<usize>) {
+        self.max_write_chunk = max;
+    }
+
+    /// Sets whether reads fill the `BufReader` buffer ahead of the data requested, which is the default.  With read-ahead
+    /// off, `read` reads no more than the requested length from the underlying reader, so the data of a later message is
+    /// left in the underlying stream.  Data saved across a switch to write mode is still returned first.  Methods that
+    /// need a filled buffer, such as `read_until_into` and `copy_to`, still read ahead.
+    pub fn set_read_ahead(&mut self, enabled: bool) {
+        self.read_ahead = enabled;
+    }
+
+    /// Returns the kind of the current run of reads or writes, and the number of operations in it, counting each `read` or
+    /// `write` call since the last call of the other kind.  Before any operation the count is 0, with the initial mode.
+    pub fn current_run(&self) -> (Mode, u64) {
+        self.run
+    }
+
+    /// Returns the number of `read` and `write` calls made so far.
+    pub fn total_ops(&self) -> u64 {
+        self.ops.0
+    }
+
+    /// Returns the number of times a `read` or `write` call followed a call of the other kind, or, for the first call,
+    /// did not match the initial mode.
+    pub fn switch_count(&self) -> u64 {
+        self.ops.1
+    }
+
+    /// Returns the ratio of `switch_count` to `total_ops`, or 0.0 before any operation.  A ratio close to 1.0 means reads
+    /// and writes alternate on almost every call, so little use is made of the buffers.
+    pub fn thrash_ratio(&self) -> f64 {
+        if self.ops.0 == 0 {
+            0.0
+        } else {
+            self.ops.1 as f64 / self.ops.0 as f64
+        }
+    }
+
+    /// Returns the buffer capacity of the underlying reader or writer.
+    pub fn capacity(&self) -> usize {
+        self.inner.as_ref().map_or(0, |b| b.capacity())
+    }
+
+    /// Returns the capacity of the `BufReader` or `BufWriter` created by the most recent switch between modes, including
+    /// the first operation of an instance created with `new_lazy`, or `None` if there has been no switch.
+    pub fn last_transition_capacity(&self) -> Option<usize> {
+        self.last_transition_capacity
+    }
+
+    /// Returns a suggested size for the chunks passed to reads and writes, the capacity of the current buffer, or before the
+    /// first operation on an instance created with `new_lazy`, the capacity the first buffer will have.
+    pub fn suggested_io_size(&self) -> usize {
+        match self.inner.as_ref() {
+            Some(BufIO::Raw(_, Mode::Unset)) => self
+                .read_capacity
+                .max(self.write_capacity)
+                .unwrap_or(DEFAULT_CAPACITY),
+            inner => inner.map_or(0, |b| b.capacity()),
+        }
+    }
+
+    /// Returns `suggested_io_size` rounded up to a multiple of the block size of the underlying IO instance, if it reports
+    /// one.
+    pub fn suggested_block_io_size(&self) -> usize
+    where
+        RW: BlockSize,
+    {
+        let size = self.suggested_io_size();
+        match self.get_ref().block_size() {
+            Some(block) if block > 0 => ((size + block - 1) / block).max(1) * block,
+            _ => size,
+        }
+    }
+
+    /// Swaps the read and write buffer capacities and rebuilds the current buffer with its new capacity.  In write mode
+    /// the write buffer is flushed first, in read mode any buffered read data is kept in the saved buffer.  Writes
+    /// deferred by the switch hysteresis that no longer fit in the new write buffer capacity are written out, as they
+    /// are kept to less than it.
+    pub fn swap_capacities(&mut self) -> io::Result<()> {
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => w.flush()?,
+            BufIO::Reader(r) => {
+                if !r.buffer().is_empty() {
+                    self.buffer = Some(r.buffer().to_vec());
+                    self.pos = 0;
+                }
+            }
+            BufIO::Raw(..) => {}
+        }
+        std::mem::swap(&mut self.read_capacity, &mut self.write_capacity);
+        self.inner = Some(match self.inner.take().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => BufIO::new_writer(w.into_parts().0, self.write_capacity),
+            BufIO::Reader(r) => BufIO::new_reader(r.into_inner(), self.read_capacity),
+            BufIO::Raw(rw, Mode::Writer) => BufIO::new_writer(rw, self.write_capacity),
+            BufIO::Raw(rw, Mode::Reader) => BufIO::new_reader(rw, self.read_capacity),
+            raw => raw,
+        });
+        if self.deferred_writes.len() >= self.write_capacity.unwrap_or(DEFAULT_CAPACITY) {
+            let rw = self.inner.as_mut().ok_or_else(poisoned)?.get_mut();
+            write_draining(rw, &mut self.deferred_writes)?;
+        }
+        Ok(())
+    }
+
+    /// Low level function that indicates an amount of data has been consumed from the buffer and is not to be returned by the next read.  The buffer is dropped if all data has been consumed.
+    /// If there is no saved read data, the data is consumed from the `BufReader` buffer instead, as returned by `fill_and_peek`.
+    pub fn consume(&mut self, amt: usize) {
+        if let Some(b) = self.buffer.as_ref() {
+            self.pos += amt;
+            if self.pos >= b.len() {
+                self.buffer = None
+            }
+        } else if let Some(BufIO::Reader(r)) = self.inner.as_mut() {
+            r.consume(amt);
+        }
+    }
+
+    /// Switches to read mode and returns up to `max` bytes of the next read data without consuming it, for use with
+    /// `consume`.  If saved read data is pending but shorter than `max`, one read of the underlying reader extends it,
+    /// otherwise the `BufReader` buffer is filled if empty.  The slice may be shorter than `max`, as only contiguous
+    /// buffered data is returned, and is empty at the end of the underlying reader.  `max` is limited to the read buffer
+    /// capacity, so the saved read data grows by at most that much.
+    pub fn fill_and_peek(&mut self, max: usize) -> io::Result<&[u8]> {
+        self.switch_to_reader()?;
+        let max = match self.capacity() {
+            0 => max.min(UNBUFFERED_FILL_CAPACITY),
+            capacity => max.min(capacity),
+        };
+        if let (Some(b), Some(r)) = (self.buffer.as_mut(), self.inner.as_mut()) {
+            let len = b.len();
+            if len - self.pos < max && !self.eof {
+                b.resize(self.pos.saturating_add(max), 0);
+                let n = match r.get_mut().read(&mut b[len..]) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        b.truncate(len);
+                        return Err(e);
+                    }
+                };
+                b.truncate(len + n);
+                if n == 0 {
+                    self.eof = true;
+                }
+                self.inner_read_bytes += n as u64;
+            }
+        }
+        if self.buffer.is_none() {
+            let data = self.fill_read_buf()?;
+            return Ok(&data[..data.len().min(max)]);
+        }
+        let end = self.pos.saturating_add(max);
+        Ok(self
+            .buffer
+            .as_deref()
+            .map_or(&[], |b| &b[self.pos..b.len().min(end)]))
+    }
+}
+
+impl BufReaderWriterConfig {
+    /// Returns a new `BufReaderWriterSeq` over `rw` with this configuration.
+    pub fn build_seq<RW: Read + Write>(&self, rw: RW) -> BufReaderWriterSeq<RW> {
+        let inner = match self.mode {
+            Mode::Reader => BufIO::new_reader(rw, self.read_capacity),
+            Mode::Writer => BufIO::new_writer(rw, self.write_capacity),
+            Mode::Unset => BufIO::Raw(rw, Mode::Unset),
+        };
+        let mut brw =
+            BufReaderWriterSeq::from_parts(inner, self.read_capacity, self.write_capacity);
+        brw.retry_on_interrupt = self.retry_on_interrupt;
+        brw.max_saved_buffer = self.max_saved_buffer;
+        brw.max_write_chunk = self.max_write_chunk;
+        brw.read_ahead = self.read_ahead;
+        brw.wouldblock_as_eof = self.wouldblock_as_eof;
+        brw.seek_bound = self.seek_bound;
+        brw
+    }
+}
+
+impl<RW: Read + Write> Drop for BufReaderWriterSeq<RW> {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            let data = self.take_unbuffered_writes();
+            // As with dropping a `BufWriter`, errors writing out the buffered data are ignored.
+            let _ = inner.into_inner_with(&data);
+        }
+        if let Some((budget, granted)) = self.budget.take() {
+            budget.release(granted);
+        }
+    }
+}
+
+/// Reads successive chunks of a `BufReaderWriterSeq` into a caller-provided scratch buffer, returned by `chunks_into`.
+pub struct ChunksInto<'b, RW: Read + Write> {
+    brw: &'b mut BufReaderWriterSeq<RW>,
+    scratch: &'b mut [u8],
+}
+
+impl<'b, RW: Read + Write> ChunksInto<'b, RW> {
+    /// Reads the next chunk into the scratch buffer, filling it unless the end of the data is reached first, and returns
+    /// the filled part.  Returns `None` at the end of the data.
+    pub fn next_chunk(&mut self) -> Option<io::Result<&[u8]>> {
+        let mut filled = 0;
+        while filled < self.scratch.len() {
+            match self.brw.read(&mut self.scratch[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        if filled == 0 {
+            None
+        } else {
+            Some(Ok(&self.scratch[..filled]))
+        }
+    }
+}
+
+/// Iterates over fixed-size records of a `BufReaderWriterSeq`, returned by `records`.  Reading switches to read mode.
+pub struct Records<'a, RW: Read + Write> {
+    brw: &'a mut BufReaderWriterSeq<RW>,
+    record_len: usize,
+    allow_partial: bool,
+    done: bool,
+}
+
+impl<'a, RW: Read + Write> Records<'a, RW> {
+    /// Sets whether a trailing partial record is yielded as a shorter record rather than as an error.
+    pub fn allow_partial(mut self, allow: bool) -> Self {
+        self.allow_partial = allow;
+        self
+    }
+}
+
+impl<'a, RW: Read + Write> Iterator for Records<'a, RW> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done || self.record_len == 0 {
+            return None;
+        }
+        let mut record = vec![0; self.record_len];
+        let mut filled = 0;
+        while filled < record.len() {
+            match self.brw.read(&mut record[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        if filled == record.len() {
+            return Some(Ok(record));
+        }
+        self.done = true;
+        if filled == 0 {
+            None
+        } else if self.allow_partial {
+            record.truncate(filled);
+            Some(Ok(record))
+        } else {
+            Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "trailing partial record",
+            )))
+        }
+    }
+}
+
+impl<RW: Read + Write + fmt::Debug> fmt::Debug for BufReaderWriterSeq<RW> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufReaderWriterSeq")
+            .field("name", &self.name)
+            .field("inner_type", &self.inner_type_name())
+            .field("inner", &self.inner.as_ref().map(|b| b.get_ref()))
+            .field("state", &self.inner.as_ref().map(|_| self.state()))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<RW: Read + Write> Read for BufReaderWriterSeq<RW> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(mut swap) = self.byteswap.take() {
+            let partial = swap.take_write_partial();
+            let result = self
+                .write_all(&partial)
+                .and_then(|_| swap.read(buf, |b| self.read(b)));
+            self.byteswap = Some(swap);
+            return result;
+        }
+        loop {
+            match self.read_once(buf) {
+                Err(e) if self.retry_on_interrupt && e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Reads into `bufs` from the saved read data if any, filling them in turn, otherwise through the `BufReader`, which
+    /// reads directly into them from the underlying reader when its buffer is empty and they are at least its capacity
+    /// in total.
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        loop {
+            match self.read_vectored_once(bufs) {
+                Err(e) if self.retry_on_interrupt && e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Returns whether the underlying reader has an efficient `read_vectored`.  Vectored reads of saved read data are
+    /// always copies, so only reads from the underlying reader benefit.
+    #[cfg(feature = "nightly")]
+    fn is_read_vectored(&self) -> bool {
+        self.get_ref().is_read_vectored()
+    }
+
+    /// Reads through `read` while a byte swap is set, as swapping works on initialized data, otherwise reads into the
+    /// cursor directly.
+    #[cfg(feature = "nightly")]
+    fn read_buf(&mut self, mut cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
+        if self.byteswap.is_some() {
+            let n = self.read(cursor.ensure_init().init_mut())?;
+            cursor.advance(n);
+            return Ok(());
+        }
+        loop {
+            match self.read_buf_once(cursor.reborrow()) {
+                Err(e) if self.retry_on_interrupt && e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<RW: Read + Write> Write for BufReaderWriterSeq<RW> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(mut swap) = self.byteswap.take() {
+            let result = swap.write(buf, |b| self.write_all(b));
+            self.byteswap = Some(swap);
+            return result;
+        }
+        loop {
+            match self.write_once(buf) {
+                Err(e) if self.retry_on_interrupt && e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_swap_partial()?;
+        if !self.deferred_writes.is_empty() {
+            self.switch_to_writer()?;
+        }
+        match self.inner.as_mut() {
+            Some(BufIO::Writer(w)) => w.flush()?,
+            Some(BufIO::Raw(rw, Mode::Writer)) => rw.flush()?,
+            _ => return Ok(()),
+        }
+        self.last_flush = Instant::now();
+        if let Some(o) = self.observer.as_mut() {
+            o.on_flush();
+        }
+        Ok(())
+    }
+}
+
+impl<RW: Read + Write + Seek> Seek for BufReaderWriterSeq<RW> {
+    /// Seeks the underlying IO instance, discarding the `BufReaderWriter` read buffer.  In read mode, positions exclude any
+    /// buffered read data, and a `SeekFrom::Current` seek that stays within the read buffer only moves within it, without
+    /// seeking the underlying IO instance.  Writes deferred by the switch hysteresis are replayed and flushed before the
+    /// seek.  A successful seek clears the end of file state.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let max = match self.seek_bound {
+            Some(max) => max,
+            None => return self.seek_unbounded(pos),
+        };
+        let out_of_bounds =
+            || io::Error::new(io::ErrorKind::InvalidInput, "seek beyond the seek bound");
+        match pos {
+            SeekFrom::Start(n) if n > max => Err(out_of_bounds()),
+            SeekFrom::Current(n)
+                if n > 0 && self.stream_position()?.saturating_add(n as u64) > max =>
+            {
+                Err(out_of_bounds())
+            }
+            SeekFrom::End(_) => {
+                let prev = self.stream_position()?;
+                let new = self.seek_unbounded(pos)?;
+                if new > max {
+                    self.seek_unbounded(SeekFrom::Start(prev))?;
+                    return Err(out_of_bounds());
+                }
+                Ok(new)
+            }
+            _ => self.seek_unbounded(pos),
+        }
+    }
+
+    /// Takes `&mut self`, as required by `Seek`, since the position is queried from the underlying IO instance.
+    fn stream_position(&mut self) -> io::Result<u64> {
+        let remaining = self.buffer().map_or(0, |b| b.len()) as u64
+            + self.byteswap.as_ref().map_or(0, |s| s.read_ahead()) as u64;
+        match self.inner.as_mut().ok_or_else(poisoned)? {
+            BufIO::Writer(w) => w.stream_position(),
+            r if r.mode() == Mode::Reader => Ok(r.stream_position()? - remaining),
+            r => r.stream_position(),
         }
     }
 }
@@ -0,0 +1,70 @@
+//! Allocation counts for the methods that promise to reuse their buffers.  These live in their own test binary because
+//! they install a global allocator.
+
+use bufreaderwriter::rand::BufReaderWriterRand;
+use bufreaderwriter::seq::BufReaderWriterSeq;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = Cell::new(0);
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|a| a.get())
+}
+
+#[test]
+fn testcopyfromscratch() {
+    let data: Vec<u8> = (0..10).collect();
+    let mut brw = BufReaderWriterSeq::writer_with_capacity(64, Cursor::new(vec![0; 4096]));
+    let before = allocations();
+    for _ in 0..100 {
+        assert_eq!(10, brw.copy_from(&mut &data[..]).expect("Copy error"));
+    }
+    assert_eq!(1, allocations() - before);
+
+    let mut brw = BufReaderWriterRand::writer_with_capacity(64, Cursor::new(vec![0; 4096]));
+    let before = allocations();
+    for _ in 0..100 {
+        assert_eq!(10, brw.copy_from(&mut &data[..]).expect("Copy error"));
+    }
+    assert_eq!(1, allocations() - before);
+    brw.seek(SeekFrom::Start(990)).expect("Seek error");
+    let mut buf = [0; 10];
+    brw.read_exact(&mut buf).expect("Read error");
+    assert_eq!(&data[..], &buf);
+}
+
+#[test]
+fn testshrinksavedbuffer() {
+    let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+    let mut brw = BufReaderWriterSeq::reader_with_capacity(64 * 1024, Cursor::new(data.clone()));
+    brw.read_exact(&mut [0; 1]).expect("Read error");
+    brw.write_all(b"x").expect("Write error");
+    brw.read_exact(&mut vec![0; 60 * 1024]).expect("Read error");
+
+    let before = allocations();
+    brw.shrink_saved_buffer();
+    assert_eq!(1, allocations() - before);
+    let before = allocations();
+    brw.shrink_saved_buffer();
+    assert_eq!(0, allocations() - before);
+}